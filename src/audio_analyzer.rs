@@ -0,0 +1,71 @@
+use anyhow::Result;
+
+use crate::audio_bass_tamer::{BassReport, BassTamer};
+use crate::audio_declicker::{ClickReport, DeClicker};
+use crate::audio_deharsher::{DeHarsher, HarshnessReport};
+use crate::audio_hum_remover::{HumRemover, HumReport};
+use crate::audio_normalizer::measure_loudness_and_true_peak;
+use crate::audio_stereo_fixer::{StereoFix, StereoFixer};
+use crate::dsp::{analyze_stereo, StereoAnalysis};
+
+/// Measurements of a clip, used to flag problems before anyone has to hear them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipAnalysis {
+    /// Integrated loudness in LUFS, `None` when the clip is too short to be gated
+    pub loudness: Option<f64>,
+    /// True peak in dBTP
+    pub true_peak: f64,
+    /// Energy in the 2–5 kHz band
+    pub harshness: HarshnessReport,
+    /// Unweighted energy below 100 Hz
+    pub bass: BassReport,
+    /// Phase correlation, balance and mono compatibility, `None` for mono clips
+    pub stereo: Option<StereoAnalysis>,
+    /// Isolated clicks and steps at the start or end of the clip
+    pub clicks: ClickReport,
+    /// Mains hum series, `None` when the clip has no hum
+    pub hum: Option<HumReport>,
+}
+
+impl ClipAnalysis {
+    /// Analyze interleaved samples with the default detection thresholds
+    pub fn analyze(samples: &[f32], channels: usize, sample_rate: u32) -> Result<Self> {
+        let (loudness, true_peak) = measure_loudness_and_true_peak(channels, sample_rate, samples)?;
+
+        Ok(Self {
+            loudness: loudness.is_finite().then_some(loudness),
+            true_peak,
+            harshness: DeHarsher::default().analyze(samples, channels, sample_rate),
+            bass: BassTamer::default().analyze(samples, channels, sample_rate),
+            stereo: analyze_stereo(samples, channels, sample_rate),
+            clicks: DeClicker::default().detect(samples, channels, sample_rate),
+            hum: HumRemover::default().detect(samples, channels, sample_rate),
+        })
+    }
+
+    /// Short names of the problems found in the clip
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = Vec::new();
+        if self.harshness.harsh {
+            flags.push("harsh");
+        }
+        if self.bass.heavy {
+            flags.push("bass-heavy");
+        }
+        if self.clicks.total() > 0 {
+            flags.push("clicks");
+        }
+        if self.hum.is_some() {
+            flags.push("hum");
+        }
+        if let Some(stereo) = &self.stereo {
+            for fix in StereoFixer::default().plan(stereo) {
+                flags.push(match fix {
+                    StereoFix::FlipPolarity => "out-of-phase",
+                    StereoFix::Rebalance(_) | StereoFix::FoldToMono => "lopsided",
+                });
+            }
+        }
+        flags
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Error, Result};
+use log::debug;
+
+use crate::dsp::{band_energies, gated_band_ratio, AudioProcessor, Biquad, DynamicFilter};
+
+/// Dynamic low shelf that pulls sub-100 Hz energy down only while it dominates the clip
+///
+/// K-weighting rolls off the lows, so bass-boosted clips measure as moderately loud while
+/// being physically punishing on headphones.
+pub struct BassTamer {
+    threshold: f64,
+    max_cut: f64,
+}
+
+/// How much unweighted sub-bass a clip has compared to its K-weighted energy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BassReport {
+    /// Unweighted energy below 100 Hz relative to the K-weighted total, in dB
+    pub ratio: f64,
+    /// Largest ratio of any single frame, in dB
+    pub peak_ratio: f64,
+    /// Whether the ratio exceeds the threshold
+    pub heavy: bool,
+}
+
+impl Default for BassTamer {
+    fn default() -> Self {
+        Self {
+            threshold: Self::DEFAULT_THRESHOLD,
+            max_cut: Self::DEFAULT_MAX_CUT,
+        }
+    }
+}
+
+impl BassTamer {
+    /// Upper edge of the sub-bass band and corner of the shelf, in Hz
+    pub const CUTOFF: f64 = 100.0;
+    /// Sub-bass ratio above which a clip is bass heavy, in dB.
+    /// Pink noise sits around -6 dB, bass-boosted clips above 0 dB.
+    pub const DEFAULT_THRESHOLD: f64 = -3.0;
+    /// Default deepest cut in dB
+    pub const DEFAULT_MAX_CUT: f64 = 12.0;
+    /// Deepest cut allowed in dB
+    pub const MAX_CUT: f64 = 24.0;
+
+    /// Long enough to resolve the sub-bass band
+    const ANALYSIS_FFT_SIZE: usize = 8192;
+    /// Frames this far below the loudest frame are ignored by the analysis, in dB
+    const ANALYSIS_GATE: f64 = -40.0;
+    /// Smoothing of the sub-bass and total energy detectors, long enough to span a 20 Hz cycle
+    const DETECTOR_MS: f64 = 50.0;
+    const ATTACK_MS: f64 = 10.0;
+    const RELEASE_MS: f64 = 150.0;
+    /// dB of cut per dB the sub-bass is over the threshold
+    const CUT_PER_DB: f64 = 2.0;
+
+    pub fn new(threshold: f64, max_cut: f64) -> Result<Self> {
+        if max_cut <= 0.0 || max_cut > Self::MAX_CUT {
+            return Err(anyhow::anyhow!(
+                "Maximum cut must be between 0 and {} dB (got: {} dB)",
+                Self::MAX_CUT,
+                max_cut
+            ));
+        }
+
+        Ok(Self { threshold, max_cut })
+    }
+
+    /// Measure how much sub-bass the clip has
+    pub fn analyze(&self, samples: &[f32], channels: usize, sample_rate: u32) -> BassReport {
+        let frames = band_energies(
+            samples,
+            channels,
+            sample_rate,
+            (0.0, Self::CUTOFF),
+            false,
+            Self::ANALYSIS_FFT_SIZE,
+        );
+        let (ratio, peak_ratio) = gated_band_ratio(&frames, Self::ANALYSIS_GATE);
+
+        BassReport {
+            ratio,
+            peak_ratio,
+            heavy: ratio > self.threshold,
+        }
+    }
+
+    /// How far the clip's sub-bass ratio is over the threshold, in dB
+    pub fn excess(&self, samples: &[f32], channels: usize, sample_rate: u32) -> f64 {
+        (self.analyze(samples, channels, sample_rate).ratio - self.threshold).max(0.0)
+    }
+}
+
+impl AudioProcessor for BassTamer {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let dynamics = DynamicFilter {
+            threshold: self.threshold,
+            max_cut: self.max_cut,
+            cut_per_db: Self::CUT_PER_DB,
+            detector_ms: Self::DETECTOR_MS,
+            attack_ms: Self::ATTACK_MS,
+            release_ms: Self::RELEASE_MS,
+        };
+
+        // The detector compares the raw sub-bass of the mono sum with its K-weighted energy
+        let mut sub_filter = Biquad::lowpass(sample_rate, Self::CUTOFF, 0.707);
+        let (output, deepest_cut) = dynamics.apply(
+            samples,
+            channels,
+            sample_rate,
+            |mono, _| sub_filter.process(mono),
+            |gain| Biquad::low_shelf(sample_rate, Self::CUTOFF, 0.707, gain),
+        );
+
+        debug!("Bass tamer cut the lows by up to {:.1} dB", deepest_cut);
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::sine;
+
+    /// A sub-bass sine under a quiet mid-range tone
+    fn bass_boosted(sample_rate: u32) -> Vec<f32> {
+        let frames = sample_rate as usize * 2;
+        let bass = sine(50.0, 0.6, sample_rate, frames);
+        let mid = sine(1000.0, 0.1, sample_rate, frames);
+        bass.iter().zip(&mid).map(|(b, m)| b + m).collect()
+    }
+
+    #[test]
+    fn test_flags_bass_heavy_clips() {
+        let sample_rate = 44100;
+        let tamer = BassTamer::default();
+
+        let heavy = tamer.analyze(&bass_boosted(sample_rate), 1, sample_rate);
+        assert!(heavy.heavy, "Expected a bass-heavy clip, got {:?}", heavy);
+
+        let tone = sine(440.0, 0.5, sample_rate, sample_rate as usize);
+        let light = tamer.analyze(&tone, 1, sample_rate);
+        assert!(!light.heavy, "Expected a light clip, got {:?}", light);
+    }
+
+    /// Unweighted energy of the clip between `low` and `high` Hz, in dB
+    fn band_level(samples: &[f32], sample_rate: u32, band: (f64, f64)) -> f64 {
+        let energy: f64 = band_energies(samples, 1, sample_rate, band, false, 8192)
+            .iter()
+            .map(|frame| frame.band)
+            .sum();
+        10.0 * energy.log10()
+    }
+
+    #[test]
+    fn test_cuts_sub_bass_only() {
+        let sample_rate = 44100;
+        let clip = bass_boosted(sample_rate);
+        let processed = BassTamer::default().process(&clip, 1, sample_rate).unwrap();
+
+        let sub_drop = band_level(&clip, sample_rate, (0.0, 100.0))
+            - band_level(&processed, sample_rate, (0.0, 100.0));
+        let mid_drop = band_level(&clip, sample_rate, (900.0, 1100.0))
+            - band_level(&processed, sample_rate, (900.0, 1100.0));
+
+        assert!(
+            sub_drop > 6.0,
+            "Expected the sub-bass to drop, got {:.1} dB",
+            sub_drop
+        );
+        assert!(
+            mid_drop.abs() < 0.5,
+            "Expected the mids untouched, got {:.1} dB",
+            mid_drop
+        );
+    }
+
+    #[test]
+    fn test_invalid_max_cut() {
+        assert!(BassTamer::new(-3.0, 0.0).is_err());
+        assert!(BassTamer::new(-3.0, 30.0).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::ops::Range;
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+
+use crate::dsp::{median, AudioProcessor};
+
+/// Finds isolated clicks and steps at the clip boundaries and smooths them over
+///
+/// Clicks are found as outliers in the high-passed residual of the signal, so the limiter
+/// does not pump on them later in the chain. Each click is replaced by a cubic
+/// interpolation between the clean samples on either side.
+pub struct DeClicker {
+    sensitivity: f64,
+}
+
+/// What was found or repaired in a clip
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClickReport {
+    /// Isolated clicks inside the clip
+    pub clicks: usize,
+    /// Start and end of the clip cut away from a zero crossing
+    pub boundary_steps: usize,
+}
+
+impl ClickReport {
+    /// Clicks and boundary steps together
+    pub fn total(&self) -> usize {
+        self.clicks + self.boundary_steps
+    }
+}
+
+impl Default for DeClicker {
+    fn default() -> Self {
+        Self {
+            sensitivity: Self::DEFAULT_SENSITIVITY,
+        }
+    }
+}
+
+impl DeClicker {
+    /// Default detection threshold, in robust standard deviations of the residual
+    pub const DEFAULT_SENSITIVITY: f64 = 8.0;
+    /// Lowest threshold allowed, below which ordinary noise is taken for clicks
+    pub const MIN_SENSITIVITY: f64 = 3.0;
+
+    /// Samples over which the residual's noise floor is estimated
+    const BLOCK_SIZE: usize = 1024;
+    /// Smallest residual that can be a click, so digital silence is not searched for clicks
+    const MIN_THRESHOLD: f64 = 1e-3;
+    /// Longer outliers are real transients and are left alone
+    const MAX_CLICK_MS: f64 = 1.0;
+    /// Outliers this close together belong to the same click
+    const MERGE_GAP: usize = 4;
+    /// Level of the first or last sample above which the clip starts or ends on a step
+    const BOUNDARY_LEVEL: f32 = 0.01;
+    /// Length of the fade that removes a boundary step
+    const FADE_MS: f64 = 5.0;
+
+    pub fn new(sensitivity: f64) -> Result<Self> {
+        if sensitivity < Self::MIN_SENSITIVITY {
+            return Err(anyhow::anyhow!(
+                "Sensitivity must be at least {} (got: {})",
+                Self::MIN_SENSITIVITY,
+                sensitivity
+            ));
+        }
+
+        Ok(Self { sensitivity })
+    }
+
+    /// Count the clicks and boundary steps in the clip without repairing them
+    pub fn detect(&self, samples: &[f32], channels: usize, sample_rate: u32) -> ClickReport {
+        ClickReport {
+            clicks: self.find_clicks(samples, channels, sample_rate).len(),
+            boundary_steps: Self::boundary_steps(samples, channels)
+                .iter()
+                .filter(|&&step| step)
+                .count(),
+        }
+    }
+
+    /// Repair the clicks and boundary steps, returning the samples and what was repaired
+    pub fn repair(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> (Vec<f32>, ClickReport) {
+        let mut output = samples.to_vec();
+        let clicks = self.find_clicks(samples, channels, sample_rate);
+        for click in &clicks {
+            for channel in 0..channels {
+                Self::interpolate(&mut output, channels, channel, click);
+            }
+        }
+
+        let [starts_on_step, ends_on_step] = Self::boundary_steps(samples, channels);
+        let fade_frames = ((Self::FADE_MS * 0.001 * sample_rate as f64) as usize)
+            .min(output.len() / channels / 2)
+            .max(1);
+        let fade =
+            |i: usize| 0.5 - 0.5 * (std::f32::consts::PI * i as f32 / fade_frames as f32).cos();
+        if starts_on_step {
+            for (i, frame) in output.chunks_mut(channels).take(fade_frames).enumerate() {
+                frame.iter_mut().for_each(|s| *s *= fade(i));
+            }
+        }
+        if ends_on_step {
+            for (i, frame) in output
+                .chunks_mut(channels)
+                .rev()
+                .take(fade_frames)
+                .enumerate()
+            {
+                frame.iter_mut().for_each(|s| *s *= fade(i));
+            }
+        }
+
+        let report = ClickReport {
+            clicks: clicks.len(),
+            boundary_steps: starts_on_step as usize + ends_on_step as usize,
+        };
+        (output, report)
+    }
+
+    /// Frame ranges of the clicks, found on any channel and repaired on all of them
+    fn find_clicks(&self, samples: &[f32], channels: usize, sample_rate: u32) -> Vec<Range<usize>> {
+        let frames = samples.len() / channels;
+        if frames < 5 {
+            return Vec::new();
+        }
+
+        let mut outlier = vec![false; frames];
+        for channel in 0..channels {
+            let x = |n: usize| samples[n * channels + channel] as f64;
+
+            // The second difference is a high-pass that leaves little of tonal content
+            let residual: Vec<f64> = (0..frames)
+                .map(|n| match n {
+                    0 | 1 => 0.0,
+                    _ => (x(n) - 2.0 * x(n - 1) + x(n - 2)).abs(),
+                })
+                .collect();
+
+            for (block, flags) in residual
+                .chunks(Self::BLOCK_SIZE)
+                .zip(outlier.chunks_mut(Self::BLOCK_SIZE))
+            {
+                // The median absolute residual estimates the noise floor, unaffected by clicks
+                let sigma = median(&mut block.to_vec()).unwrap_or(0.0) / 0.6745;
+                let threshold = (self.sensitivity * sigma).max(Self::MIN_THRESHOLD);
+                for (flag, &r) in flags.iter_mut().zip(block) {
+                    *flag |= r > threshold;
+                }
+            }
+        }
+
+        // Group the outliers into clicks, leaving two clean frames on either side as anchors
+        let max_len = (Self::MAX_CLICK_MS * 0.001 * sample_rate as f64).ceil() as usize;
+        let mut clicks: Vec<Range<usize>> = Vec::new();
+        for n in (0..frames).filter(|&n| outlier[n]) {
+            match clicks.last_mut() {
+                Some(click) if n <= click.end + Self::MERGE_GAP => click.end = n + 1,
+                _ => clicks.push(n..n + 1),
+            }
+        }
+
+        clicks
+            .into_iter()
+            .filter(|click| click.len() <= max_len)
+            .map(|click| click.start.saturating_sub(1).max(2)..(click.end + 1).min(frames - 2))
+            .filter(|click| !click.is_empty())
+            .collect()
+    }
+
+    /// Whether the clip starts and ends away from silence
+    fn boundary_steps(samples: &[f32], channels: usize) -> [bool; 2] {
+        let is_step = |frame: Option<&[f32]>| {
+            frame.is_some_and(|frame| frame.iter().any(|s| s.abs() > Self::BOUNDARY_LEVEL))
+        };
+
+        [
+            is_step(samples.chunks(channels).next()),
+            is_step(samples.chunks(channels).last()),
+        ]
+    }
+
+    /// Replace the click with a cubic Hermite curve matching the slope on both sides
+    fn interpolate(samples: &mut [f32], channels: usize, channel: usize, click: &Range<usize>) {
+        let x = |samples: &[f32], n: usize| samples[n * channels + channel];
+
+        let (before, after) = (click.start - 1, click.end);
+        let (p0, p1) = (x(samples, before), x(samples, after));
+        let span = (after - before) as f32;
+        let m0 = (p0 - x(samples, before - 1)) * span;
+        let m1 = (x(samples, after + 1) - p1) * span;
+
+        for n in click.clone() {
+            let t = (n - before) as f32 / span;
+            let (t2, t3) = (t * t, t * t * t);
+            samples[n * channels + channel] = (2.0 * t3 - 3.0 * t2 + 1.0) * p0
+                + (t3 - 2.0 * t2 + t) * m0
+                + (-2.0 * t3 + 3.0 * t2) * p1
+                + (t3 - t2) * m1;
+        }
+    }
+}
+
+impl AudioProcessor for DeClicker {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let (output, report) = self.repair(samples, channels, sample_rate);
+
+        if report.total() > 0 {
+            info!(
+                "Repaired {} clicks and {} boundary steps",
+                report.clicks, report.boundary_steps
+            );
+        } else {
+            debug!("No clicks found");
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::sine;
+
+    /// A tone that starts and ends on a zero crossing
+    fn tone(sample_rate: u32) -> Vec<f32> {
+        sine(441.0, 0.5, sample_rate, sample_rate as usize + 1)
+    }
+
+    #[test]
+    fn test_repairs_clicks() {
+        let sample_rate = 44100;
+        let clean = tone(sample_rate);
+        let mut clicky = clean.clone();
+        for position in [5000, 17000, 30001] {
+            clicky[position] += 0.8;
+        }
+
+        let declicker = DeClicker::default();
+        assert_eq!(
+            declicker.detect(&clicky, 1, sample_rate),
+            ClickReport {
+                clicks: 3,
+                boundary_steps: 0
+            }
+        );
+
+        let (repaired, report) = declicker.repair(&clicky, 1, sample_rate);
+        assert_eq!(report.clicks, 3);
+        for position in [5000, 17000, 30001] {
+            let error = (repaired[position] - clean[position]).abs();
+            assert!(error < 0.01, "Click at {} left {:.3}", position, error);
+        }
+    }
+
+    #[test]
+    fn test_repairs_stereo_clicks_once() {
+        let sample_rate = 44100;
+        let mut clicky: Vec<f32> = tone(sample_rate).iter().flat_map(|&s| [s, s]).collect();
+        clicky[2 * 12000] -= 0.6;
+        clicky[2 * 12000 + 1] -= 0.6;
+
+        let (_, report) = DeClicker::default().repair(&clicky, 2, sample_rate);
+        assert_eq!(report.clicks, 1);
+    }
+
+    #[test]
+    fn test_fades_boundary_steps() {
+        let sample_rate = 44100;
+        let stepped: Vec<f32> = tone(sample_rate).iter().map(|&s| s + 0.3).collect();
+
+        let (repaired, report) = DeClicker::default().repair(&stepped, 1, sample_rate);
+        assert_eq!(report.boundary_steps, 2);
+        assert_eq!(repaired[0], 0.0);
+        assert!(repaired.last().unwrap().abs() < 0.01);
+    }
+
+    #[test]
+    fn test_leaves_clean_clips_alone() {
+        let sample_rate = 44100;
+        let clean = tone(sample_rate);
+
+        let (repaired, report) = DeClicker::default().repair(&clean, 1, sample_rate);
+        assert_eq!(report, ClickReport::default());
+        assert_eq!(repaired, clean);
+    }
+
+    #[test]
+    fn test_invalid_sensitivity() {
+        assert!(DeClicker::new(1.0).is_err());
+    }
+}
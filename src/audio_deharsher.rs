@@ -0,0 +1,214 @@
+use anyhow::{Error, Result};
+use log::debug;
+
+use crate::dsp::{band_energies, gated_band_ratio, AudioProcessor, Biquad, DynamicFilter};
+
+/// Dynamic EQ that cuts the 2–5 kHz band only while it dominates the K-weighted signal
+///
+/// Hearing is most sensitive around 2–5 kHz, so clips with a lot of energy there sound
+/// ear-piercing at the same loudness as other clips.
+pub struct DeHarsher {
+    threshold: f64,
+    max_cut: f64,
+}
+
+/// How much of a clip's K-weighted energy falls in the harsh band
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HarshnessReport {
+    /// Share of the clip's energy in the harsh band, in dB
+    pub ratio: f64,
+    /// Largest share of any single frame, in dB
+    pub peak_ratio: f64,
+    /// Whether the share exceeds the threshold
+    pub harsh: bool,
+}
+
+impl Default for DeHarsher {
+    fn default() -> Self {
+        Self {
+            threshold: Self::DEFAULT_THRESHOLD,
+            max_cut: Self::DEFAULT_MAX_CUT,
+        }
+    }
+}
+
+impl DeHarsher {
+    /// Lower and upper edge of the harsh band in Hz
+    pub const HARSH_BAND: (f64, f64) = (2000.0, 5000.0);
+    /// Share of the K-weighted energy in the harsh band above which a clip is harsh, in dB.
+    /// Broadband noise sits around -9 dB.
+    pub const DEFAULT_THRESHOLD: f64 = -6.0;
+    /// Default deepest cut in dB
+    pub const DEFAULT_MAX_CUT: f64 = 9.0;
+    /// Deepest cut allowed in dB
+    pub const MAX_CUT: f64 = 24.0;
+
+    /// Size of the FFT used to analyze the band energy
+    const ANALYSIS_FFT_SIZE: usize = 2048;
+    /// Frames this far below the loudest frame are ignored by the analysis, in dB
+    const ANALYSIS_GATE: f64 = -40.0;
+    /// Smoothing of the band and total energy detectors
+    const DETECTOR_MS: f64 = 20.0;
+    const ATTACK_MS: f64 = 2.0;
+    const RELEASE_MS: f64 = 80.0;
+    /// dB of cut per dB the band is over the threshold. Cutting the band also lowers the
+    /// total it is measured against, so it takes more than 1 dB to bring the share down by 1 dB.
+    const CUT_PER_DB: f64 = 2.0;
+
+    pub fn new(threshold: f64, max_cut: f64) -> Result<Self> {
+        if threshold >= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Threshold must be negative (got: {} dB)",
+                threshold
+            ));
+        }
+
+        if max_cut <= 0.0 || max_cut > Self::MAX_CUT {
+            return Err(anyhow::anyhow!(
+                "Maximum cut must be between 0 and {} dB (got: {} dB)",
+                Self::MAX_CUT,
+                max_cut
+            ));
+        }
+
+        Ok(Self { threshold, max_cut })
+    }
+
+    /// Measure how much energy the clip has in the harsh band
+    pub fn analyze(&self, samples: &[f32], channels: usize, sample_rate: u32) -> HarshnessReport {
+        let frames = band_energies(
+            samples,
+            channels,
+            sample_rate,
+            Self::HARSH_BAND,
+            true,
+            Self::ANALYSIS_FFT_SIZE,
+        );
+
+        let (ratio, peak_ratio) = gated_band_ratio(&frames, Self::ANALYSIS_GATE);
+
+        HarshnessReport {
+            ratio,
+            peak_ratio,
+            harsh: ratio > self.threshold,
+        }
+    }
+}
+
+impl AudioProcessor for DeHarsher {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let (low, high) = Self::HARSH_BAND;
+        let center = (low * high).sqrt();
+        let q = center / (high - low);
+
+        let dynamics = DynamicFilter {
+            threshold: self.threshold,
+            max_cut: self.max_cut,
+            cut_per_db: Self::CUT_PER_DB,
+            detector_ms: Self::DETECTOR_MS,
+            attack_ms: Self::ATTACK_MS,
+            release_ms: Self::RELEASE_MS,
+        };
+
+        // The detector listens to the harsh band of the K-weighted signal
+        let mut band_filter = Biquad::bandpass(sample_rate, center, q);
+        let (output, deepest_cut) = dynamics.apply(
+            samples,
+            channels,
+            sample_rate,
+            |_, weighted| band_filter.process(weighted),
+            |gain| Biquad::peaking(sample_rate, center, q, gain),
+        );
+
+        debug!(
+            "De-harsher cut the harsh band by up to {:.1} dB",
+            deepest_cut
+        );
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::noise;
+
+    /// Broadband noise with a strong resonance at 3.2 kHz
+    fn harsh_clip(sample_rate: u32) -> Vec<f32> {
+        let mut band = Biquad::bandpass(sample_rate, 3200.0, 2.0);
+        noise(sample_rate as usize * 2, 0.25)
+            .iter()
+            .map(|&s| 0.3 * s + 2.0 * band.process(s as f64) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn test_flags_harsh_clips() {
+        let sample_rate = 44100;
+        let deharsher = DeHarsher::default();
+
+        let harsh = deharsher.analyze(&harsh_clip(sample_rate), 1, sample_rate);
+        assert!(harsh.harsh, "Expected a harsh clip, got {:?}", harsh);
+
+        let mut lowpass = Biquad::lowpass(sample_rate, 800.0, 0.707);
+        let dull: Vec<f32> = noise(sample_rate as usize * 2, 0.25)
+            .iter()
+            .map(|&s| lowpass.process(s as f64) as f32)
+            .collect();
+        let dull = deharsher.analyze(&dull, 1, sample_rate);
+        assert!(!dull.harsh, "Expected a dull clip, got {:?}", dull);
+    }
+
+    #[test]
+    fn test_cuts_harsh_band() {
+        let sample_rate = 44100;
+        let deharsher = DeHarsher::default();
+        let clip = harsh_clip(sample_rate);
+
+        let before = deharsher.analyze(&clip, 1, sample_rate);
+        let processed = deharsher.process(&clip, 1, sample_rate).unwrap();
+        let after = deharsher.analyze(&processed, 1, sample_rate);
+
+        assert!(
+            after.ratio < before.ratio - 2.0,
+            "Expected the harsh band to drop ({:.1} dB -> {:.1} dB)",
+            before.ratio,
+            after.ratio
+        );
+    }
+
+    #[test]
+    fn test_leaves_balanced_clips_alone() {
+        let sample_rate = 44100;
+        let mut lowpass = Biquad::lowpass(sample_rate, 800.0, 0.707);
+        let clip: Vec<f32> = noise(sample_rate as usize, 0.25)
+            .iter()
+            .map(|&s| lowpass.process(s as f64) as f32)
+            .collect();
+
+        let deharsher = DeHarsher::default();
+        let processed = deharsher.process(&clip, 1, sample_rate).unwrap();
+        let before = deharsher.analyze(&clip, 1, sample_rate);
+        let after = deharsher.analyze(&processed, 1, sample_rate);
+
+        assert!(
+            (before.ratio - after.ratio).abs() < 0.1,
+            "Expected no change ({:.2} dB -> {:.2} dB)",
+            before.ratio,
+            after.ratio
+        );
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(DeHarsher::new(0.0, 6.0).is_err());
+        assert!(DeHarsher::new(-6.0, 0.0).is_err());
+        assert!(DeHarsher::new(-6.0, 30.0).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use anyhow::{Error, Result};
+use log::debug;
+use num_complex::Complex32;
+
+use crate::dsp::{db_to_linear, hann_window, inverse_spectrum, spectrum, AudioProcessor};
+
+/// Spectral noise reduction that learns the noise from the clip itself
+///
+/// The noise profile is the average spectrum of the quietest frames, or of a given time
+/// range, and every frame is Wiener filtered against it. Run it before the [`Normalizer`]
+/// so the hiss of quiet voice clips is not brought up with them.
+///
+/// [`Normalizer`]: crate::audio_normalizer::Normalizer
+pub struct DeNoiser {
+    strength: f64,
+    smoothing: f64,
+    noise_range: Option<(f64, f64)>,
+}
+
+impl Default for DeNoiser {
+    fn default() -> Self {
+        Self {
+            strength: Self::DEFAULT_STRENGTH,
+            smoothing: Self::DEFAULT_SMOOTHING,
+            noise_range: None,
+        }
+    }
+}
+
+impl DeNoiser {
+    /// Default deepest reduction of a bin in dB
+    pub const DEFAULT_STRENGTH: f64 = 12.0;
+    /// Deepest reduction allowed in dB
+    pub const MAX_STRENGTH: f64 = 40.0;
+    /// Default share of the previous frame's gain kept in each frame
+    pub const DEFAULT_SMOOTHING: f64 = 0.5;
+
+    /// Share of the frames, quietest first, taken to be noise
+    const QUIET_FRACTION: f64 = 0.1;
+    /// How many times the noise estimate is subtracted, to also catch noise peaks
+    const OVERSUBTRACTION: f64 = 2.0;
+    /// Frames below this power are digital silence and say nothing about the noise
+    const SILENCE_POWER: f64 = 1e-10;
+
+    /// `strength` is the deepest reduction in dB, `smoothing` between 0 and 1 how much
+    /// each frame's gains follow the previous frame, which keeps the residual noise from
+    /// warbling
+    pub fn new(strength: f64, smoothing: f64) -> Result<Self> {
+        if strength <= 0.0 || strength > Self::MAX_STRENGTH {
+            return Err(anyhow::anyhow!(
+                "Strength must be between 0 and {} dB (got: {} dB)",
+                Self::MAX_STRENGTH,
+                strength
+            ));
+        }
+
+        if !(0.0..1.0).contains(&smoothing) {
+            return Err(anyhow::anyhow!(
+                "Smoothing must be at least 0 and below 1 (got: {})",
+                smoothing
+            ));
+        }
+
+        Ok(Self {
+            strength,
+            smoothing,
+            noise_range: None,
+        })
+    }
+
+    /// Learn the noise from the frames between `start` and `end` seconds instead of the
+    /// quietest frames
+    pub fn with_noise_range(mut self, start: f64, end: f64) -> Result<Self> {
+        if start < 0.0 || end <= start {
+            return Err(anyhow::anyhow!(
+                "Noise range must start at or after 0 s and end after it starts (got: {} s to {} s)",
+                start,
+                end
+            ));
+        }
+
+        self.noise_range = Some((start, end));
+        Ok(self)
+    }
+
+    /// About 20 ms, enough to resolve hiss without smearing speech
+    fn fft_size(sample_rate: u32) -> usize {
+        (sample_rate as usize / 50).next_power_of_two()
+    }
+
+    /// Indices of the frames that make up the noise profile, given the power and the
+    /// centre in seconds of every frame
+    fn noise_frames(&self, powers: &[f64], centers: &[f64]) -> Result<Vec<usize>> {
+        if let Some((start, end)) = self.noise_range {
+            let frames: Vec<usize> = (0..centers.len())
+                .filter(|&i| (start..=end).contains(&centers[i]))
+                .collect();
+            if frames.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Noise range {} s to {} s holds no frames of the clip",
+                    start,
+                    end
+                ));
+            }
+            return Ok(frames);
+        }
+
+        let mut frames: Vec<usize> = (0..powers.len())
+            .filter(|&i| powers[i] > Self::SILENCE_POWER)
+            .collect();
+        frames.sort_by(|&a, &b| powers[a].total_cmp(&powers[b]));
+        let quiet = ((frames.len() as f64 * Self::QUIET_FRACTION).ceil() as usize).max(1);
+        frames.truncate(quiet);
+        Ok(frames)
+    }
+}
+
+impl AudioProcessor for DeNoiser {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let frames = samples.len() / channels;
+        let fft_size = Self::fft_size(sample_rate);
+        let hop = fft_size / 2;
+
+        // Square-root Hann windows on analysis and synthesis overlap-add back to unity at
+        // half-frame hops
+        let window: Vec<f32> = hann_window(fft_size).iter().map(|w| w.sqrt()).collect();
+
+        // Pad half a frame on both sides so every sample is covered by two frames
+        let padded: Vec<Vec<f32>> = (0..channels)
+            .map(|channel| {
+                let mut padded = vec![0.0; hop];
+                padded.extend(samples.iter().skip(channel).step_by(channels));
+                padded.resize(hop + frames + fft_size, 0.0);
+                padded
+            })
+            .collect();
+        let starts: Vec<usize> = (0..)
+            .map(|i| i * hop)
+            .take_while(|&p| p < hop + frames)
+            .collect();
+
+        let spectra: Vec<Vec<Vec<Complex32>>> = padded
+            .iter()
+            .map(|channel| {
+                starts
+                    .iter()
+                    .map(|&p| spectrum(&channel[p..p + fft_size], &window, fft_size))
+                    .collect()
+            })
+            .collect();
+
+        let power = |bin: &Complex32| bin.norm_sqr() as f64;
+        let frame_powers: Vec<f64> = (0..starts.len())
+            .map(|i| {
+                spectra
+                    .iter()
+                    .map(|c| c[i].iter().map(power).sum::<f64>())
+                    .sum()
+            })
+            .collect();
+        // Frame i covers padded samples starting at i * hop, so its centre is sample i * hop
+        let centers: Vec<f64> = starts
+            .iter()
+            .map(|&p| p as f64 / sample_rate as f64)
+            .collect();
+
+        let noise_frames = self.noise_frames(&frame_powers, &centers)?;
+        if noise_frames.is_empty() {
+            debug!("Clip is silent, nothing to denoise");
+            return Ok(samples.to_vec());
+        }
+
+        let bins = fft_size / 2 + 1;
+        let mut noise = vec![0.0_f64; bins];
+        for channel in &spectra {
+            for &i in &noise_frames {
+                for (n, bin) in noise.iter_mut().zip(&channel[i]) {
+                    *n += power(bin);
+                }
+            }
+        }
+        let count = (noise_frames.len() * channels) as f64;
+        noise.iter_mut().for_each(|n| *n /= count);
+
+        debug!(
+            "Learned noise profile from {} frames of {} samples",
+            noise_frames.len(),
+            fft_size
+        );
+
+        let floor = db_to_linear(-self.strength);
+        let mut output = vec![0.0_f32; samples.len()];
+        for (channel, channel_spectra) in spectra.iter().enumerate() {
+            let mut restored = vec![0.0_f32; hop + frames + fft_size];
+            let mut gains = vec![1.0_f64; bins];
+
+            for (&start, frame) in starts.iter().zip(channel_spectra) {
+                let filtered: Vec<Complex32> = frame
+                    .iter()
+                    .zip(&noise)
+                    .zip(gains.iter_mut())
+                    .map(|((bin, &noise), gain)| {
+                        let wiener = match power(bin) {
+                            p if p > 0.0 => (1.0 - Self::OVERSUBTRACTION * noise / p).max(floor),
+                            _ => floor,
+                        };
+                        *gain = self.smoothing * *gain + (1.0 - self.smoothing) * wiener;
+                        bin * *gain as f32
+                    })
+                    .collect();
+
+                for ((out, s), w) in restored[start..start + fft_size]
+                    .iter_mut()
+                    .zip(inverse_spectrum(&filtered))
+                    .zip(&window)
+                {
+                    *out += s * w;
+                }
+            }
+
+            for (frame, &s) in restored[hop..hop + frames].iter().enumerate() {
+                output[frame * channels + channel] = s;
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::rms_db;
+    use crate::test_signals::noise;
+
+    /// Half a second of hiss, a second of tone over the hiss, then half a second of hiss
+    fn hissy_tone(sample_rate: u32) -> Vec<f32> {
+        let sr = sample_rate as usize;
+        noise(sr * 2, 0.02)
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| {
+                let tone = if (sr / 2..sr * 3 / 2).contains(&i) {
+                    0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / sample_rate as f32).sin()
+                } else {
+                    0.0
+                };
+                tone + n
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reduces_hiss_and_keeps_tone() {
+        let sample_rate = 44100;
+        let sr = sample_rate as usize;
+        let clip = hissy_tone(sample_rate);
+        let processed = DeNoiser::default().process(&clip, 1, sample_rate).unwrap();
+        assert_eq!(processed.len(), clip.len());
+
+        let hiss_drop = rms_db(&clip[..sr / 4]) - rms_db(&processed[..sr / 4]);
+        assert!(
+            hiss_drop > 6.0,
+            "Expected the hiss to drop, got {:.1} dB",
+            hiss_drop
+        );
+
+        let tone_change =
+            rms_db(&processed[sr * 3 / 4..sr * 5 / 4]) - rms_db(&clip[sr * 3 / 4..sr * 5 / 4]);
+        assert!(
+            tone_change.abs() < 1.0,
+            "Expected the tone to stay, got {:.1} dB",
+            tone_change
+        );
+    }
+
+    #[test]
+    fn test_noise_range() {
+        let sample_rate = 44100;
+        let clip: Vec<f32> = hissy_tone(sample_rate)
+            .iter()
+            .flat_map(|&s| [s, s])
+            .collect();
+
+        let denoiser = DeNoiser::default().with_noise_range(0.0, 0.4).unwrap();
+        let processed = denoiser.process(&clip, 2, sample_rate).unwrap();
+        assert!(
+            rms_db(&processed[..sample_rate as usize / 2])
+                < rms_db(&clip[..sample_rate as usize / 2]) - 6.0
+        );
+
+        let outside = DeNoiser::default().with_noise_range(5.0, 6.0).unwrap();
+        assert!(outside.process(&clip, 2, sample_rate).is_err());
+    }
+
+    #[test]
+    fn test_silence_passes_through() {
+        let silence = vec![0.0; 44100];
+        let processed = DeNoiser::default().process(&silence, 1, 44100).unwrap();
+        assert_eq!(processed, silence);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(DeNoiser::new(0.0, 0.5).is_err());
+        assert!(DeNoiser::new(50.0, 0.5).is_err());
+        assert!(DeNoiser::new(12.0, 1.0).is_err());
+        assert!(DeNoiser::default().with_noise_range(1.0, 0.5).is_err());
+    }
+}
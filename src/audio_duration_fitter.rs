@@ -0,0 +1,189 @@
+use anyhow::{Error, Result};
+use log::{debug, info};
+
+use crate::dsp::{time_stretch, AudioProcessor, TimeStretcher};
+
+/// Fits clips into the soundboard's duration limit
+///
+/// Clips that are too long are first sped up, keeping their pitch, by at most
+/// `max_speed_up`. Whatever still does not fit is trimmed off the end behind a short
+/// fade. Clips that fit already are passed through untouched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DurationFitter {
+    max_duration: f64,
+    max_speed_up: f64,
+}
+
+/// How a clip was fitted
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DurationFit {
+    /// Factor the clip was sped up by, 1 when it was not stretched
+    pub speed_up: f64,
+    /// Seconds cut off the end after stretching
+    pub trimmed: f64,
+}
+
+impl Default for DurationFitter {
+    fn default() -> Self {
+        Self {
+            max_duration: Self::DISCORD_MAX_DURATION,
+            max_speed_up: Self::DEFAULT_MAX_SPEED_UP,
+        }
+    }
+}
+
+impl DurationFitter {
+    /// Longest sound Discord accepts on the soundboard, in seconds
+    pub const DISCORD_MAX_DURATION: f64 = 5.2;
+    /// Default fastest speed-up before the clip is trimmed instead
+    pub const DEFAULT_MAX_SPEED_UP: f64 = 1.15;
+
+    /// Length of the fade at a trimmed end
+    const FADE_MS: f64 = 50.0;
+
+    /// `max_duration` in seconds, `max_speed_up` the fastest the clip may be played back
+    /// as a factor of its original speed, 1 to only trim
+    pub fn new(max_duration: f64, max_speed_up: f64) -> Result<Self> {
+        if max_duration <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Maximum duration must be positive (got: {} s)",
+                max_duration
+            ));
+        }
+
+        let fastest = 1.0 / TimeStretcher::MIN_RATIO;
+        if !(1.0..=fastest).contains(&max_speed_up) {
+            return Err(anyhow::anyhow!(
+                "Maximum speed-up must be between 1 and {} (got: {})",
+                fastest,
+                max_speed_up
+            ));
+        }
+
+        Ok(Self {
+            max_duration,
+            max_speed_up,
+        })
+    }
+
+    /// Fit the clip, returning the samples and how they were fitted, or `None` when the
+    /// clip fits already
+    pub fn fit(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<(Vec<f32>, Option<DurationFit>), Error> {
+        let frames = samples.len() / channels;
+        let max_frames = (self.max_duration * sample_rate as f64).floor() as usize;
+        if frames <= max_frames {
+            return Ok((samples.to_vec(), None));
+        }
+
+        let speed_up = (frames as f64 / max_frames as f64).min(self.max_speed_up);
+        let mut output = if speed_up > 1.0 {
+            time_stretch(samples, channels, sample_rate, 1.0 / speed_up)?
+        } else {
+            samples.to_vec()
+        };
+
+        let stretched_frames = output.len() / channels;
+        if stretched_frames > max_frames {
+            output.truncate(max_frames * channels);
+            let fade_frames = ((Self::FADE_MS * 0.001 * sample_rate as f64) as usize)
+                .min(max_frames)
+                .max(1);
+            let fade =
+                |i: usize| 0.5 - 0.5 * (std::f32::consts::PI * i as f32 / fade_frames as f32).cos();
+            for (i, frame) in output
+                .chunks_mut(channels)
+                .rev()
+                .take(fade_frames)
+                .enumerate()
+            {
+                frame.iter_mut().for_each(|s| *s *= fade(i));
+            }
+        }
+
+        let fit = DurationFit {
+            speed_up,
+            trimmed: stretched_frames.saturating_sub(max_frames) as f64 / sample_rate as f64,
+        };
+        Ok((output, Some(fit)))
+    }
+}
+
+impl AudioProcessor for DurationFitter {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let (output, fit) = self.fit(samples, channels, sample_rate)?;
+
+        match fit {
+            Some(fit) => info!(
+                "Fitted clip to {} s by speeding it up {:.2}x and trimming {:.2} s",
+                self.max_duration, fit.speed_up, fit.trimmed
+            ),
+            None => debug!("Clip fits in {} s", self.max_duration),
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::sine;
+
+    #[test]
+    fn test_stretches_slightly_long_clips() {
+        let sample_rate = 44100;
+        let clip: Vec<f32> = sine(330.0, 0.5, sample_rate, (5.7 * sample_rate as f64) as usize)
+            .iter()
+            .flat_map(|&s| [s, s])
+            .collect();
+
+        let (fitted, fit) = DurationFitter::default()
+            .fit(&clip, 2, sample_rate)
+            .unwrap();
+        let fit = fit.unwrap();
+        assert!((fit.speed_up - 5.7 / 5.2).abs() < 1e-3);
+        assert_eq!(fit.trimmed, 0.0);
+        assert!(fitted.len() / 2 <= (5.2 * sample_rate as f64) as usize);
+    }
+
+    #[test]
+    fn test_trims_what_does_not_fit() {
+        let sample_rate = 44100;
+        let clip = sine(330.0, 0.5, sample_rate, sample_rate as usize * 8);
+
+        let (fitted, fit) = DurationFitter::default()
+            .fit(&clip, 1, sample_rate)
+            .unwrap();
+        let fit = fit.unwrap();
+        assert_eq!(fit.speed_up, DurationFitter::DEFAULT_MAX_SPEED_UP);
+        assert!((fit.trimmed - (8.0 / 1.15 - 5.2)).abs() < 0.01);
+        assert_eq!(fitted.len(), (5.2 * sample_rate as f64) as usize);
+        assert!(fitted.last().unwrap().abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_leaves_short_clips_alone() {
+        let clip = sine(330.0, 0.5, 44100, 44100 * 2);
+        let (fitted, fit) = DurationFitter::default().fit(&clip, 1, 44100).unwrap();
+        assert_eq!(fit, None);
+        assert_eq!(fitted, clip);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(DurationFitter::new(0.0, 1.1).is_err());
+        assert!(DurationFitter::new(5.2, 0.9).is_err());
+        assert!(DurationFitter::new(5.2, 3.0).is_err());
+        assert!(DurationFitter::new(5.2, 1.0).is_ok());
+    }
+}
@@ -1,10 +1,22 @@
 use anyhow::{Context, Result};
-use log::debug;
-use mp3lame_encoder::{Bitrate, Builder, DualPcm, FlushNoGap, Quality};
+#[cfg(feature = "opus")]
+use base64::{engine::general_purpose::STANDARD as base64, Engine};
+use ebur128::{EbuR128, Mode};
+use log::{debug, info};
+use mp3lame_encoder::{Bitrate, BuildError, Builder, DualPcm, FlushGap, Quality, VbrMode};
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use symphonia::core::codecs::{
+    CODEC_TYPE_PCM_F32BE, CODEC_TYPE_PCM_F32LE, CODEC_TYPE_PCM_F64BE, CODEC_TYPE_PCM_F64LE,
+};
 use symphonia::core::formats::Track;
+use symphonia::core::sample::SampleFormat;
+
+use crate::audio_metadata::Metadata;
+use crate::dsp::{downmix_to_mono, linear_to_db, resample};
+use crate::sample_format::{quantize, to_i16, Dither};
 
 /// Common interface for different audio file types
 pub trait AudioFile {
@@ -12,7 +24,302 @@ pub trait AudioFile {
     fn write_to_buffer(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>>;
 }
 
-pub struct Mp3File;
+/// How the bitrate of MP3 files is chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mp3Mode {
+    /// Constant bitrate, every frame the same size
+    #[default]
+    Cbr,
+    /// Average bitrate, spending more on hard passages while keeping to a target
+    Abr,
+    /// Variable bitrate, following a quality target instead of a bitrate
+    Vbr,
+}
+
+/// How the channels of stereo MP3 files are coded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mp3Stereo {
+    /// Pick left/right or mid/side coding frame by frame
+    #[default]
+    Joint,
+    /// Always code left and right separately
+    Separate,
+    /// Always code mid and side, the smallest for near-mono clips
+    Forced,
+}
+
+/// Settings of the LAME MP3 encoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mp3Options {
+    mode: Mp3Mode,
+    bitrate: u32,
+    vbr_quality: u8,
+    stereo: Mp3Stereo,
+    lowpass: Option<u32>,
+    quality: u8,
+}
+
+impl Default for Mp3Options {
+    fn default() -> Self {
+        Self {
+            mode: Mp3Mode::default(),
+            bitrate: Self::DEFAULT_BITRATE,
+            vbr_quality: Self::DEFAULT_VBR_QUALITY,
+            stereo: Mp3Stereo::default(),
+            lowpass: None,
+            quality: 0,
+        }
+    }
+}
+
+impl Mp3Options {
+    /// Default bitrate in kbps of CBR and ABR encoding
+    pub const DEFAULT_BITRATE: u32 = 192;
+    /// Bitrates in kbps an MP3 frame can have, and so the choices for CBR encoding
+    pub const BITRATES: [u32; 16] = [
+        8, 16, 24, 32, 40, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    /// Default VBR quality, around 190 kbps for most clips
+    pub const DEFAULT_VBR_QUALITY: u8 = 2;
+    /// Lowest VBR and encoder quality, 0 being the best
+    pub const WORST_QUALITY: u8 = 9;
+
+    /// `bitrate` in kbps applies to CBR and ABR encoding, `vbr_quality` from 0, the best,
+    /// to 9 to VBR encoding. Either falls back to its default when not given.
+    pub fn new(mode: Mp3Mode, bitrate: Option<u32>, vbr_quality: Option<u8>) -> Result<Self> {
+        match (mode, bitrate, vbr_quality) {
+            (Mp3Mode::Vbr, Some(bitrate), _) => {
+                return Err(anyhow::anyhow!(
+                    "VBR encoding follows a quality instead of a bitrate (got: {} kbps)",
+                    bitrate
+                ))
+            }
+            (Mp3Mode::Cbr | Mp3Mode::Abr, _, Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "A VBR quality only applies to VBR encoding, use a bitrate for {:?}",
+                    mode
+                ))
+            }
+            (Mp3Mode::Cbr, Some(bitrate), _) if !Self::BITRATES.contains(&bitrate) => {
+                return Err(anyhow::anyhow!(
+                    "CBR bitrate must be one of {:?} kbps (got: {} kbps)",
+                    Self::BITRATES,
+                    bitrate
+                ))
+            }
+            (Mp3Mode::Abr, Some(bitrate), _) if !(8..=320).contains(&bitrate) => {
+                return Err(anyhow::anyhow!(
+                    "ABR bitrate must be between 8 and 320 kbps (got: {} kbps)",
+                    bitrate
+                ))
+            }
+            (_, _, Some(quality)) if quality > Self::WORST_QUALITY => {
+                return Err(anyhow::anyhow!(
+                    "VBR quality must be between 0 and {} (got: {})",
+                    Self::WORST_QUALITY,
+                    quality
+                ))
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            mode,
+            bitrate: bitrate.unwrap_or(Self::DEFAULT_BITRATE),
+            vbr_quality: vbr_quality.unwrap_or(Self::DEFAULT_VBR_QUALITY),
+            ..Self::default()
+        })
+    }
+
+    /// How the channels of stereo clips are coded
+    pub fn with_stereo(mut self, stereo: Mp3Stereo) -> Self {
+        self.stereo = stereo;
+        self
+    }
+
+    /// Cut everything above `frequency` Hz instead of letting LAME pick the lowpass from
+    /// the bitrate
+    pub fn with_lowpass(mut self, frequency: u32) -> Result<Self> {
+        if frequency == 0 {
+            return Err(anyhow::anyhow!("Lowpass frequency must be above 0 Hz"));
+        }
+
+        self.lowpass = Some(frequency);
+        Ok(self)
+    }
+
+    /// Encoder quality preset from 0, the best and slowest, to 9, the fastest
+    pub fn with_quality(mut self, quality: u8) -> Result<Self> {
+        if quality > Self::WORST_QUALITY {
+            return Err(anyhow::anyhow!(
+                "MP3 quality must be between 0 and {} (got: {})",
+                Self::WORST_QUALITY,
+                quality
+            ));
+        }
+
+        self.quality = quality;
+        Ok(self)
+    }
+
+    /// LAME quality of a 0 to 9 setting
+    fn lame_quality(quality: u8) -> Quality {
+        match quality {
+            0 => Quality::Best,
+            1 => Quality::SecondBest,
+            2 => Quality::NearBest,
+            3 => Quality::VeryNice,
+            4 => Quality::Nice,
+            5 => Quality::Good,
+            6 => Quality::Decent,
+            7 => Quality::Ok,
+            8 => Quality::SecondWorst,
+            _ => Quality::Worst,
+        }
+    }
+
+    /// LAME bitrate of a CBR setting
+    fn lame_bitrate(bitrate: u32) -> Bitrate {
+        match bitrate {
+            8 => Bitrate::Kbps8,
+            16 => Bitrate::Kbps16,
+            24 => Bitrate::Kbps24,
+            32 => Bitrate::Kbps32,
+            40 => Bitrate::Kbps40,
+            48 => Bitrate::Kbps48,
+            64 => Bitrate::Kbps64,
+            80 => Bitrate::Kbps80,
+            96 => Bitrate::Kbps96,
+            112 => Bitrate::Kbps112,
+            128 => Bitrate::Kbps128,
+            160 => Bitrate::Kbps160,
+            224 => Bitrate::Kbps224,
+            256 => Bitrate::Kbps256,
+            320 => Bitrate::Kbps320,
+            _ => Bitrate::Kbps192,
+        }
+    }
+}
+
+/// One step down in quality when an MP3 has to fit a size budget
+struct BudgetRung {
+    /// Most channels kept
+    channels: usize,
+    /// Highest sample rate kept
+    sample_rate: u32,
+    /// CBR bitrate in kbps
+    bitrate: u32,
+}
+
+/// Settings tried in order when an MP3 does not fit its size budget. Lower bitrates are
+/// tried before stereo is given up, and stereo before bandwidth.
+const BUDGET_LADDER: [BudgetRung; 19] = [
+    BudgetRung::new(2, u32::MAX, 256),
+    BudgetRung::new(2, u32::MAX, 224),
+    BudgetRung::new(2, u32::MAX, 192),
+    BudgetRung::new(2, u32::MAX, 160),
+    BudgetRung::new(2, u32::MAX, 128),
+    BudgetRung::new(2, u32::MAX, 112),
+    BudgetRung::new(2, u32::MAX, 96),
+    BudgetRung::new(1, u32::MAX, 96),
+    BudgetRung::new(1, u32::MAX, 80),
+    BudgetRung::new(1, u32::MAX, 64),
+    BudgetRung::new(1, 32000, 48),
+    BudgetRung::new(1, 32000, 40),
+    BudgetRung::new(1, 32000, 32),
+    BudgetRung::new(1, 22050, 32),
+    BudgetRung::new(1, 22050, 24),
+    BudgetRung::new(1, 22050, 16),
+    BudgetRung::new(1, 16000, 16),
+    BudgetRung::new(1, 16000, 8),
+    BudgetRung::new(1, 8000, 8),
+];
+
+impl BudgetRung {
+    const fn new(channels: usize, sample_rate: u32, bitrate: u32) -> Self {
+        Self {
+            channels,
+            sample_rate,
+            bitrate,
+        }
+    }
+}
+
+/// Fold a clip down to at most `channels` channels and `sample_rate` Hz
+fn reduce(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    max_channels: usize,
+    max_sample_rate: u32,
+) -> Result<(Cow<'_, [f32]>, usize, u32)> {
+    let mut reduced = Cow::Borrowed(samples);
+    let mut reduced_channels = channels;
+    if channels > max_channels {
+        reduced = Cow::Owned(downmix_to_mono(samples, channels));
+        reduced_channels = 1;
+    }
+
+    let reduced_rate = sample_rate.min(max_sample_rate);
+    if reduced_rate != sample_rate {
+        reduced = Cow::Owned(resample(
+            &reduced,
+            reduced_channels,
+            reduced_rate as f64 / sample_rate as f64,
+        )?);
+    }
+    Ok((reduced, reduced_channels, reduced_rate))
+}
+
+/// LAME encoder that can also read back the Xing/LAME tag after the last frame
+struct LameEncoder {
+    encoder: mp3lame_encoder::Encoder,
+}
+
+// mp3lame-encoder keeps the settings handle of a built encoder private, and the encoder is
+// nothing but that handle
+const _: () = assert!(
+    std::mem::size_of::<mp3lame_encoder::Encoder>()
+        == std::mem::size_of::<*mut mp3lame_encoder::ffi::lame_global_flags>()
+);
+
+impl LameEncoder {
+    /// Largest MP3 frame, 320 kbps at 32 kHz with padding
+    const MAX_FRAME: usize = 1441;
+
+    /// Settings of the encoder, valid for as long as the encoder is
+    fn flags(&self) -> *mut mp3lame_encoder::ffi::lame_global_flags {
+        let encoder: *const mp3lame_encoder::Encoder = &self.encoder;
+        // SAFETY: the encoder holds a single non-null pointer to its settings and, as the
+        // size check above makes sure, nothing else, so its bytes are that pointer. Copying
+        // it out leaves the encoder the only owner.
+        unsafe { *encoder.cast::<*mut mp3lame_encoder::ffi::lame_global_flags>() }
+    }
+
+    /// The Xing/LAME tag frame, with the frame count, encoder delay and padding of
+    /// everything encoded so far
+    fn tag_frame(&self) -> Vec<u8> {
+        let mut frame = vec![0; Self::MAX_FRAME];
+        // SAFETY: the settings belong to the live encoder borrowed by `self`, and LAME
+        // writes at most `frame.len()` bytes into the buffer
+        let size = unsafe {
+            mp3lame_encoder::ffi::lame_get_lametag_frame(
+                self.flags(),
+                frame.as_mut_ptr(),
+                frame.len(),
+            )
+        };
+        frame.truncate(if size <= Self::MAX_FRAME { size } else { 0 });
+        frame
+    }
+}
+
+pub struct Mp3File {
+    options: Mp3Options,
+    max_size: Option<usize>,
+    metadata: Metadata,
+    dither: Dither,
+}
 
 impl Default for Mp3File {
     fn default() -> Self {
@@ -22,29 +329,223 @@ impl Default for Mp3File {
 
 impl Mp3File {
     pub fn new() -> Self {
-        Self
+        Self {
+            options: Mp3Options::default(),
+            max_size: None,
+            metadata: Metadata::default(),
+            dither: Dither::default(),
+        }
     }
 
-    /// Configures the LAME MP3 encoder with optimal settings
-    fn configure_encoder(channels: usize, sample_rate: u32) -> Result<mp3lame_encoder::Encoder> {
-        let mut builder = Builder::new().context("Failed to create LAME builder")?;
-        let _ = builder.set_num_channels(channels as u8);
-        let _ = builder.set_sample_rate(sample_rate);
-        let _ = builder.set_brate(Bitrate::Kbps192);
-        let _ = builder.set_quality(Quality::Best);
+    /// Encode with `options` instead of 192 kbps CBR at the best quality
+    pub fn with_options(mut self, options: Mp3Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Keep files within `max_size` bytes, stepping down to lower bitrates, mono and
+    /// lower sample rates for clips that do not fit with the options, and failing for
+    /// clips that fit with none of them
+    pub fn with_max_size(mut self, max_size: usize) -> Result<Self> {
+        if max_size == 0 {
+            return Err(anyhow::anyhow!("Maximum file size must be above 0 bytes"));
+        }
+
+        self.max_size = Some(max_size);
+        Ok(self)
+    }
+
+    /// Write the tags and pictures in an ID3v2 tag in front of the audio, which counts
+    /// towards the size budget
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Dither used when converting to 16-bit samples, [`Dither::None`] for output that
+    /// follows the input exactly
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Encode a clip, within the size budget when there is one
+    fn encode(&self, samples: &[f32], channels: usize, sample_rate: u32) -> Result<Vec<u8>> {
+        let encoded = self.encode_with(&self.options, samples, channels, sample_rate)?;
+        let max_size = match self.max_size {
+            Some(max_size) if encoded.len() > max_size => max_size,
+            _ => return Ok(encoded),
+        };
+
+        // CBR sizes follow the bitrate, so settings that cannot fit are skipped unencoded
+        let seconds = (samples.len() / channels) as f64 / sample_rate as f64;
+        let start = match self.options.mode {
+            Mp3Mode::Vbr => u32::MAX,
+            _ => self.options.bitrate,
+        };
+        for rung in BUDGET_LADDER.iter().filter(|rung| rung.bitrate < start) {
+            if rung.bitrate as f64 * 125.0 * seconds > max_size as f64 {
+                continue;
+            }
+
+            let (reduced, reduced_channels, reduced_rate) = reduce(
+                samples,
+                channels,
+                sample_rate,
+                rung.channels,
+                rung.sample_rate,
+            )?;
+            let options = Mp3Options {
+                mode: Mp3Mode::Cbr,
+                bitrate: rung.bitrate,
+                lowpass: None,
+                ..self.options
+            };
+            let encoded = self.encode_with(&options, &reduced, reduced_channels, reduced_rate)?;
+            if encoded.len() <= max_size {
+                info!(
+                    "Fitted MP3 in {} bytes at {} kbps, {} channels, {} Hz",
+                    encoded.len(),
+                    rung.bitrate,
+                    reduced_channels,
+                    reduced_rate
+                );
+                return Ok(encoded);
+            }
+        }
 
-        Ok(builder.build().unwrap())
+        Err(anyhow::anyhow!(
+            "Clip of {:.1} s does not fit in {} bytes, even as the smallest MP3 (got: {} bytes with the options)",
+            seconds,
+            max_size,
+            encoded.len()
+        ))
     }
 
-    /// Converts normalized float samples to 16-bit integer samples
-    fn convert_samples_to_i16(samples: &[f32]) -> Vec<i16> {
-        /// The range of values for 16-bit PCM audio samples
-        const I16_RANGE: (f32, f32) = (-32768.0, 32767.0);
+    /// Encode samples with `options` into a whole file, tags included
+    fn encode_with(
+        &self,
+        options: &Mp3Options,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<u8>> {
+        let encoder = Self::configure_encoder(options, channels, sample_rate)?;
+        let samples_i16 = to_i16(samples, channels, self.dither);
+        let (left, right) = Self::split_channels(&samples_i16, channels);
+
+        let mut file = self.metadata.id3v2();
+        file.extend(Self::encode_to_buffer(encoder, &left, &right)?);
+        Ok(file)
+    }
+
+    /// Configures the LAME MP3 encoder with the options
+    fn configure_encoder(
+        options: &Mp3Options,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<LameEncoder> {
+        if !(1..=2).contains(&channels) {
+            return Err(anyhow::anyhow!(
+                "MP3 supports mono and stereo clips (got: {} channels)",
+                channels
+            ));
+        }
+        if let Some(lowpass) = options.lowpass {
+            if lowpass >= sample_rate / 2 {
+                return Err(anyhow::anyhow!(
+                    "Lowpass frequency must be below half the sample rate ({} Hz >= {} Hz)",
+                    lowpass,
+                    sample_rate / 2
+                ));
+            }
+        }
+
+        let check = |setting: &str, result: Result<(), BuildError>| {
+            result.map_err(|e| anyhow::anyhow!("Failed to set MP3 {}: {}", setting, e))
+        };
+        let check_raw = |setting: &str, code: i32| {
+            check(
+                setting,
+                match code {
+                    0.. => Ok(()),
+                    _ => Err(BuildError::Other(code)),
+                },
+            )
+        };
+
+        let mut builder = Builder::new().context("Failed to create LAME builder")?;
+        check("channels", builder.set_num_channels(channels as u8))?;
+        check("sample rate", builder.set_sample_rate(sample_rate))?;
+        check(
+            "quality",
+            builder.set_quality(Mp3Options::lame_quality(options.quality)),
+        )?;
+
+        let mode = match (channels, options.stereo) {
+            (1, _) => mp3lame_encoder::Mode::Mono,
+            (_, Mp3Stereo::Separate) => mp3lame_encoder::Mode::Stereo,
+            _ => mp3lame_encoder::Mode::JointStereo,
+        };
+        check("stereo mode", builder.set_mode(mode))?;
+        if channels == 2 && options.stereo == Mp3Stereo::Forced {
+            // SAFETY: the builder owns a live LAME handle until it is built, and the setter
+            // only stores the flag in it
+            let code = unsafe { mp3lame_encoder::ffi::lame_set_force_ms(builder.as_ptr(), 1) };
+            check_raw("forced mid/side", code)?;
+        }
+
+        match options.mode {
+            Mp3Mode::Cbr => {
+                check("VBR mode", builder.set_vbr_mode(VbrMode::Off))?;
+                check(
+                    "bitrate",
+                    builder.set_brate(Mp3Options::lame_bitrate(options.bitrate)),
+                )?;
+            }
+            Mp3Mode::Abr => {
+                check("VBR mode", builder.set_vbr_mode(VbrMode::Abr))?;
+                // SAFETY: the builder owns a live LAME handle until it is built, and the
+                // setter only stores the bitrate in it
+                let code = unsafe {
+                    mp3lame_encoder::ffi::lame_set_VBR_mean_bitrate_kbps(
+                        builder.as_ptr(),
+                        options.bitrate as i32,
+                    )
+                };
+                check_raw("average bitrate", code)?;
+            }
+            Mp3Mode::Vbr => {
+                check("VBR mode", builder.set_vbr_mode(VbrMode::Mtrh))?;
+                check(
+                    "VBR quality",
+                    builder.set_vbr_quality(Mp3Options::lame_quality(options.vbr_quality)),
+                )?;
+            }
+        }
+
+        if let Some(lowpass) = options.lowpass {
+            // SAFETY: the builder owns a live LAME handle until it is built, and the setter
+            // only stores the frequency in it
+            let code = unsafe {
+                mp3lame_encoder::ffi::lame_set_lowpassfreq(builder.as_ptr(), lowpass as i32)
+            };
+            check_raw("lowpass", code)?;
+        }
+
+        // LAME leaves the first frame empty for the tag that tells decoders how much
+        // delay and padding to drop
+        check("Xing/LAME tag", builder.set_to_write_vbr_tag(true))?;
 
-        samples
-            .iter()
-            .map(|&x| (x * I16_RANGE.1).clamp(I16_RANGE.0, I16_RANGE.1) as i16)
-            .collect()
+        let encoder = builder.build().map_err(|e| {
+            anyhow::anyhow!(
+                "Invalid MP3 settings {:?} for {} Hz audio: {}",
+                options,
+                sample_rate,
+                e
+            )
+        })?;
+        Ok(LameEncoder { encoder })
     }
 
     /// Splits interleaved samples into separate left and right channels
@@ -65,11 +566,8 @@ impl Mp3File {
     }
 
     /// Writes the encoded MP3 data to a buffer
-    fn encode_to_buffer(
-        mut encoder: mp3lame_encoder::Encoder,
-        left: &[i16],
-        right: &[i16],
-    ) -> Result<Vec<u8>> {
+    fn encode_to_buffer(mut lame: LameEncoder, left: &[i16], right: &[i16]) -> Result<Vec<u8>> {
+        let encoder = &mut lame.encoder;
         let mut output = Vec::new();
         let mut mp3_buffer =
             vec![std::mem::MaybeUninit::uninit(); mp3lame_encoder::max_required_buffer_size(1024)];
@@ -81,33 +579,30 @@ impl Mp3File {
                 right: right_chunk,
             };
 
-            let encoded = encoder.encode(input, &mut mp3_buffer).unwrap();
+            let encoded = encoder
+                .encode(input, &mut mp3_buffer)
+                .map_err(|e| anyhow::anyhow!("Failed to encode MP3: {}", e))?;
             output.extend_from_slice(unsafe {
                 std::slice::from_raw_parts(mp3_buffer.as_ptr() as *const u8, encoded)
             });
         }
 
         // Flush remaining samples
-        let final_bytes = encoder.flush::<FlushNoGap>(&mut mp3_buffer).unwrap();
+        let final_bytes = encoder
+            .flush::<FlushGap>(&mut mp3_buffer)
+            .map_err(|e| anyhow::anyhow!("Failed to flush MP3 encoder: {}", e))?;
         output.extend_from_slice(unsafe {
             std::slice::from_raw_parts(mp3_buffer.as_ptr() as *const u8, final_bytes)
         });
 
-        Ok(output)
-    }
+        // Fill in the tag now that the frame count and padding are known
+        let tag = lame.tag_frame();
+        if tag.is_empty() || tag.len() > output.len() {
+            return Err(anyhow::anyhow!("Failed to write the Xing/LAME tag"));
+        }
+        output[..tag.len()].copy_from_slice(&tag);
 
-    /// Writes the encoded MP3 data to the output file
-    fn encode_to_file(
-        output_path: &Path,
-        encoder: mp3lame_encoder::Encoder,
-        left: &[i16],
-        right: &[i16],
-    ) -> Result<()> {
-        let encoded = Self::encode_to_buffer(encoder, left, right)?;
-        let mut output_file =
-            File::create(output_path).context("Failed to create output MP3 file")?;
-        output_file.write_all(&encoded)?;
-        Ok(())
+        Ok(output)
     }
 }
 
@@ -122,11 +617,10 @@ impl AudioFile for Mp3File {
         let channels = track.codec_params.channels.unwrap().count();
         let sample_rate = track.codec_params.sample_rate.unwrap();
 
-        let encoder = Self::configure_encoder(channels, sample_rate)?;
-        let samples_i16 = Self::convert_samples_to_i16(samples);
-        let (left, right) = Self::split_channels(&samples_i16, channels);
-
-        Self::encode_to_file(output_path, encoder, &left, &right)?;
+        let encoded = self.encode(samples, channels, sample_rate)?;
+        let mut output_file =
+            File::create(output_path).context("Failed to create output MP3 file")?;
+        output_file.write_all(&encoded)?;
         debug!("Wrote normalized MP3 to: {}", output_path.display());
         Ok(output_path.to_path_buf())
     }
@@ -135,10 +629,989 @@ impl AudioFile for Mp3File {
         let channels = track.codec_params.channels.unwrap().count();
         let sample_rate = track.codec_params.sample_rate.unwrap();
 
-        let encoder = Self::configure_encoder(channels, sample_rate)?;
-        let samples_i16 = Self::convert_samples_to_i16(samples);
-        let (left, right) = Self::split_channels(&samples_i16, channels);
+        self.encode(samples, channels, sample_rate)
+    }
+}
+
+/// Sample format of a WAV file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WavFormat {
+    /// 16-bit integer PCM
+    #[default]
+    Int16,
+    /// 24-bit integer PCM
+    Int24,
+    /// 32-bit IEEE float
+    Float32,
+}
+
+impl WavFormat {
+    fn bits(self) -> u16 {
+        match self {
+            WavFormat::Int16 => 16,
+            WavFormat::Int24 => 24,
+            WavFormat::Float32 => 32,
+        }
+    }
+}
+
+/// Writes uncompressed WAV files, so local WAV clips are not turned into lossy MP3
+///
+/// Files with more than two channels or more than 16 bits use `WAVE_FORMAT_EXTENSIBLE`
+/// with a channel mask, as Windows and most editors expect.
+pub struct WavFile {
+    format: WavFormat,
+    dither: Dither,
+    loudness_metadata: bool,
+    metadata: Metadata,
+}
+
+impl Default for WavFile {
+    fn default() -> Self {
+        Self::new(WavFormat::default())
+    }
+}
+
+impl WavFile {
+    /// `WAVE_FORMAT_PCM`
+    const FORMAT_PCM: u16 = 1;
+    /// `WAVE_FORMAT_IEEE_FLOAT`
+    const FORMAT_FLOAT: u16 = 3;
+    /// `WAVE_FORMAT_EXTENSIBLE`
+    const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+    /// Bytes shared by the `KSDATAFORMAT_SUBTYPE` GUIDs, after the format tag
+    const SUBTYPE_TAIL: [u8; 12] = [
+        0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+    ];
+    /// Speaker positions with a bit in the channel mask
+    const MASK_BITS: u32 = 0x3ffff;
+    /// Fixed part of the BWF `bext` chunk, before the coding history
+    const BEXT_SIZE: usize = 602;
+    /// Loudness value marking a `bext` field as not measured
+    const BEXT_UNSET: i16 = 0x7fff;
+
+    pub fn new(format: WavFormat) -> Self {
+        Self {
+            format,
+            dither: Dither::default(),
+            loudness_metadata: false,
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// A writer keeping the sample format of the decoded track, for writing files back
+    pub fn matching(track: &Track) -> Self {
+        let params = &track.codec_params;
+        let float = matches!(
+            params.codec,
+            CODEC_TYPE_PCM_F32LE
+                | CODEC_TYPE_PCM_F32BE
+                | CODEC_TYPE_PCM_F64LE
+                | CODEC_TYPE_PCM_F64BE
+        ) || matches!(
+            params.sample_format,
+            Some(SampleFormat::F32 | SampleFormat::F64)
+        );
+        let format = match params.bits_per_sample {
+            _ if float => WavFormat::Float32,
+            Some(bits) if bits > 16 => WavFormat::Int24,
+            _ => WavFormat::Int16,
+        };
+        Self::new(format)
+    }
+
+    /// Dither used when converting to integer samples, [`Dither::None`] for output that
+    /// follows the input exactly. Float files are written undithered.
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Measure the written samples and store their EBU R128 loudness in a BWF `bext` chunk
+    pub fn with_loudness_metadata(mut self) -> Self {
+        self.loudness_metadata = true;
+        self
+    }
+
+    /// Write the tags in a RIFF `LIST` `INFO` chunk. WAV files have no place for
+    /// pictures, so those are left out.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Speaker layout of the channels, taken from the track when it describes them
+    fn channel_mask(track: &Track, channels: usize) -> u32 {
+        match track.codec_params.channels {
+            Some(layout) if channels > 2 && layout.count() == channels => {
+                layout.bits() & Self::MASK_BITS
+            }
+            _ => match channels {
+                1 => 0x4,
+                2 => 0x3,
+                3 => 0x7,
+                4 => 0x33,
+                5 => 0x37,
+                6 => 0x3f,
+                7 => 0x13f,
+                8 => 0x63f,
+                _ => 0,
+            },
+        }
+    }
+
+    /// Encode the samples as a complete WAV file
+    fn encode(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>> {
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let bits = self.format.bits();
+        let block_align = channels as u16 * bits / 8;
+        let extensible = channels > 2 || bits > 16;
+
+        let mut fmt = Vec::with_capacity(40);
+        let tag = match self.format {
+            WavFormat::Float32 => Self::FORMAT_FLOAT,
+            _ => Self::FORMAT_PCM,
+        };
+        fmt.extend(
+            if extensible {
+                Self::FORMAT_EXTENSIBLE
+            } else {
+                tag
+            }
+            .to_le_bytes(),
+        );
+        fmt.extend((channels as u16).to_le_bytes());
+        fmt.extend(sample_rate.to_le_bytes());
+        fmt.extend((sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(bits.to_le_bytes());
+        if extensible {
+            fmt.extend(22_u16.to_le_bytes());
+            fmt.extend(bits.to_le_bytes());
+            fmt.extend(Self::channel_mask(track, channels).to_le_bytes());
+            fmt.extend((tag as u32).to_le_bytes());
+            fmt.extend(Self::SUBTYPE_TAIL);
+        }
+
+        let mut data = Vec::with_capacity(samples.len() * bits as usize / 8);
+        match self.format {
+            WavFormat::Int16 => {
+                for s in to_i16(samples, channels, self.dither) {
+                    data.extend(s.to_le_bytes());
+                }
+            }
+            WavFormat::Int24 => {
+                for s in quantize(samples, channels, 24, self.dither) {
+                    data.extend(&s.to_le_bytes()[..3]);
+                }
+            }
+            WavFormat::Float32 => {
+                for s in samples {
+                    data.extend(s.to_le_bytes());
+                }
+            }
+        }
+
+        let mut chunks = vec![(*b"fmt ", fmt)];
+        if self.format == WavFormat::Float32 {
+            let frames = (samples.len() / channels) as u32;
+            chunks.push((*b"fact", frames.to_le_bytes().to_vec()));
+        }
+        if self.loudness_metadata {
+            let bext = self.bext(samples, channels, sample_rate)?;
+            chunks.push((*b"bext", bext));
+        }
+        // Before the audio, as readers may stop at the data chunk
+        if let Some(info) = self.metadata.riff_info() {
+            chunks.push((*b"LIST", info));
+        }
+        chunks.push((*b"data", data));
+
+        let mut body = b"WAVE".to_vec();
+        for (id, chunk) in chunks {
+            body.extend(id);
+            body.extend((chunk.len() as u32).to_le_bytes());
+            body.extend(&chunk);
+            // Chunks start on even offsets
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut file = b"RIFF".to_vec();
+        file.extend((body.len() as u32).to_le_bytes());
+        file.extend(body);
+        Ok(file)
+    }
+
+    /// BWF `bext` chunk, version 2, holding the loudness of the samples
+    fn bext(&self, samples: &[f32], channels: usize, sample_rate: u32) -> Result<Vec<u8>> {
+        let mut ebu = EbuR128::new(
+            channels as u32,
+            sample_rate,
+            Mode::I | Mode::LRA | Mode::TRUE_PEAK | Mode::M | Mode::S | Mode::HISTOGRAM,
+        )
+        .context("Failed to create EBU R128 analyzer")?;
+
+        // Follow the momentary and short-term loudness through the clip for their maxima
+        let (mut max_momentary, mut max_short_term) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for block in samples.chunks((sample_rate as usize / 10).max(1) * channels) {
+            ebu.add_frames_f32(block)
+                .context("Failed to analyze audio samples")?;
+            max_momentary = max_momentary.max(ebu.loudness_momentary()?);
+            max_short_term = max_short_term.max(ebu.loudness_shortterm()?);
+        }
+
+        let true_peak = (0..channels as u32)
+            .map(|channel| ebu.true_peak(channel))
+            .collect::<Result<Vec<f64>, _>>()?
+            .into_iter()
+            .fold(0.0, f64::max);
+        let field = |value: f64| {
+            if value.is_finite() {
+                (value * 100.0).round() as i16
+            } else {
+                Self::BEXT_UNSET
+            }
+        };
+
+        let mut bext = vec![0_u8; Self::BEXT_SIZE];
+        bext[256..256 + 8].copy_from_slice(b"earpeace");
+        // Version 2 added the loudness fields
+        bext[346..348].copy_from_slice(&2_u16.to_le_bytes());
+        for (offset, value) in [
+            ebu.loudness_global()?,
+            ebu.loudness_range()?,
+            linear_to_db(true_peak),
+            max_momentary,
+            max_short_term,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let position = 412 + offset * 2;
+            bext[position..position + 2].copy_from_slice(&field(value).to_le_bytes());
+        }
+
+        let mode = match channels {
+            1 => "mono",
+            2 => "stereo",
+            _ => "multichannel",
+        };
+        bext.extend(
+            format!(
+                "A=PCM,F={},W={},M={},T=earpeace\r\n",
+                sample_rate,
+                self.format.bits(),
+                mode
+            )
+            .bytes(),
+        );
+        Ok(bext)
+    }
+}
+
+impl AudioFile for WavFile {
+    fn write(&self, samples: &[f32], track: &Track, output_path: &Path) -> Result<PathBuf> {
+        let encoded = self.encode(samples, track)?;
+        let mut output_file =
+            File::create(output_path).context("Failed to create output WAV file")?;
+        output_file.write_all(&encoded)?;
+
+        debug!("Wrote WAV to: {}", output_path.display());
+        Ok(output_path.to_path_buf())
+    }
+
+    fn write_to_buffer(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>> {
+        self.encode(samples, track)
+    }
+}
+
+/// Writes Ogg Opus files, the format Discord stores its own soundboard sounds in
+///
+/// Opus always runs at 48 kHz, so clips at other rates are resampled first. The encoder
+/// delay is stored as the pre-skip and the last page ends at the granule position of the
+/// clip's last sample, so players drop both the delay and the padding of the final frame.
+#[cfg(feature = "opus")]
+pub struct OggOpusFile {
+    bitrate: u32,
+    max_size: Option<usize>,
+    metadata: Metadata,
+    vbr: bool,
+    complexity: u8,
+}
+
+#[cfg(feature = "opus")]
+impl Default for OggOpusFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "opus")]
+impl OggOpusFile {
+    /// Sample rate of every Opus stream
+    pub const SAMPLE_RATE: u32 = 48000;
+    /// Default bitrate in kbps, transparent for most clips at half the size of the MP3s
+    pub const DEFAULT_BITRATE: u32 = 96;
+    pub const MIN_BITRATE: u32 = 6;
+    pub const MAX_BITRATE: u32 = 510;
+    /// Slowest and best encoder setting, and the default
+    pub const MAX_COMPLEXITY: u8 = 10;
+
+    /// Frames per packet, 20 ms at 48 kHz
+    const FRAME_SIZE: usize = 960;
+    /// Largest packet the encoder may produce, as recommended by libopus
+    const MAX_PACKET: usize = 4000;
+    /// Serial number of the only logical stream in the file
+    const SERIAL: u32 = 0x4541_5250;
+    /// Bitrates in kbps tried in order when a file does not fit its size budget
+    const BUDGET_BITRATES: [u32; 8] = [64, 48, 32, 24, 16, 12, 8, 6];
+    /// Bitrate in kbps below which stereo is folded to mono to fit a size budget
+    const BUDGET_MONO_BELOW: u32 = 32;
+
+    pub fn new() -> Self {
+        Self {
+            bitrate: Self::DEFAULT_BITRATE,
+            max_size: None,
+            metadata: Metadata::default(),
+            vbr: true,
+            complexity: Self::MAX_COMPLEXITY,
+        }
+    }
+
+    /// Target bitrate in kbps for all channels together
+    pub fn with_bitrate(mut self, bitrate: u32) -> Result<Self> {
+        if !(Self::MIN_BITRATE..=Self::MAX_BITRATE).contains(&bitrate) {
+            return Err(anyhow::anyhow!(
+                "Opus bitrate must be between {} and {} kbps (got: {} kbps)",
+                Self::MIN_BITRATE,
+                Self::MAX_BITRATE,
+                bitrate
+            ));
+        }
+
+        self.bitrate = bitrate;
+        Ok(self)
+    }
+
+    /// Keep files within `max_size` bytes, stepping down to lower bitrates and mono for
+    /// clips that do not fit at the bitrate, and failing for clips that fit at none
+    pub fn with_max_size(mut self, max_size: usize) -> Result<Self> {
+        if max_size == 0 {
+            return Err(anyhow::anyhow!("Maximum file size must be above 0 bytes"));
+        }
+
+        self.max_size = Some(max_size);
+        Ok(self)
+    }
+
+    /// Write the tags as comments and the pictures as `METADATA_BLOCK_PICTURE` comments
+    /// in the `OpusTags` header
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Let the bitrate follow the content, on by default. Off gives a constant bitrate.
+    pub fn with_vbr(mut self, vbr: bool) -> Self {
+        self.vbr = vbr;
+        self
+    }
+
+    /// Trade encoding speed for quality, from 0 for the fastest to 10 for the best
+    pub fn with_complexity(mut self, complexity: u8) -> Result<Self> {
+        if complexity > Self::MAX_COMPLEXITY {
+            return Err(anyhow::anyhow!(
+                "Opus complexity must be between 0 and {} (got: {})",
+                Self::MAX_COMPLEXITY,
+                complexity
+            ));
+        }
+
+        self.complexity = complexity;
+        Ok(self)
+    }
+
+    /// Configures the libopus encoder with the chosen settings
+    fn configure_encoder(&self, channels: usize, bitrate: u32) -> Result<audiopus::coder::Encoder> {
+        let opus_channels = match channels {
+            1 => audiopus::Channels::Mono,
+            2 => audiopus::Channels::Stereo,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Opus output supports mono and stereo clips (got: {} channels)",
+                    channels
+                ))
+            }
+        };
+
+        let mut encoder = audiopus::coder::Encoder::new(
+            audiopus::SampleRate::Hz48000,
+            opus_channels,
+            audiopus::Application::Audio,
+        )
+        .context("Failed to create Opus encoder")?;
+        encoder
+            .set_bitrate(audiopus::Bitrate::BitsPerSecond(bitrate as i32 * 1000))
+            .context("Failed to set Opus bitrate")?;
+        encoder
+            .set_vbr(self.vbr)
+            .context("Failed to set Opus VBR")?;
+        encoder
+            .set_complexity(self.complexity)
+            .context("Failed to set Opus complexity")?;
+
+        Ok(encoder)
+    }
+
+    /// `OpusHead` identification header
+    fn head(channels: usize, pre_skip: u16, input_sample_rate: u32) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(channels as u8);
+        head.extend(pre_skip.to_le_bytes());
+        head.extend(input_sample_rate.to_le_bytes());
+        // No output gain, and mapping family 0 for mono and stereo
+        head.extend(0_i16.to_le_bytes());
+        head.push(0);
+        head
+    }
+
+    /// `OpusTags` comment header, naming the encoder and holding the metadata
+    fn tags(&self) -> Vec<u8> {
+        let vendor = format!("earpeace {}", audiopus::version());
+        let mut comments = self.metadata.vorbis_comments();
+        comments.extend(self.metadata.pictures().iter().map(|picture| {
+            format!(
+                "METADATA_BLOCK_PICTURE={}",
+                base64.encode(picture.flac_block())
+            )
+        }));
+
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend((vendor.len() as u32).to_le_bytes());
+        tags.extend(vendor.bytes());
+        tags.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            tags.extend((comment.len() as u32).to_le_bytes());
+            tags.extend(comment.bytes());
+        }
+        tags
+    }
+
+    /// Encode the samples as a complete Ogg Opus file, within the size budget when
+    /// there is one
+    fn encode(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>> {
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let resampled = resample(
+            samples,
+            channels,
+            Self::SAMPLE_RATE as f64 / sample_rate as f64,
+        )?;
+
+        let encoded = self.encode_at(self.bitrate, resampled.clone(), channels, sample_rate)?;
+        let max_size = match self.max_size {
+            Some(max_size) if encoded.len() > max_size => max_size,
+            _ => return Ok(encoded),
+        };
+
+        let bitrates = Self::BUDGET_BITRATES.iter().filter(|&&b| b < self.bitrate);
+        for &bitrate in bitrates {
+            let max_channels = if bitrate < Self::BUDGET_MONO_BELOW {
+                1
+            } else {
+                2
+            };
+            let (reduced, reduced_channels, _) = reduce(
+                &resampled,
+                channels,
+                Self::SAMPLE_RATE,
+                max_channels,
+                u32::MAX,
+            )?;
+            let encoded =
+                self.encode_at(bitrate, reduced.into_owned(), reduced_channels, sample_rate)?;
+            if encoded.len() <= max_size {
+                info!(
+                    "Fitted Ogg Opus in {} bytes at {} kbps, {} channels",
+                    encoded.len(),
+                    bitrate,
+                    reduced_channels
+                );
+                return Ok(encoded);
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "Clip of {:.1} s does not fit in {} bytes, even as the smallest Ogg Opus (got: {} bytes at {} kbps)",
+            samples.len() as f64 / channels as f64 / sample_rate as f64,
+            max_size,
+            encoded.len(),
+            self.bitrate
+        ))
+    }
+
+    /// Encode samples at 48 kHz with `bitrate`, recording `input_rate` in the header
+    fn encode_at(
+        &self,
+        bitrate: u32,
+        mut samples: Vec<f32>,
+        channels: usize,
+        input_rate: u32,
+    ) -> Result<Vec<u8>> {
+        let encoder = self.configure_encoder(channels, bitrate)?;
+        let frames = samples.len() / channels;
+
+        // The decoder output lags the input by the lookahead, so keep encoding silence
+        // until the last sample of the clip has come out
+        let pre_skip = encoder
+            .lookahead()
+            .context("Failed to read Opus lookahead")? as usize;
+        let packets = (frames + pre_skip).div_ceil(Self::FRAME_SIZE).max(1);
+        samples.resize(packets * Self::FRAME_SIZE * channels, 0.0);
+
+        let mut writer = ogg::PacketWriter::new(Vec::new());
+        let header_pages = [
+            Self::head(channels, pre_skip as u16, input_rate),
+            self.tags(),
+        ];
+        for header in header_pages {
+            writer.write_packet(
+                header.into(),
+                Self::SERIAL,
+                ogg::PacketWriteEndInfo::EndPage,
+                0,
+            )?;
+        }
+
+        let mut packet = vec![0_u8; Self::MAX_PACKET];
+        for (index, frame) in samples.chunks(Self::FRAME_SIZE * channels).enumerate() {
+            let size = encoder
+                .encode_float(frame, &mut packet)
+                .context("Failed to encode Opus packet")?;
+
+            // Granule positions count decoded samples including the pre-skip. The last
+            // one stops at the end of the clip, which trims the padding.
+            let (granule, end) = if index + 1 == packets {
+                (pre_skip + frames, ogg::PacketWriteEndInfo::EndStream)
+            } else {
+                (
+                    (index + 1) * Self::FRAME_SIZE,
+                    ogg::PacketWriteEndInfo::NormalPacket,
+                )
+            };
+            writer.write_packet(packet[..size].into(), Self::SERIAL, end, granule as u64)?;
+        }
+
+        Ok(writer.into_inner())
+    }
+}
+
+#[cfg(feature = "opus")]
+impl AudioFile for OggOpusFile {
+    fn write(&self, samples: &[f32], track: &Track, output_path: &Path) -> Result<PathBuf> {
+        let encoded = self.encode(samples, track)?;
+        let mut output_file =
+            File::create(output_path).context("Failed to create output Opus file")?;
+        output_file.write_all(&encoded)?;
+
+        debug!("Wrote Ogg Opus to: {}", output_path.display());
+        Ok(output_path.to_path_buf())
+    }
+
+    fn write_to_buffer(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>> {
+        self.encode(samples, track)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_normalizer::measure_loudness;
+    use crate::dsp::decode_file;
+    use crate::test_signals::{interleave, sine, track};
+    use symphonia::core::audio::Channels;
+
+    /// Offset and contents of the first chunk with the given id
+    fn chunk<'a>(file: &'a [u8], id: &[u8; 4]) -> Option<&'a [u8]> {
+        let mut position = 12;
+        while position + 8 <= file.len() {
+            let size = u32::from_le_bytes(file[position + 4..position + 8].try_into().unwrap());
+            let body = &file[position + 8..position + 8 + size as usize];
+            if &file[position..position + 4] == id {
+                return Some(body);
+            }
+            position += 8 + size as usize + size as usize % 2;
+        }
+        None
+    }
+
+    #[test]
+    fn test_wav_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+
+        for (format, tolerance) in [
+            (WavFormat::Int16, 1e-4),
+            (WavFormat::Int24, 1e-6),
+            (WavFormat::Float32, 0.0),
+        ] {
+            let path = dir.path().join(format!("{:?}.wav", format));
+            WavFile::new(format)
+                .with_dither(Dither::None)
+                .write(&samples, &stereo, &path)
+                .unwrap();
+
+            let (decoded, decoded_track) = decode_file(&path).unwrap();
+            assert_eq!(decoded.len(), samples.len());
+            assert_eq!(decoded_track.codec_params.sample_rate, Some(44100));
+            for (a, b) in decoded.iter().zip(&samples) {
+                assert!((a - b).abs() <= tolerance, "{:?}: {} != {}", format, a, b);
+            }
+            assert_eq!(WavFile::matching(&decoded_track).format, format);
+        }
+    }
+
+    #[test]
+    fn test_wav_channel_masks() {
+        let surround = track(
+            Channels::FRONT_LEFT
+                | Channels::FRONT_RIGHT
+                | Channels::FRONT_CENTRE
+                | Channels::LFE1
+                | Channels::SIDE_LEFT
+                | Channels::SIDE_RIGHT,
+        );
+        let file = WavFile::default()
+            .write_to_buffer(&interleave(&sine(1000.0, 0.1, 44100, 44100), 6), &surround)
+            .unwrap();
+        let fmt = chunk(&file, b"fmt ").unwrap();
+        assert_eq!(u16::from_le_bytes([fmt[0], fmt[1]]), 0xfffe);
+        assert_eq!(u32::from_le_bytes(fmt[20..24].try_into().unwrap()), 0x60f);
+
+        // Plain 16-bit stereo keeps the basic header every reader understands
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let file = WavFile::default()
+            .write_to_buffer(&interleave(&sine(1000.0, 0.1, 44100, 44100), 2), &stereo)
+            .unwrap();
+        assert_eq!(chunk(&file, b"fmt ").unwrap().len(), 16);
+    }
+
+    #[test]
+    fn test_wav_loudness_metadata() {
+        let mono = track(Channels::FRONT_CENTRE);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 1);
+        let file = WavFile::new(WavFormat::Float32)
+            .with_loudness_metadata()
+            .write_to_buffer(&samples, &mono)
+            .unwrap();
+
+        let bext = chunk(&file, b"bext").unwrap();
+        assert_eq!(u16::from_le_bytes([bext[346], bext[347]]), 2);
+        let loudness = i16::from_le_bytes([bext[412], bext[413]]) as f64 / 100.0;
+        let expected = measure_loudness(1, 44100, &samples).unwrap();
+        assert!(
+            (loudness - expected).abs() < 0.01,
+            "{} != {}",
+            loudness,
+            expected
+        );
+        assert!(chunk(&file, b"fact").is_some());
+
+        assert!(chunk(
+            &WavFile::default().write_to_buffer(&samples, &mono).unwrap(),
+            b"bext"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_mp3_options_change_the_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+
+        let encode = |options: Mp3Options| {
+            Mp3File::new()
+                .with_options(options)
+                .write_to_buffer(&samples, &stereo)
+                .unwrap()
+        };
+        let cbr = |bitrate| Mp3Options::new(Mp3Mode::Cbr, Some(bitrate), None).unwrap();
+        assert!(encode(cbr(64)).len() < encode(cbr(192)).len() / 2);
+        assert!(
+            encode(Mp3Options::new(Mp3Mode::Abr, Some(100), None).unwrap()).len()
+                < encode(cbr(192)).len()
+        );
+
+        for (name, options) in [
+            ("vbr", Mp3Options::new(Mp3Mode::Vbr, None, Some(4)).unwrap()),
+            (
+                "forced",
+                Mp3Options::default()
+                    .with_stereo(Mp3Stereo::Forced)
+                    .with_lowpass(16000)
+                    .unwrap()
+                    .with_quality(5)
+                    .unwrap(),
+            ),
+        ] {
+            let path = dir.path().join(format!("{}.mp3", name));
+            Mp3File::new()
+                .with_options(options)
+                .write(&samples, &stereo, &path)
+                .unwrap();
+            let (decoded, decoded_track) = decode_file(&path).unwrap();
+            assert_eq!(decoded_track.codec_params.channels.unwrap().count(), 2);
+            let loudness = |s: &[f32]| measure_loudness(2, 44100, s).unwrap();
+            assert!((loudness(&decoded) - loudness(&samples)).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn test_mp3_steps_down_to_fit_the_size_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+        assert!(
+            Mp3File::new()
+                .write_to_buffer(&samples, &stereo)
+                .unwrap()
+                .len()
+                > 20_000
+        );
+
+        // One second at 192 kbps is 24 KB, so the clip has to give up stereo to fit
+        let path = dir.path().join("budget.mp3");
+        Mp3File::new()
+            .with_max_size(10_000)
+            .unwrap()
+            .write(&samples, &stereo, &path)
+            .unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() <= 10_000);
+        let (decoded, decoded_track) = decode_file(&path).unwrap();
+        assert_eq!(decoded_track.codec_params.channels.unwrap().count(), 1);
+        let loudness = measure_loudness(1, 44100, &decoded).unwrap();
+        let mono = sine(1000.0, 0.1, 44100, 44100);
+        assert!((loudness - measure_loudness(1, 44100, &mono).unwrap()).abs() < 1.0);
+
+        // Clips that already fit are left as the options make them
+        assert_eq!(
+            Mp3File::new()
+                .with_max_size(100_000)
+                .unwrap()
+                .write_to_buffer(&samples, &stereo)
+                .unwrap(),
+            Mp3File::new().write_to_buffer(&samples, &stereo).unwrap()
+        );
+
+        // Not even 8 kbps mono fits in 500 bytes
+        let error = Mp3File::new()
+            .with_max_size(500)
+            .unwrap()
+            .write_to_buffer(&samples, &stereo)
+            .unwrap_err();
+        assert!(error.to_string().contains("does not fit in 500 bytes"));
+        assert!(Mp3File::new().with_max_size(0).is_err());
+    }
+
+    #[test]
+    fn test_mp3_round_trips_without_delay_or_padding() {
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let mut samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+
+        for (pass, options) in [
+            Mp3Options::default(),
+            Mp3Options::new(Mp3Mode::Vbr, None, None).unwrap(),
+            Mp3Options::new(Mp3Mode::Cbr, Some(128), None).unwrap(),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let path = dir.path().join(format!("pass{}.mp3", pass));
+            Mp3File::new()
+                .with_options(options)
+                .write(&samples, &stereo, &path)
+                .unwrap();
+            let (decoded, _) = decode_file(&path).unwrap();
+            assert_eq!(decoded.len(), samples.len(), "pass {}", pass);
+
+            // Lined up with the input, not shifted by the encoder delay
+            let lag = |lag: usize| -> f64 {
+                (0..samples.len() / 2 - 64)
+                    .map(|i| samples[i * 2] as f64 * decoded[(i + lag) * 2] as f64)
+                    .sum()
+            };
+            assert!((1..24).all(|l| lag(0) > lag(l)), "pass {}", pass);
+            samples = decoded;
+        }
+    }
+
+    #[test]
+    fn test_tags_survive_a_round_trip() {
+        use crate::audio_metadata::{Picture, TagKey};
+        use crate::dsp::decode_file_with_metadata;
+
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+        let metadata = Metadata::new()
+            .with_tag(TagKey::Title, "Vine Boom")
+            .unwrap()
+            .with_tag(TagKey::Artist, "Ünïcödé")
+            .unwrap()
+            .with_tag(TagKey::Comment, "Loud")
+            .unwrap()
+            .with_picture(Picture::front_cover(
+                "image/png",
+                vec![0x89, b'P', b'N', b'G'],
+            ));
+
+        let path = dir.path().join("tagged.mp3");
+        Mp3File::new()
+            .with_metadata(metadata.clone())
+            .write(&samples, &stereo, &path)
+            .unwrap();
+        let (decoded, _, read) = decode_file_with_metadata(&path).unwrap();
+        assert_eq!(read, metadata);
+        // The tag does not get in the way of the gapless info
+        assert_eq!(decoded.len(), samples.len());
+
+        // WAV keeps the tags, but has no place for the picture
+        let path = dir.path().join("tagged.wav");
+        WavFile::default()
+            .with_metadata(metadata.clone())
+            .write(&samples, &stereo, &path)
+            .unwrap();
+        let (_, _, read) = decode_file_with_metadata(&path).unwrap();
+        assert_eq!(read.tags(), metadata.tags());
+        assert!(read.pictures().is_empty());
+    }
+
+    #[test]
+    fn test_mp3_invalid_options() {
+        assert!(Mp3Options::new(Mp3Mode::Cbr, Some(100), None).is_err());
+        assert!(Mp3Options::new(Mp3Mode::Cbr, None, Some(2)).is_err());
+        assert!(Mp3Options::new(Mp3Mode::Abr, Some(400), None).is_err());
+        assert!(Mp3Options::new(Mp3Mode::Vbr, Some(128), None).is_err());
+        assert!(Mp3Options::new(Mp3Mode::Vbr, None, Some(10)).is_err());
+        assert!(Mp3Options::default().with_quality(10).is_err());
+        assert!(Mp3Options::default().with_lowpass(0).is_err());
+        assert!(Mp3Options::new(Mp3Mode::Abr, Some(100), None).is_ok());
+
+        // Lowpass above Nyquist is only known once the sample rate is
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let options = Mp3Options::default().with_lowpass(30000).unwrap();
+        assert!(Mp3File::new()
+            .with_options(options)
+            .write_to_buffer(&interleave(&sine(1000.0, 0.1, 44100, 44100), 2), &stereo)
+            .is_err());
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn test_opus_granules_trim_to_the_clip() {
+        use crate::dsp::rms_db;
+        use std::convert::TryFrom;
+
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+        let file = OggOpusFile::new()
+            .write_to_buffer(&samples, &stereo)
+            .unwrap();
+
+        let mut reader = ogg::PacketReader::new(std::io::Cursor::new(file));
+        let head = reader.read_packet_expected().unwrap();
+        assert_eq!(&head.data[..8], b"OpusHead");
+        assert_eq!(head.data[9], 2);
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+        assert!(pre_skip > 0);
+        assert_eq!(head.absgp_page(), 0);
+        let tags = reader.read_packet_expected().unwrap();
+        assert_eq!(&tags.data[..8], b"OpusTags");
+
+        let mut decoder = audiopus::coder::Decoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+        )
+        .unwrap();
+        let mut decoded = Vec::new();
+        let mut end = 0;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let mut output = vec![0.0_f32; 5760 * 2];
+            let frames = decoder
+                .decode_float(
+                    Some(audiopus::packet::Packet::try_from(&packet.data[..]).unwrap()),
+                    audiopus::MutSignals::try_from(&mut output[..]).unwrap(),
+                    false,
+                )
+                .unwrap();
+            decoded.extend_from_slice(&output[..frames * 2]);
+            if packet.last_in_stream() {
+                end = packet.absgp_page() as usize;
+            }
+        }
+
+        // One second at 48 kHz after the pre-skip, with the padding past the end dropped
+        assert_eq!(end, pre_skip + 48000);
+        assert!(decoded.len() >= end * 2);
+        let clip = &decoded[pre_skip * 2..end * 2];
+        assert!((rms_db(clip) - rms_db(&samples)).abs() < 0.5);
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn test_opus_steps_down_to_fit_the_size_budget() {
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = interleave(&sine(1000.0, 0.1, 44100, 44100), 2);
+        assert!(
+            OggOpusFile::new()
+                .write_to_buffer(&samples, &stereo)
+                .unwrap()
+                .len()
+                > 6000
+        );
+
+        let file = OggOpusFile::new()
+            .with_max_size(3000)
+            .unwrap()
+            .write_to_buffer(&samples, &stereo)
+            .unwrap();
+        assert!(file.len() <= 3000);
+        let mut reader = ogg::PacketReader::new(std::io::Cursor::new(file));
+        let head = reader.read_packet_expected().unwrap();
+        assert_eq!(head.data[9], 1);
+
+        assert!(OggOpusFile::new()
+            .with_max_size(200)
+            .unwrap()
+            .write_to_buffer(&samples, &stereo)
+            .is_err());
+    }
 
-        Self::encode_to_buffer(encoder, &left, &right)
+    #[cfg(feature = "opus")]
+    #[test]
+    fn test_opus_invalid_options() {
+        assert!(OggOpusFile::new().with_bitrate(5).is_err());
+        assert!(OggOpusFile::new().with_bitrate(600).is_err());
+        assert!(OggOpusFile::new().with_complexity(11).is_err());
+        assert!(OggOpusFile::new()
+            .with_bitrate(64)
+            .unwrap()
+            .with_complexity(5)
+            .is_ok());
+        assert!(OggOpusFile::new()
+            .write_to_buffer(
+                &interleave(&sine(1000.0, 0.1, 44100, 44100), 3),
+                &track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE)
+            )
+            .is_err());
     }
 }
@@ -0,0 +1,1063 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use symphonia::core::checksum::{Crc16Ansi, Crc8Ccitt, Md5};
+use symphonia::core::formats::Track;
+use symphonia::core::io::Monitor;
+
+use crate::audio_file::AudioFile;
+use crate::audio_metadata::{Metadata, Picture};
+use crate::sample_format::{quantize, Dither};
+
+/// Writes lossless FLAC files, for archiving originals and processed masters
+///
+/// Every block is predicted with the best of the fixed polynomial predictors and, from
+/// compression level 3, linear prediction, and the residual is Rice coded. Stereo blocks
+/// also try mid/side coding. The file decodes to exactly the samples that were written,
+/// so clips can be processed again later without stacking lossy encodes.
+pub struct FlacFile {
+    level: usize,
+    bits: u32,
+    dither: Dither,
+    tags: Vec<String>,
+    pictures: Vec<Picture>,
+}
+
+/// Encoder settings behind a compression level
+struct Level {
+    block_size: usize,
+    max_lpc_order: usize,
+    max_partition_order: u32,
+    mid_side: bool,
+}
+
+/// Compression levels 0 to 8, following the presets of the reference encoder
+const LEVELS: [Level; 9] = [
+    Level::new(1152, 0, 3, false),
+    Level::new(1152, 0, 3, true),
+    Level::new(1152, 0, 4, true),
+    Level::new(4096, 6, 4, false),
+    Level::new(4096, 8, 4, true),
+    Level::new(4096, 8, 5, true),
+    Level::new(4096, 8, 6, true),
+    Level::new(4096, 12, 6, true),
+    Level::new(4096, 12, 8, true),
+];
+
+impl Level {
+    const fn new(
+        block_size: usize,
+        max_lpc_order: usize,
+        max_partition_order: u32,
+        mid_side: bool,
+    ) -> Self {
+        Self {
+            block_size,
+            max_lpc_order,
+            max_partition_order,
+            mid_side,
+        }
+    }
+}
+
+impl Default for FlacFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlacFile {
+    /// Default compression level, the usual balance of size and speed
+    pub const DEFAULT_LEVEL: usize = 5;
+    /// Smallest and slowest compression level
+    pub const MAX_LEVEL: usize = 8;
+    pub const DEFAULT_BITS: u32 = 16;
+    pub const MIN_BITS: u32 = 8;
+    pub const MAX_BITS: u32 = 24;
+    /// Most channels a FLAC stream can hold
+    pub const MAX_CHANNELS: usize = 8;
+
+    /// Largest Rice parameter of the 4-bit partition encoding, beyond which the 5-bit one
+    /// is used
+    const MAX_RICE_PARAMETER: u32 = 14;
+    /// Largest Rice parameter of the 5-bit encoding, below its escape code
+    const MAX_RICE2_PARAMETER: u32 = 30;
+
+    pub fn new() -> Self {
+        Self {
+            level: Self::DEFAULT_LEVEL,
+            bits: Self::DEFAULT_BITS,
+            dither: Dither::default(),
+            tags: Vec::new(),
+            pictures: Vec::new(),
+        }
+    }
+
+    /// A writer keeping the bit depth of the decoded track, 24 bits for anything finer
+    /// than 16
+    pub fn matching(track: &Track) -> Self {
+        let bits = match track.codec_params.bits_per_sample {
+            Some(bits) if bits > 16 => Self::MAX_BITS,
+            _ => Self::DEFAULT_BITS,
+        };
+        Self {
+            bits,
+            ..Self::new()
+        }
+    }
+
+    /// Compression level from 0, the fastest, to 8, the smallest. Every level is lossless.
+    pub fn with_compression(mut self, level: usize) -> Result<Self> {
+        if level > Self::MAX_LEVEL {
+            return Err(anyhow::anyhow!(
+                "FLAC compression level must be between 0 and {} (got: {})",
+                Self::MAX_LEVEL,
+                level
+            ));
+        }
+
+        self.level = level;
+        Ok(self)
+    }
+
+    /// Bits per sample of the written file
+    pub fn with_bits(mut self, bits: u32) -> Result<Self> {
+        if !(Self::MIN_BITS..=Self::MAX_BITS).contains(&bits) {
+            return Err(anyhow::anyhow!(
+                "FLAC bit depth must be between {} and {} bits (got: {})",
+                Self::MIN_BITS,
+                Self::MAX_BITS,
+                bits
+            ));
+        }
+
+        self.bits = bits;
+        Ok(self)
+    }
+
+    /// Dither used when converting to integer samples, [`Dither::None`] for output that
+    /// follows the input exactly
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Add a Vorbis comment such as `TITLE` or `ARTIST`. Names are case-insensitive and
+    /// stored in upper case, and may be given more than once.
+    pub fn with_tag(mut self, name: &str, value: &str) -> Result<Self> {
+        let valid = |c: char| (' '..='}').contains(&c) && c != '=';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(anyhow::anyhow!(
+                "Invalid Vorbis comment name '{}', expected printable ASCII without '='",
+                name
+            ));
+        }
+
+        self.tags
+            .push(format!("{}={}", name.to_ascii_uppercase(), value));
+        Ok(self)
+    }
+
+    /// Add the tags as Vorbis comments, after any added with [`Self::with_tag`], and the
+    /// pictures as PICTURE blocks
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.tags.extend(metadata.vorbis_comments());
+        self.pictures.extend(metadata.pictures().iter().cloned());
+        self
+    }
+
+    /// Encode the samples as a complete FLAC file
+    fn encode(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>> {
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+        if channels > Self::MAX_CHANNELS {
+            return Err(anyhow::anyhow!(
+                "FLAC supports up to {} channels (got: {})",
+                Self::MAX_CHANNELS,
+                channels
+            ));
+        }
+
+        let samples = quantize(samples, channels, self.bits, self.dither);
+        let frames = samples.len() / channels;
+        let level = &LEVELS[self.level];
+
+        let mut audio = Vec::new();
+        let (mut min_frame, mut max_frame) = (u32::MAX, 0);
+        for (number, block) in samples.chunks(level.block_size * channels).enumerate() {
+            let frame = self.encode_frame(block, channels, sample_rate, number as u64, level);
+            min_frame = min_frame.min(frame.len() as u32);
+            max_frame = max_frame.max(frame.len() as u32);
+            audio.extend(frame);
+        }
+
+        // The checksum covers the samples as little-endian integers of whole bytes
+        let mut md5 = Md5::default();
+        let width = self.bits.div_ceil(8) as usize;
+        for sample in &samples {
+            md5.process_buf_bytes(&sample.to_le_bytes()[..width]);
+        }
+
+        let mut info = BitWriter::default();
+        let block_size = level.block_size.min(frames.max(16)) as u64;
+        info.write(block_size, 16);
+        info.write(block_size, 16);
+        info.write(if max_frame > 0 { min_frame as u64 } else { 0 }, 24);
+        info.write(max_frame as u64, 24);
+        info.write(sample_rate as u64, 20);
+        info.write(channels as u64 - 1, 3);
+        info.write(self.bits as u64 - 1, 5);
+        info.write(frames as u64 >> 32, 4);
+        info.write(frames as u64 & 0xffff_ffff, 32);
+
+        let vendor = "earpeace";
+        let mut comments = Vec::new();
+        comments.extend((vendor.len() as u32).to_le_bytes());
+        comments.extend(vendor.bytes());
+        comments.extend((self.tags.len() as u32).to_le_bytes());
+        for tag in &self.tags {
+            comments.extend((tag.len() as u32).to_le_bytes());
+            comments.extend(tag.bytes());
+        }
+
+        let mut file = b"fLaC".to_vec();
+        let mut streaminfo = info.into_bytes();
+        streaminfo.extend(md5.md5());
+        let mut blocks = vec![(0_u8, streaminfo), (4, comments)];
+        blocks.extend(
+            self.pictures
+                .iter()
+                .map(|picture| (6, picture.flac_block())),
+        );
+        let count = blocks.len();
+        for (index, (kind, block)) in blocks.into_iter().enumerate() {
+            if block.len() >= 1 << 24 {
+                return Err(anyhow::anyhow!(
+                    "FLAC metadata blocks must be under 16 MB (got: {} bytes)",
+                    block.len()
+                ));
+            }
+            let last = index + 1 == count;
+            file.push(kind | if last { 0x80 } else { 0 });
+            file.extend(&(block.len() as u32).to_be_bytes()[1..]);
+            file.extend(block);
+        }
+        file.extend(audio);
+
+        debug!(
+            "Encoded {} frames of {}-bit FLAC at level {} into {} bytes",
+            frames,
+            self.bits,
+            self.level,
+            file.len()
+        );
+        Ok(file)
+    }
+
+    /// Encode one block of interleaved samples as a frame
+    fn encode_frame(
+        &self,
+        block: &[i32],
+        channels: usize,
+        sample_rate: u32,
+        number: u64,
+        level: &Level,
+    ) -> Vec<u8> {
+        let size = block.len() / channels;
+        let channel = |c: usize| -> Vec<i64> {
+            block
+                .iter()
+                .skip(c)
+                .step_by(channels)
+                .map(|&s| s as i64)
+                .collect()
+        };
+        let code = |samples: Vec<i64>, bits: u32| {
+            let subframe = Subframe::best(&samples, bits, level);
+            (samples, subframe, bits)
+        };
+
+        // Stereo blocks may also be coded as one channel and the difference, or as mid and
+        // side, whichever is smallest
+        let bits = self.bits;
+        let (assignment, subframes) = if channels == 2 && level.mid_side {
+            let (left, right) = (channel(0), channel(1));
+            let side = left.iter().zip(&right).map(|(l, r)| l - r).collect();
+            let mid = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+            let coded = [
+                code(left, bits),
+                code(right, bits),
+                code(side, bits + 1),
+                code(mid, bits),
+            ];
+
+            let (assignment, [first, second]) =
+                [(1, [0, 1]), (8, [0, 2]), (9, [2, 1]), (10, [3, 2])]
+                    .into_iter()
+                    .min_by_key(|(_, [first, second])| coded[*first].1.bits + coded[*second].1.bits)
+                    .unwrap();
+            (
+                assignment,
+                vec![coded[first].clone(), coded[second].clone()],
+            )
+        } else {
+            let subframes = (0..channels).map(|c| code(channel(c), bits)).collect();
+            (channels as u64 - 1, subframes)
+        };
+
+        let mut writer = BitWriter::default();
+        writer.write(0xfff8, 16);
+        let (size_code, size_field) = match size {
+            192 => (1, None),
+            576 | 1152 | 2304 | 4608 => (2 + (size / 576).trailing_zeros() as u64, None),
+            _ if size.is_power_of_two() && (256..=32768).contains(&size) => {
+                (8 + (size / 256).trailing_zeros() as u64, None)
+            }
+            _ if size <= 256 => (6, Some((size as u64 - 1, 8))),
+            _ => (7, Some((size as u64 - 1, 16))),
+        };
+        let (rate_code, rate_field) = match sample_rate {
+            88200 => (1, None),
+            176400 => (2, None),
+            192000 => (3, None),
+            8000 => (4, None),
+            16000 => (5, None),
+            22050 => (6, None),
+            24000 => (7, None),
+            32000 => (8, None),
+            44100 => (9, None),
+            48000 => (10, None),
+            96000 => (11, None),
+            rate if rate % 1000 == 0 && rate / 1000 <= 255 => (12, Some((rate as u64 / 1000, 8))),
+            rate if rate <= 65535 => (13, Some((rate as u64, 16))),
+            rate if rate % 10 == 0 && rate / 10 <= 65535 => (14, Some((rate as u64 / 10, 16))),
+            _ => (0, None),
+        };
+        let bits_code = match bits {
+            8 => 1,
+            12 => 2,
+            16 => 4,
+            20 => 5,
+            24 => 6,
+            _ => 0,
+        };
+        writer.write(size_code, 4);
+        writer.write(rate_code, 4);
+        writer.write(assignment, 4);
+        writer.write(bits_code, 3);
+        writer.write(0, 1);
+        for byte in utf8_number(number) {
+            writer.write(byte as u64, 8);
+        }
+        for (value, width) in [size_field, rate_field].into_iter().flatten() {
+            writer.write(value, width);
+        }
+        let mut crc8 = Crc8Ccitt::new(0);
+        crc8.process_buf_bytes(&writer.bytes);
+        writer.write(crc8.crc() as u64, 8);
+
+        for (samples, subframe, bits) in &subframes {
+            subframe.write(&mut writer, samples, *bits);
+        }
+        writer.align();
+
+        let mut crc16 = Crc16Ansi::new(0);
+        crc16.process_buf_bytes(&writer.bytes);
+        writer.write(crc16.crc() as u64, 16);
+        writer.into_bytes()
+    }
+}
+
+impl AudioFile for FlacFile {
+    fn write(&self, samples: &[f32], track: &Track, output_path: &Path) -> Result<PathBuf> {
+        let encoded = self.encode(samples, track)?;
+        let mut output_file =
+            File::create(output_path).context("Failed to create output FLAC file")?;
+        output_file.write_all(&encoded)?;
+
+        debug!("Wrote FLAC to: {}", output_path.display());
+        Ok(output_path.to_path_buf())
+    }
+
+    fn write_to_buffer(&self, samples: &[f32], track: &Track) -> Result<Vec<u8>> {
+        self.encode(samples, track)
+    }
+}
+
+/// Frame number in the UTF-8 like variable length code of FLAC frame headers
+fn utf8_number(number: u64) -> Vec<u8> {
+    if number < 0x80 {
+        return vec![number as u8];
+    }
+
+    // Each continuation byte holds 6 bits, the first byte the rest behind a length prefix
+    let length = (2..7)
+        .find(|&length| number < 1 << (5 * length + 1))
+        .unwrap_or(7);
+    let mut bytes = vec![0_u8; length];
+    let mut rest = number;
+    for byte in bytes[1..].iter_mut().rev() {
+        *byte = 0x80 | (rest & 0x3f) as u8;
+        rest >>= 6;
+    }
+    bytes[0] = (0xff00_u16 >> length) as u8 | rest as u8;
+    bytes
+}
+
+/// How a subframe predicts its samples
+#[derive(Debug, Clone)]
+enum Predictor {
+    Constant,
+    Verbatim,
+    Fixed(usize),
+    Lpc {
+        coefficients: Vec<i64>,
+        precision: u32,
+        shift: u32,
+    },
+}
+
+impl Predictor {
+    fn order(&self) -> usize {
+        match self {
+            Predictor::Constant | Predictor::Verbatim => 0,
+            Predictor::Fixed(order) => *order,
+            Predictor::Lpc { coefficients, .. } => coefficients.len(),
+        }
+    }
+}
+
+/// Rice coding of a residual, split into 2^`partition_order` partitions
+#[derive(Debug, Clone)]
+struct Rice {
+    partition_order: u32,
+    parameters: Vec<u32>,
+}
+
+/// One channel of a frame, with the predictor that codes it in the fewest bits
+#[derive(Debug, Clone)]
+struct Subframe {
+    predictor: Predictor,
+    wasted_bits: u32,
+    residual: Vec<i64>,
+    rice: Option<Rice>,
+    bits: usize,
+}
+
+impl Subframe {
+    /// Pick the predictor that codes the samples smallest
+    fn best(samples: &[i64], bits: u32, level: &Level) -> Self {
+        // Trailing zero bits shared by every sample are signalled once and dropped
+        let wasted_bits = match samples.iter().fold(0, |acc, &s| acc | s) {
+            0 => 0,
+            all => all.trailing_zeros().min(bits - 1),
+        };
+        let shifted: Vec<i64> = samples.iter().map(|&s| s >> wasted_bits).collect();
+        let samples = &shifted[..];
+        let bits = bits - wasted_bits;
+        let header = 8 + wasted_bits as usize;
+
+        if samples.windows(2).all(|w| w[0] == w[1]) {
+            return Self {
+                predictor: Predictor::Constant,
+                wasted_bits,
+                residual: Vec::new(),
+                rice: None,
+                bits: header + bits as usize,
+            };
+        }
+
+        let mut best = Self {
+            predictor: Predictor::Verbatim,
+            wasted_bits,
+            residual: Vec::new(),
+            rice: None,
+            bits: header + samples.len() * bits as usize,
+        };
+        let mut consider = |predictor: Predictor, residual: Vec<i64>, extra: usize| {
+            let order = predictor.order();
+            if let Some((rice, rice_bits)) =
+                plan_rice(&residual, order, samples.len(), level.max_partition_order)
+            {
+                let total = header + order * bits as usize + extra + rice_bits;
+                if total < best.bits {
+                    best = Self {
+                        predictor,
+                        wasted_bits,
+                        residual,
+                        rice: Some(rice),
+                        bits: total,
+                    };
+                }
+            }
+        };
+
+        for order in 0..=4.min(samples.len().saturating_sub(1)) {
+            consider(Predictor::Fixed(order), fixed_residual(samples, order), 0);
+        }
+
+        let precision = lpc_precision(samples.len());
+        for coefficients in lpc_coefficients(samples, level.max_lpc_order) {
+            if coefficients.len() >= samples.len() {
+                break;
+            }
+            let (coefficients, shift) = quantize_coefficients(&coefficients, precision);
+            let residual = lpc_residual(samples, &coefficients, shift);
+            let extra = 4 + 5 + coefficients.len() * precision as usize;
+            let predictor = Predictor::Lpc {
+                coefficients,
+                precision,
+                shift,
+            };
+            consider(predictor, residual, extra);
+        }
+
+        best
+    }
+
+    /// Write the subframe for the original, unshifted samples
+    fn write(&self, writer: &mut BitWriter, samples: &[i64], bits: u32) {
+        let kind = match &self.predictor {
+            Predictor::Constant => 0,
+            Predictor::Verbatim => 1,
+            Predictor::Fixed(order) => 8 + *order as u64,
+            Predictor::Lpc { coefficients, .. } => 32 + coefficients.len() as u64 - 1,
+        };
+        writer.write(0, 1);
+        writer.write(kind, 6);
+        if self.wasted_bits > 0 {
+            writer.write(1, 1);
+            writer.write_unary(self.wasted_bits as u64 - 1);
+        } else {
+            writer.write(0, 1);
+        }
+
+        let bits = bits - self.wasted_bits;
+        let mut samples = samples.iter().map(|&s| s >> self.wasted_bits);
+        match &self.predictor {
+            Predictor::Constant => writer.write_signed(samples.next().unwrap_or(0), bits),
+            Predictor::Verbatim => samples.for_each(|s| writer.write_signed(s, bits)),
+            Predictor::Fixed(order) => {
+                samples
+                    .take(*order)
+                    .for_each(|s| writer.write_signed(s, bits));
+            }
+            Predictor::Lpc {
+                coefficients,
+                precision,
+                shift,
+            } => {
+                samples
+                    .take(coefficients.len())
+                    .for_each(|s| writer.write_signed(s, bits));
+                writer.write(*precision as u64 - 1, 4);
+                writer.write(*shift as u64, 5);
+                for &coefficient in coefficients {
+                    writer.write_signed(coefficient, *precision);
+                }
+            }
+        }
+
+        if let Some(rice) = &self.rice {
+            write_residual(writer, &self.residual, rice, self.predictor.order());
+        }
+    }
+}
+
+/// Residual of the fixed polynomial predictor of `order`
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let x = |back: usize| samples[i - back];
+            match order {
+                0 => x(0),
+                1 => x(0) - x(1),
+                2 => x(0) - 2 * x(1) + x(2),
+                3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+                _ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+            }
+        })
+        .collect()
+}
+
+/// Linear prediction coefficients for every order up to `max_order`, from the
+/// autocorrelation of the Tukey windowed samples
+fn lpc_coefficients(samples: &[i64], max_order: usize) -> Vec<Vec<f64>> {
+    let n = samples.len();
+    if max_order == 0 || n < 2 {
+        return Vec::new();
+    }
+
+    let taper = (n - 1) as f64 / 4.0;
+    let window = |i: usize| {
+        let edge = (i as f64).min((n - 1 - i) as f64);
+        if edge < taper {
+            0.5 - 0.5 * (std::f64::consts::PI * edge / taper).cos()
+        } else {
+            1.0
+        }
+    };
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| s as f64 * window(i))
+        .collect();
+    let autocorrelation: Vec<f64> = (0..=max_order)
+        .map(|lag| {
+            windowed[lag.min(n)..]
+                .iter()
+                .zip(&windowed)
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect();
+    if autocorrelation[0] <= 0.0 {
+        return Vec::new();
+    }
+
+    // Levinson-Durbin recursion, keeping the predictor of every order along the way
+    let mut lpc: Vec<f64> = Vec::with_capacity(max_order);
+    let mut error = autocorrelation[0];
+    let mut orders = Vec::with_capacity(max_order);
+    for i in 0..max_order {
+        let reflection = (autocorrelation[i + 1]
+            - (0..i).map(|j| lpc[j] * autocorrelation[i - j]).sum::<f64>())
+            / error;
+        let previous = lpc.clone();
+        for j in 0..i {
+            lpc[j] = previous[j] - reflection * previous[i - 1 - j];
+        }
+        lpc.push(reflection);
+        orders.push(lpc.clone());
+
+        error *= 1.0 - reflection * reflection;
+        if error <= 0.0 {
+            break;
+        }
+    }
+
+    orders
+}
+
+/// Coefficient precision in bits for a block of `size` samples, as the reference encoder
+/// picks it
+fn lpc_precision(size: usize) -> u32 {
+    match size {
+        0..=192 => 7,
+        193..=384 => 8,
+        385..=576 => 9,
+        577..=1152 => 10,
+        1153..=2304 => 11,
+        2305..=4608 => 12,
+        _ => 13,
+    }
+}
+
+/// Quantize coefficients to `precision` bits, returning them with their shift
+fn quantize_coefficients(coefficients: &[f64], precision: u32) -> (Vec<i64>, u32) {
+    let largest = coefficients.iter().fold(0.0_f64, |m, c| m.max(c.abs()));
+    let magnitude = if largest > 0.0 {
+        largest.log2().floor() as i32 + 1
+    } else {
+        0
+    };
+    let shift = (precision as i32 - 1 - magnitude).clamp(0, 15) as u32;
+
+    // Carry the rounding error into the next coefficient, so it is not lost
+    let limit = (1_i64 << (precision - 1)) - 1;
+    let mut error = 0.0;
+    let quantized = coefficients
+        .iter()
+        .map(|&c| {
+            let scaled = c * (1 << shift) as f64 + error;
+            let q = (scaled.round() as i64).clamp(-limit - 1, limit);
+            error = scaled - q as f64;
+            q
+        })
+        .collect();
+
+    (quantized, shift)
+}
+
+/// Residual of the quantized linear predictor
+fn lpc_residual(samples: &[i64], coefficients: &[i64], shift: u32) -> Vec<i64> {
+    (coefficients.len()..samples.len())
+        .map(|i| {
+            let prediction: i64 = coefficients
+                .iter()
+                .enumerate()
+                .map(|(j, &c)| c * samples[i - j - 1])
+                .sum();
+            samples[i] - (prediction >> shift)
+        })
+        .collect()
+}
+
+/// Fold signed residuals onto unsigned values, small magnitudes first
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Best Rice parameter for a partition, with its size in bits
+fn rice_parameter(values: &[u64]) -> (u32, usize) {
+    let cost = |k: u32| {
+        values
+            .iter()
+            .map(|&v| (v >> k) as usize + 1 + k as usize)
+            .sum()
+    };
+    let mean = values.iter().sum::<u64>() / values.len().max(1) as u64;
+    let estimate = (64 - mean.leading_zeros()).min(FlacFile::MAX_RICE2_PARAMETER);
+
+    (estimate.saturating_sub(1)..=(estimate + 1).min(FlacFile::MAX_RICE2_PARAMETER))
+        .map(|k| (k, cost(k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+/// Partitioning and Rice parameters that code the residual smallest, with its size in
+/// bits, or `None` when it does not fit in 32 bits
+fn plan_rice(
+    residual: &[i64],
+    order: usize,
+    block_size: usize,
+    max_partition_order: u32,
+) -> Option<(Rice, usize)> {
+    if residual
+        .iter()
+        .any(|&r| r < i32::MIN as i64 || r > i32::MAX as i64)
+    {
+        return None;
+    }
+    let values: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+
+    let mut best: Option<(Rice, usize)> = None;
+    for partition_order in 0..=max_partition_order {
+        let partitions = 1 << partition_order;
+        if !block_size.is_multiple_of(partitions) || block_size / partitions <= order {
+            break;
+        }
+
+        let length = block_size / partitions;
+        let mut start = 0;
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut bits = 2 + 4;
+        for partition in 0..partitions {
+            let count = if partition == 0 {
+                length - order
+            } else {
+                length
+            };
+            let (parameter, partition_bits) = rice_parameter(&values[start..start + count]);
+            parameters.push(parameter);
+            bits += partition_bits;
+            start += count;
+        }
+        let rice2 = parameters.iter().any(|&k| k > FlacFile::MAX_RICE_PARAMETER);
+        bits += partitions * if rice2 { 5 } else { 4 };
+
+        if best.as_ref().is_none_or(|(_, best_bits)| bits < *best_bits) {
+            let rice = Rice {
+                partition_order,
+                parameters,
+            };
+            best = Some((rice, bits));
+        }
+    }
+
+    best
+}
+
+/// Write a Rice coded residual
+fn write_residual(writer: &mut BitWriter, residual: &[i64], rice: &Rice, order: usize) {
+    let rice2 = rice
+        .parameters
+        .iter()
+        .any(|&k| k > FlacFile::MAX_RICE_PARAMETER);
+    writer.write(rice2 as u64, 2);
+    writer.write(rice.partition_order as u64, 4);
+
+    let length = (residual.len() + order) >> rice.partition_order;
+    let mut values = residual.iter().map(|&r| zigzag(r));
+    for (partition, &parameter) in rice.parameters.iter().enumerate() {
+        writer.write(parameter as u64, if rice2 { 5 } else { 4 });
+        let count = if partition == 0 {
+            length - order
+        } else {
+            length
+        };
+        for value in values.by_ref().take(count) {
+            writer.write_unary(value >> parameter);
+            writer.write(value & ((1 << parameter) - 1), parameter);
+        }
+    }
+}
+
+/// Most significant bit first writer for FLAC frames
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    filled: u32,
+}
+
+impl BitWriter {
+    /// Write the low `bits` bits of `value`, at most 32
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.buffer = (self.buffer << bits) | (value & ((1 << bits) - 1));
+        self.filled += bits;
+        while self.filled >= 8 {
+            self.filled -= 8;
+            self.bytes.push((self.buffer >> self.filled) as u8);
+        }
+    }
+
+    /// Write a two's complement value in `bits` bits
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// Write `zeros` zero bits followed by a one
+    fn write_unary(&mut self, zeros: u64) {
+        let mut left = zeros;
+        while left >= 32 {
+            self.write(0, 32);
+            left -= 32;
+        }
+        self.write(1, left as u32 + 1);
+    }
+
+    /// Pad with zero bits up to the next byte
+    fn align(&mut self) {
+        if self.filled > 0 {
+            self.write(0, 8 - self.filled);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::decode_file;
+    use crate::test_signals::track;
+    use std::process::Command;
+    use symphonia::core::audio::Channels;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::probe::Hint;
+
+    /// A second of a chord, with the channels slightly apart
+    fn chord(channels: usize) -> Vec<f32> {
+        (0..44100)
+            .flat_map(|i| {
+                let t = i as f32 / 44100.0;
+                (0..channels).map(move |c| {
+                    [220.0, 277.2, 329.6]
+                        .iter()
+                        .map(|f| {
+                            0.2 * (2.0 * std::f32::consts::PI * f * (t + c as f32 * 1e-3)).sin()
+                        })
+                        .sum::<f32>()
+                })
+            })
+            .collect()
+    }
+
+    /// Decode the file, checking the MD5 signature of the decoded samples
+    fn decode_verified(path: &Path) -> bool {
+        let file = File::open(path).unwrap();
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let probed = symphonia::default::get_probe()
+            .format(&Hint::new(), mss, &Default::default(), &Default::default())
+            .unwrap();
+        let mut format = probed.format;
+        let params = format.default_track().unwrap().codec_params.clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions { verify: true })
+            .unwrap();
+        while let Ok(packet) = format.next_packet() {
+            decoder.decode(&packet).unwrap();
+        }
+        decoder.finalize().verify_ok == Some(true)
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cases = [
+            (Channels::FRONT_CENTRE, 16, 0),
+            (Channels::FRONT_LEFT | Channels::FRONT_RIGHT, 16, 5),
+            (Channels::FRONT_LEFT | Channels::FRONT_RIGHT, 24, 8),
+            (
+                Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE,
+                20,
+                3,
+            ),
+        ];
+
+        for (layout, bits, level) in cases {
+            let channels = layout.count();
+            let samples = chord(channels);
+            let path = dir
+                .path()
+                .join(format!("{}-{}-{}.flac", channels, bits, level));
+            FlacFile::new()
+                .with_bits(bits)
+                .unwrap()
+                .with_compression(level)
+                .unwrap()
+                .with_dither(Dither::None)
+                .write(&samples, &track(layout), &path)
+                .unwrap();
+
+            let (decoded, decoded_track) = decode_file(&path).unwrap();
+            assert_eq!(decoded_track.codec_params.bits_per_sample, Some(bits));
+            assert_eq!(decoded.len(), samples.len());
+            let expected = quantize(&samples, channels, bits, Dither::None);
+            let scale = (1_i64 << (bits - 1)) as f32;
+            assert!(
+                decoded
+                    .iter()
+                    .zip(&expected)
+                    .all(|(&d, &e)| (d * scale).round() as i32 == e),
+                "{}-bit {} channel file at level {} is not lossless",
+                bits,
+                channels,
+                level
+            );
+            assert!(decode_verified(&path));
+
+            // Real compression, well under the size of the PCM
+            let size = std::fs::metadata(&path).unwrap().len() as usize;
+            assert!(size < samples.len() * bits as usize / 8 / 2);
+        }
+    }
+
+    #[test]
+    fn test_reference_decoder_accepts_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = chord(2);
+
+        let mut paths = Vec::new();
+        for (bits, level) in [(16, 0), (16, FlacFile::DEFAULT_LEVEL), (24, 8)] {
+            let path = dir.path().join(format!("{}-{}.flac", bits, level));
+            FlacFile::new()
+                .with_bits(bits)
+                .unwrap()
+                .with_compression(level)
+                .unwrap()
+                .with_tag("TITLE", "Airhorn")
+                .unwrap()
+                .write(&samples, &stereo, &path)
+                .unwrap();
+            paths.push(path);
+        }
+
+        // `flac -t` decodes every frame and checks the CRCs and the MD5 signature
+        let output = match Command::new("flac").arg("-t").args(&paths).output() {
+            Ok(output) => output,
+            Err(e) if std::env::var_os("CI").is_some() => {
+                panic!("The flac command line tool is needed in CI: {}", e)
+            }
+            Err(_) => {
+                eprintln!("Skipping the reference decoder check, flac is not installed");
+                return;
+            }
+        };
+        assert!(
+            output.status.success(),
+            "flac -t rejected the files: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    #[test]
+    fn test_higher_levels_compress_smaller() {
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let samples = chord(2);
+        let size = |level: usize| {
+            FlacFile::new()
+                .with_compression(level)
+                .unwrap()
+                .write_to_buffer(&samples, &stereo)
+                .unwrap()
+                .len()
+        };
+
+        let (fastest, default, smallest) = (size(0), size(FlacFile::DEFAULT_LEVEL), size(8));
+        assert!(
+            smallest <= default && default < fastest,
+            "Expected sizes to shrink with the level ({} / {} / {} bytes)",
+            fastest,
+            default,
+            smallest
+        );
+    }
+
+    #[test]
+    fn test_vorbis_comments() {
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let file = FlacFile::new()
+            .with_tag("title", "Airhorn")
+            .unwrap()
+            .with_tag("ARTIST", "earpeace")
+            .unwrap()
+            .write_to_buffer(&chord(2)[..4000], &stereo)
+            .unwrap();
+
+        // The comment block follows the 34 byte stream info
+        let block = &file[4 + 4 + 34..];
+        assert_eq!(block[0], 0x84);
+        let length = u32::from_be_bytes([0, block[1], block[2], block[3]]) as usize;
+        let comments = &block[4..4 + length];
+        let text = String::from_utf8_lossy(comments);
+        assert!(text.contains("TITLE=Airhorn"));
+        assert!(text.contains("ARTIST=earpeace"));
+        assert_eq!(u32::from_le_bytes(comments[12..16].try_into().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        use crate::audio_metadata::TagKey;
+        use crate::dsp::decode_file_with_metadata;
+
+        let dir = tempfile::tempdir().unwrap();
+        let stereo = track(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        let metadata = Metadata::new()
+            .with_tag(TagKey::Title, "Airhorn")
+            .unwrap()
+            .with_tag(TagKey::TrackNumber, "3")
+            .unwrap()
+            .with_picture(Picture::front_cover("image/jpeg", vec![0xff, 0xd8, 0xff]));
+
+        let path = dir.path().join("tagged.flac");
+        FlacFile::new()
+            .with_metadata(metadata.clone())
+            .write(&chord(2)[..4000], &stereo, &path)
+            .unwrap();
+        let (decoded, _, read) = decode_file_with_metadata(&path).unwrap();
+        assert_eq!(decoded.len(), 4000);
+        assert_eq!(read, metadata);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(FlacFile::new().with_compression(9).is_err());
+        assert!(FlacFile::new().with_bits(4).is_err());
+        assert!(FlacFile::new().with_bits(32).is_err());
+        assert!(FlacFile::new().with_tag("A=B", "c").is_err());
+        assert!(FlacFile::new().with_tag("", "c").is_err());
+        assert!(FlacFile::new().with_tag("COMMENT", "a=b").is_ok());
+    }
+}
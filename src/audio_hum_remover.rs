@@ -0,0 +1,312 @@
+use anyhow::{Error, Result};
+use log::{debug, info};
+
+use crate::dsp::{average_power_spectrum, median, AudioProcessor, Biquad};
+
+/// Finds 50 or 60 Hz mains hum and its harmonics, and notches them out
+///
+/// Hum shows up as a series of narrow peaks at multiples of the mains frequency that
+/// stand well clear of the spectrum around them. Clips without such a series are passed
+/// through untouched.
+pub struct HumRemover {
+    threshold: f64,
+    max_harmonics: usize,
+}
+
+/// A hum partial that was found, and removed when processing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HumPartial {
+    /// Frequency of the peak in Hz
+    pub frequency: f64,
+    /// Height of the peak over the surrounding spectrum in dB
+    pub prominence: f64,
+}
+
+/// The hum found in a clip
+#[derive(Debug, Clone, PartialEq)]
+pub struct HumReport {
+    /// Nominal mains frequency, 50 or 60 Hz
+    pub mains: f64,
+    /// Partials of the series that stand out, lowest first
+    pub partials: Vec<HumPartial>,
+}
+
+impl Default for HumRemover {
+    fn default() -> Self {
+        Self {
+            threshold: Self::DEFAULT_THRESHOLD,
+            max_harmonics: Self::DEFAULT_MAX_HARMONICS,
+        }
+    }
+}
+
+impl HumRemover {
+    /// Mains frequencies in use around the world, in Hz
+    pub const MAINS_FREQUENCIES: [f64; 2] = [50.0, 60.0];
+    /// Default height in dB a peak must stand above its surroundings to count as hum
+    pub const DEFAULT_THRESHOLD: f64 = 12.0;
+    /// Default number of partials searched, counting the fundamental
+    pub const DEFAULT_MAX_HARMONICS: usize = 8;
+    /// Most partials that can be searched
+    pub const MAX_HARMONICS: usize = 20;
+
+    /// About 1.3 Hz per bin at 44.1 kHz, fine enough to separate hum from its surroundings
+    const ANALYSIS_FFT_SIZE: usize = 32768;
+    /// Partials needed before a series is taken for hum rather than a single low note
+    const MIN_PARTIALS: usize = 2;
+    /// How far each partial may drift from its nominal frequency, per harmonic number, in Hz
+    const DRIFT_HZ: f64 = 0.5;
+    /// The surroundings of a peak start this far from it, past the window's main lobe
+    const BACKGROUND_GAP_HZ: f64 = 5.0;
+    /// and reach this far from it
+    const BACKGROUND_WIDTH_HZ: f64 = 25.0;
+    /// Width of every notch in Hz
+    const NOTCH_BANDWIDTH: f64 = 4.0;
+    /// The notches are settled on this much of the clip before processing, so they do not
+    /// ring at the start
+    const WARM_UP_MS: f64 = 400.0;
+
+    pub fn new(threshold: f64, max_harmonics: usize) -> Result<Self> {
+        if threshold <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Threshold must be positive (got: {} dB)",
+                threshold
+            ));
+        }
+
+        if !(1..=Self::MAX_HARMONICS).contains(&max_harmonics) {
+            return Err(anyhow::anyhow!(
+                "Harmonics must be between 1 and {} (got: {})",
+                Self::MAX_HARMONICS,
+                max_harmonics
+            ));
+        }
+
+        Ok(Self {
+            threshold,
+            max_harmonics,
+        })
+    }
+
+    /// Find the hum series in the clip, or `None` when there is none
+    pub fn detect(&self, samples: &[f32], channels: usize, sample_rate: u32) -> Option<HumReport> {
+        let power = average_power_spectrum(samples, channels, Self::ANALYSIS_FFT_SIZE);
+        let bin_width = sample_rate as f64 / Self::ANALYSIS_FFT_SIZE as f64;
+        let nyquist = sample_rate as f64 / 2.0;
+
+        Self::MAINS_FREQUENCIES
+            .iter()
+            .map(|&mains| {
+                let partials: Vec<HumPartial> = (1..=self.max_harmonics)
+                    .map(|k| k as f64 * mains)
+                    .take_while(|&f| f + Self::BACKGROUND_WIDTH_HZ < nyquist)
+                    .enumerate()
+                    .filter_map(|(i, f)| {
+                        Self::partial(&power, bin_width, f, (i + 1) as f64 * Self::DRIFT_HZ)
+                    })
+                    .filter(|partial| partial.prominence > self.threshold)
+                    .collect();
+                HumReport { mains, partials }
+            })
+            .filter(|report| report.partials.len() >= Self::MIN_PARTIALS)
+            .max_by(|a, b| {
+                let strength = |r: &HumReport| r.partials.iter().map(|p| p.prominence).sum::<f64>();
+                strength(a).total_cmp(&strength(b))
+            })
+    }
+
+    /// Remove the hum, returning the samples and the hum that was removed
+    pub fn remove(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> (Vec<f32>, Option<HumReport>) {
+        let Some(report) = self.detect(samples, channels, sample_rate) else {
+            return (samples.to_vec(), None);
+        };
+
+        let notches: Vec<Biquad> = report
+            .partials
+            .iter()
+            .map(|p| {
+                Biquad::notch(
+                    sample_rate,
+                    p.frequency,
+                    p.frequency / Self::NOTCH_BANDWIDTH,
+                )
+            })
+            .collect();
+
+        // Hum repeats every mains period, so filters settled on a whole number of periods
+        // start in step with it
+        let frames = samples.len() / channels;
+        let period = sample_rate as f64 / report.mains;
+        let periods = (Self::WARM_UP_MS * 0.001 * sample_rate as f64 / period)
+            .ceil()
+            .min((frames as f64 / period).floor());
+        let warm_up = (periods * period).round() as usize;
+
+        let mut output = samples.to_vec();
+        for channel in 0..channels {
+            let mut filters = notches.clone();
+            for frame in samples.chunks_exact(channels).take(warm_up) {
+                filters
+                    .iter_mut()
+                    .fold(frame[channel] as f64, |x, f| f.process(x));
+            }
+
+            for frame in output.chunks_exact_mut(channels) {
+                frame[channel] = filters
+                    .iter_mut()
+                    .fold(frame[channel] as f64, |x, f| f.process(x))
+                    as f32;
+            }
+        }
+
+        (output, Some(report))
+    }
+
+    /// Peak nearest `frequency`, within `drift` Hz, and its height over the surroundings
+    fn partial(power: &[f64], bin_width: f64, frequency: f64, drift: f64) -> Option<HumPartial> {
+        let bin = |f: f64| (f / bin_width).round() as usize;
+        let search = bin(frequency - drift.max(bin_width))..=bin(frequency + drift.max(bin_width));
+        let peak = search.max_by(|&a, &b| power[a].total_cmp(&power[b]))?;
+
+        let mut background: Vec<f64> = (bin(frequency - Self::BACKGROUND_WIDTH_HZ)
+            ..=bin(frequency + Self::BACKGROUND_WIDTH_HZ))
+            .filter(|&k| (k as f64 * bin_width - frequency).abs() > Self::BACKGROUND_GAP_HZ)
+            .map(|k| power[k])
+            .collect();
+        let background = median(&mut background)?;
+        if power[peak] <= 0.0 || background <= 0.0 {
+            return None;
+        }
+
+        // Parabolic interpolation between the neighbouring bins finds the true peak
+        let (left, right) = (power[peak - 1].sqrt(), power[peak + 1].sqrt());
+        let centre = power[peak].sqrt();
+        let curvature = left - 2.0 * centre + right;
+        let offset = if curvature < 0.0 {
+            0.5 * (left - right) / curvature
+        } else {
+            0.0
+        };
+
+        Some(HumPartial {
+            frequency: (peak as f64 + offset) * bin_width,
+            prominence: 10.0 * (power[peak] / background).log10(),
+        })
+    }
+}
+
+impl AudioProcessor for HumRemover {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let (output, report) = self.remove(samples, channels, sample_rate);
+
+        match report {
+            Some(report) => info!(
+                "Removed {} Hz hum at {}",
+                report.mains,
+                report
+                    .partials
+                    .iter()
+                    .map(|p| format!("{:.1} Hz ({:.0} dB)", p.frequency, p.prominence))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            None => debug!("No hum found"),
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::noise;
+
+    /// Noise with hum at the given partials, as (frequency, amplitude)
+    fn hummy(sample_rate: u32, partials: &[(f32, f32)]) -> Vec<f32> {
+        noise(sample_rate as usize * 2, 0.1)
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| {
+                let t = i as f32 / sample_rate as f32;
+                n + partials
+                    .iter()
+                    .map(|&(f, a)| a * (2.0 * std::f32::consts::PI * f * t).sin())
+                    .sum::<f32>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detects_mains_frequency() {
+        let sample_rate = 44100;
+        let remover = HumRemover::default();
+
+        let european = hummy(sample_rate, &[(50.0, 0.05), (150.0, 0.03), (250.0, 0.02)]);
+        let report = remover.detect(&european, 1, sample_rate).unwrap();
+        assert_eq!(report.mains, 50.0);
+        let found: Vec<f64> = report.partials.iter().map(|p| p.frequency).collect();
+        for expected in [50.0, 150.0, 250.0] {
+            assert!(
+                found.iter().any(|f| (f - expected).abs() < 0.5),
+                "Expected a partial at {} Hz, found {:?}",
+                expected,
+                found
+            );
+        }
+
+        let american = hummy(sample_rate, &[(60.0, 0.05), (120.0, 0.03)]);
+        assert_eq!(
+            remover.detect(&american, 1, sample_rate).unwrap().mains,
+            60.0
+        );
+    }
+
+    #[test]
+    fn test_removes_hum() {
+        let sample_rate = 44100;
+        let clip = hummy(sample_rate, &[(50.0, 0.05), (100.0, 0.04), (150.0, 0.03)]);
+        let remover = HumRemover::default();
+
+        let (processed, report) = remover.remove(&clip, 1, sample_rate);
+        assert_eq!(report.unwrap().partials.len(), 3);
+        assert_eq!(remover.detect(&processed, 1, sample_rate), None);
+
+        // The broadband content is left as it was
+        let rms = |s: &[f32]| (s.iter().map(|&x| x * x).sum::<f32>() / s.len() as f32).sqrt();
+        let noise_rms = rms(&noise(sample_rate as usize * 2, 0.1));
+        assert!((rms(&processed) / noise_rms - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_skips_clips_without_hum() {
+        let sample_rate = 44100;
+        let clip = noise(sample_rate as usize * 2, 0.1);
+        let remover = HumRemover::default();
+
+        let (processed, report) = remover.remove(&clip, 1, sample_rate);
+        assert_eq!(report, None);
+        assert_eq!(processed, clip);
+
+        // A single low note is not a hum series
+        let note = hummy(sample_rate, &[(55.0, 0.05)]);
+        assert_eq!(remover.detect(&note, 1, sample_rate), None);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(HumRemover::new(0.0, 8).is_err());
+        assert!(HumRemover::new(12.0, 0).is_err());
+        assert!(HumRemover::new(12.0, 30).is_err());
+    }
+}
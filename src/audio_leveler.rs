@@ -0,0 +1,260 @@
+use anyhow::{Context, Error, Result};
+use ebur128::{EbuR128, Mode};
+use log::debug;
+
+use crate::audio_normalizer::Normalizer;
+use crate::dsp::{db_to_linear, AudioProcessor};
+
+/// Slow automatic gain control that rides clips towards a target short-term loudness
+///
+/// A clip with both a whisper and a scream gets a single gain from the [`Normalizer`] and
+/// stays uneven. The leveler lifts the quiet parts and pulls down the loud ones over
+/// seconds, following the EBU R128 short-term loudness, so it evens out the clip without
+/// squashing it like a compressor would. Silence below the gate holds the gain instead of
+/// being brought up.
+pub struct Leveler {
+    target: f64,
+    max_boost: f64,
+    max_cut: f64,
+    hold_ms: f64,
+    gate: f64,
+}
+
+impl Default for Leveler {
+    fn default() -> Self {
+        Self {
+            target: Normalizer::DEFAULT_TARGET_LOUDNESS,
+            max_boost: Self::DEFAULT_MAX_BOOST,
+            max_cut: Self::DEFAULT_MAX_CUT,
+            hold_ms: Self::DEFAULT_HOLD_MS,
+            gate: Self::DEFAULT_GATE,
+        }
+    }
+}
+
+impl Leveler {
+    pub const DEFAULT_MAX_BOOST: f64 = 12.0;
+    pub const DEFAULT_MAX_CUT: f64 = 12.0;
+    /// Largest boost or cut allowed in dB
+    pub const MAX_GAIN: f64 = 24.0;
+    /// Default time the gain is held after a cut before it may rise again
+    pub const DEFAULT_HOLD_MS: f64 = 500.0;
+    /// Default short-term loudness in LUFS below which the clip is taken to be silent
+    pub const DEFAULT_GATE: f64 = -50.0;
+
+    /// Interval between short-term loudness readings
+    const STEP_MS: u32 = 100;
+    /// Length of the EBU R128 short-term window
+    const SHORT_TERM_MS: u32 = 3000;
+    /// Time constant of the gain when cutting
+    const CUT_MS: f64 = 500.0;
+    /// Time constant of the gain when boosting, slower so pauses are not pumped up
+    const BOOST_MS: f64 = 1500.0;
+
+    pub fn new(target: f64, max_boost: f64, max_cut: f64) -> Result<Self> {
+        if target > Normalizer::MAX_TARGET_LOUDNESS {
+            return Err(anyhow::anyhow!(
+                "Target loudness {} LUFS exceeds maximum allowed value of {} LUFS",
+                target,
+                Normalizer::MAX_TARGET_LOUDNESS
+            ));
+        }
+
+        for (name, gain) in [("Maximum boost", max_boost), ("Maximum cut", max_cut)] {
+            if gain <= 0.0 || gain > Self::MAX_GAIN {
+                return Err(anyhow::anyhow!(
+                    "{} must be between 0 and {} dB (got: {} dB)",
+                    name,
+                    Self::MAX_GAIN,
+                    gain
+                ));
+            }
+        }
+
+        Ok(Self {
+            target,
+            max_boost,
+            max_cut,
+            ..Self::default()
+        })
+    }
+
+    /// Hold the gain for `hold_ms` after a cut before letting it rise again
+    pub fn with_hold(mut self, hold_ms: f64) -> Result<Self> {
+        if hold_ms < 0.0 {
+            return Err(anyhow::anyhow!(
+                "Hold time must not be negative (got: {} ms)",
+                hold_ms
+            ));
+        }
+
+        self.hold_ms = hold_ms;
+        Ok(self)
+    }
+
+    /// Hold the gain while the short-term loudness is below `gate` LUFS
+    pub fn with_gate(mut self, gate: f64) -> Result<Self> {
+        if gate >= self.target {
+            return Err(anyhow::anyhow!(
+                "Gate must be below the target loudness (got: {} LUFS >= {} LUFS)",
+                gate,
+                self.target
+            ));
+        }
+
+        self.gate = gate;
+        Ok(self)
+    }
+
+    /// Short-term loudness readings every [`Self::STEP_MS`], as (centre frame, LUFS)
+    ///
+    /// The short-term window is zero filled until it has seen 3 s of audio, so the early
+    /// readings are scaled up to the audio that was actually there.
+    fn short_term_loudness(
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<(f64, f64)>> {
+        let mut ebu = EbuR128::new(channels as u32, sample_rate, Mode::S)
+            .context("Failed to create EBU R128 analyzer")?;
+
+        let step = (sample_rate * Self::STEP_MS / 1000) as usize;
+        let window = (sample_rate * Self::SHORT_TERM_MS / 1000) as usize;
+        let mut readings = Vec::new();
+        let mut seen = 0;
+        for block in samples.chunks(step * channels) {
+            ebu.add_frames_f32(block)
+                .context("Failed to analyze audio samples")?;
+            seen += block.len() / channels;
+
+            let covered = seen.min(window);
+            let loudness = ebu
+                .loudness_shortterm()
+                .context("Failed to measure short-term loudness")?
+                + 10.0 * (window as f64 / covered as f64).log10();
+            readings.push((seen as f64 - covered as f64 / 2.0, loudness));
+        }
+
+        Ok(readings)
+    }
+}
+
+impl AudioProcessor for Leveler {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let readings = Self::short_term_loudness(samples, channels, sample_rate)?;
+
+        let step_coeff = |ms: f64| (-(Self::STEP_MS as f64) / ms).exp();
+        let (cut_coeff, boost_coeff) = (step_coeff(Self::CUT_MS), step_coeff(Self::BOOST_MS));
+        let hold_steps = (self.hold_ms / Self::STEP_MS as f64).round() as usize;
+
+        // Ride the gain at the rate of the readings, starting from the first audible one
+        let mut gain = readings
+            .iter()
+            .find(|(_, loudness)| *loudness >= self.gate)
+            .map_or(0.0, |(_, loudness)| {
+                (self.target - loudness).clamp(-self.max_cut, self.max_boost)
+            });
+        let mut hold = 0;
+        let mut control = Vec::with_capacity(readings.len());
+        for &(frame, loudness) in &readings {
+            if loudness >= self.gate {
+                let wanted = (self.target - loudness).clamp(-self.max_cut, self.max_boost);
+                if wanted < gain {
+                    gain = cut_coeff * gain + (1.0 - cut_coeff) * wanted;
+                    hold = hold_steps;
+                } else if hold > 0 {
+                    hold -= 1;
+                } else {
+                    gain = boost_coeff * gain + (1.0 - boost_coeff) * wanted;
+                }
+            }
+            control.push((frame, gain));
+        }
+
+        debug!(
+            "Leveler gain ranged from {:.1} dB to {:.1} dB",
+            control.iter().map(|c| c.1).fold(f64::INFINITY, f64::min),
+            control
+                .iter()
+                .map(|c| c.1)
+                .fold(f64::NEG_INFINITY, f64::max)
+        );
+
+        // Interpolate the gain between the readings, sample by sample
+        let mut output = Vec::with_capacity(samples.len());
+        let mut next = 0;
+        for (index, frame) in samples.chunks(channels).enumerate() {
+            let position = index as f64;
+            while next < control.len() && control[next].0 <= position {
+                next += 1;
+            }
+            let gain_db = match (next.checked_sub(1).map(|i| control[i]), control.get(next)) {
+                (Some((from, a)), Some(&(to, b))) => a + (b - a) * (position - from) / (to - from),
+                (Some((_, a)), None) => a,
+                (None, Some(&(_, b))) => b,
+                (None, None) => 0.0,
+            };
+
+            let gain = db_to_linear(gain_db) as f32;
+            output.extend(frame.iter().map(|&s| s * gain));
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::rms_db;
+    use crate::test_signals::sine;
+
+    #[test]
+    fn test_evens_out_whisper_and_scream() {
+        let sample_rate = 48000;
+        let sr = sample_rate as usize;
+        let mut clip = sine(1000.0, 0.02, sample_rate, 3 * sr);
+        clip.extend(sine(1000.0, 0.5, sample_rate, 3 * sr));
+
+        let processed = Leveler::default().process(&clip, 1, sample_rate).unwrap();
+
+        let spread = |s: &[f32]| rms_db(&s[sr * 4..sr * 5]) - rms_db(&s[sr..sr * 2]);
+        assert!(
+            spread(&processed) < spread(&clip) - 10.0,
+            "Expected the clip to even out ({:.1} dB -> {:.1} dB)",
+            spread(&clip),
+            spread(&processed)
+        );
+    }
+
+    #[test]
+    fn test_holds_gain_through_silence() {
+        let sample_rate = 48000;
+        let sr = sample_rate as usize;
+        let mut clip = sine(1000.0, 0.3, sample_rate, 2 * sr);
+        let floor = sine(1000.0, 1e-4, sample_rate, 2 * sr);
+        clip.extend(&floor);
+
+        let processed = Leveler::default().process(&clip, 1, sample_rate).unwrap();
+        let floor_gain = rms_db(&processed[sr * 3..]) - rms_db(&floor[sr..]);
+        assert!(
+            floor_gain < 0.5,
+            "Expected the noise floor to stay down, got {:.1} dB",
+            floor_gain
+        );
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(Leveler::new(-10.0, 12.0, 12.0).is_err());
+        assert!(Leveler::new(-18.0, 0.0, 12.0).is_err());
+        assert!(Leveler::new(-18.0, 12.0, 30.0).is_err());
+        assert!(Leveler::default().with_hold(-1.0).is_err());
+        assert!(Leveler::default().with_gate(-10.0).is_err());
+    }
+}
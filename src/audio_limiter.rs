@@ -1,12 +1,126 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
 use anyhow::Error;
 use log::debug;
 
-use crate::dsp::{db_to_linear, AudioProcessor};
+use crate::dsp::{db_to_linear, AudioProcessor, StreamingAdapter, StreamingProcessor};
 
 pub struct Limiter {
     threshold: f64,
     release_time: f64,
     lookahead: usize,
+    true_peak: bool,
+    state: Option<LimiterState>,
+}
+
+/// Running state used when the limiter is driven block by block
+struct LimiterState {
+    channels: usize,
+    threshold_linear: f32,
+    release_coeff: f32,
+    lookahead_frames: usize,
+    /// Interleaved ring buffer delaying the signal by `lookahead_frames`
+    delay: Vec<f32>,
+    delay_pos: usize,
+    /// Monotonic queue of `(frame, gain)` tracking the minimum gain over the lookahead window
+    window: VecDeque<(u64, f32)>,
+    frame: u64,
+    current_reduction: f32,
+    /// Oversampling detector, when limiting true peaks
+    detector: Option<TruePeakDetector>,
+}
+
+/// Oversampling peak detector following the true-peak meter of ITU-R BS.1770
+///
+/// Every channel is upsampled with a Hann-windowed sinc, 4x below 96 kHz and 2x below
+/// 192 kHz, the same way `ebur128` measures true peak, so the limiter holds the ceiling
+/// that the output is measured against.
+struct TruePeakDetector {
+    factor: usize,
+    /// Taps of each polyphase branch
+    taps: usize,
+    /// Coefficient `tap * factor + phase` of the interpolation filter
+    filter: Vec<f32>,
+    /// Interleaved ring buffer of the last `taps` frames
+    history: Vec<f32>,
+    history_pos: usize,
+    channels: usize,
+}
+
+impl TruePeakDetector {
+    /// Length of the interpolation filter across all phases
+    const FILTER_LENGTH: usize = 48;
+
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let factor = match sample_rate {
+            0..96_000 => 4,
+            96_000..192_000 => 2,
+            // Sampled finely enough that the sample peak is the true peak
+            _ => 1,
+        };
+
+        let filter: Vec<f32> = if factor == 1 {
+            vec![1.0]
+        } else {
+            let length = Self::FILTER_LENGTH as f64;
+            (0..Self::FILTER_LENGTH)
+                .map(|j| {
+                    let j = j as f64;
+                    let window = 0.5 * (1.0 - (2.0 * PI * j / length).cos());
+                    let x = (j - length / 2.0) * PI / factor as f64;
+                    let sinc = if x.abs() > 1e-6 { x.sin() / x } else { 1.0 };
+                    (window * sinc) as f32
+                })
+                .collect()
+        };
+        let taps = filter.len() / factor;
+
+        Self {
+            factor,
+            taps,
+            filter,
+            history: vec![0.0; taps * channels],
+            history_pos: 0,
+            channels,
+        }
+    }
+
+    /// Frames between a sample entering the detector and a peak next to it being reported
+    ///
+    /// A peak depends on the `taps` frames around it, so holding the gain down over twice
+    /// this delay covers every sample that builds it.
+    fn delay(&self) -> usize {
+        self.taps / 2
+    }
+
+    /// Add a frame and return the highest interpolated magnitude across its channels
+    fn push(&mut self, frame: &[f32]) -> f32 {
+        self.history[self.history_pos * self.channels..(self.history_pos + 1) * self.channels]
+            .copy_from_slice(frame);
+
+        let mut peak = 0.0_f32;
+        for channel in 0..self.channels {
+            for phase in 0..self.factor {
+                let mut value = 0.0_f32;
+                for tap in 0..self.taps {
+                    // Newest frame first, so tap 0 meets the newest sample
+                    let pos = (self.history_pos + self.taps - tap) % self.taps;
+                    value += self.filter[tap * self.factor + phase]
+                        * self.history[pos * self.channels + channel];
+                }
+                peak = peak.max(value.abs());
+            }
+        }
+
+        self.history_pos = (self.history_pos + 1) % self.taps;
+        peak
+    }
+
+    fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.history_pos = 0;
+    }
 }
 
 impl Default for Limiter {
@@ -15,6 +129,8 @@ impl Default for Limiter {
             threshold: Self::DEFAULT_THRESHOLD,
             release_time: Self::DEFAULT_RELEASE_TIME,
             lookahead: Self::DEFAULT_LOOKAHEAD_MS,
+            true_peak: false,
+            state: None,
         }
     }
 }
@@ -57,17 +173,35 @@ impl Limiter {
             threshold,
             release_time,
             lookahead: lookahead_ms,
+            true_peak: false,
+            state: None,
         })
     }
+
+    /// Detect peaks on a 4x oversampled signal, holding the true peak rather than the sample
+    /// peak under the threshold
+    pub fn with_true_peak(mut self) -> Self {
+        self.true_peak = true;
+        self
+    }
 }
 
 impl AudioProcessor for Limiter {
     fn process(
         &self,
         samples: &[f32],
-        _channels: usize,
+        channels: usize,
         sample_rate: u32,
     ) -> Result<Vec<f32>, Error> {
+        if self.true_peak {
+            // The oversampling detector runs frame by frame, which the streaming path does
+            let limiter = Limiter {
+                state: None,
+                ..*self
+            };
+            return StreamingAdapter::new(limiter).process(samples, channels, sample_rate);
+        }
+
         let threshold_linear = db_to_linear(self.threshold);
         let release_samples = (self.release_time * 0.001 * sample_rate as f64) as usize;
         let lookahead_samples = (self.lookahead as f64 * 0.001 * sample_rate as f64) as usize;
@@ -112,3 +246,195 @@ impl AudioProcessor for Limiter {
         Ok(output)
     }
 }
+
+impl StreamingProcessor for Limiter {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) -> Result<(), Error> {
+        if channels == 0 {
+            return Err(anyhow::anyhow!("Channel count must be greater than 0"));
+        }
+
+        let release_samples = (self.release_time * 0.001 * sample_rate as f64).max(1.0);
+        let detector = self
+            .true_peak
+            .then(|| TruePeakDetector::new(channels, sample_rate));
+        // Hold the gain over every frame that builds a detected true peak
+        let lookahead_frames = ((self.lookahead as f64 * 0.001 * sample_rate as f64) as usize)
+            .max(detector.as_ref().map_or(1, |d| (2 * d.delay()).max(1)));
+
+        self.state = Some(LimiterState {
+            channels,
+            threshold_linear: db_to_linear(self.threshold) as f32,
+            release_coeff: (-1.0 / release_samples).exp() as f32,
+            lookahead_frames,
+            delay: vec![0.0; lookahead_frames * channels],
+            delay_pos: 0,
+            window: VecDeque::with_capacity(lookahead_frames + 2),
+            frame: 0,
+            current_reduction: 1.0,
+            detector,
+        });
+
+        Ok(())
+    }
+
+    fn process_block(&mut self, block: &mut [f32]) -> Result<(), Error> {
+        let state = self
+            .state
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Limiter must be prepared before processing"))?;
+
+        if !block.len().is_multiple_of(state.channels) {
+            return Err(anyhow::anyhow!(
+                "Block length {} is not a multiple of {} channels",
+                block.len(),
+                state.channels
+            ));
+        }
+
+        for frame in block.chunks_exact_mut(state.channels) {
+            // Gain needed to keep the incoming frame under the threshold
+            let peak = match state.detector.as_mut() {
+                Some(detector) => detector.push(frame),
+                None => frame.iter().fold(0.0_f32, |acc, &s| acc.max(s.abs())),
+            };
+            let reduction = if peak > state.threshold_linear {
+                state.threshold_linear / peak
+            } else {
+                1.0
+            };
+
+            while state.window.back().is_some_and(|&(_, g)| g >= reduction) {
+                state.window.pop_back();
+            }
+            state.window.push_back((state.frame, reduction));
+            while state
+                .window
+                .front()
+                .is_some_and(|&(f, _)| f + (state.lookahead_frames as u64) < state.frame)
+            {
+                state.window.pop_front();
+            }
+
+            // The window covers every frame still sitting in the delay line
+            let target_reduction = state.window.front().map_or(1.0, |&(_, g)| g);
+            if target_reduction < state.current_reduction {
+                state.current_reduction = target_reduction;
+            } else {
+                state.current_reduction = target_reduction
+                    + (state.current_reduction - target_reduction) * state.release_coeff;
+            }
+
+            let offset = state.delay_pos * state.channels;
+            for (sample, delayed) in frame
+                .iter_mut()
+                .zip(&mut state.delay[offset..offset + state.channels])
+            {
+                let input = *sample;
+                *sample = *delayed * state.current_reduction;
+                *delayed = input;
+            }
+
+            state.delay_pos = (state.delay_pos + 1) % state.lookahead_frames;
+            state.frame += 1;
+        }
+
+        Ok(())
+    }
+
+    fn latency(&self) -> usize {
+        self.state
+            .as_ref()
+            .map_or(0, |state| state.lookahead_frames)
+    }
+
+    fn reset(&mut self) {
+        if let Some(state) = self.state.as_mut() {
+            state.delay.fill(0.0);
+            state.delay_pos = 0;
+            state.window.clear();
+            state.frame = 0;
+            state.current_reduction = 1.0;
+            if let Some(detector) = state.detector.as_mut() {
+                detector.reset();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_normalizer::measure_loudness_and_true_peak;
+    use crate::dsp::max_peak;
+    use crate::test_signals::sine;
+
+    #[test]
+    fn test_streaming_limiter_holds_threshold() {
+        let sample_rate = 44100;
+        let samples = sine(440.0, 1.0, sample_rate, sample_rate as usize);
+
+        let mut limiter = Limiter::new(-6.0, 50.0, 5).unwrap();
+        limiter.prepare(1, sample_rate).unwrap();
+
+        let mut output = samples.clone();
+        for block in output.chunks_mut(128) {
+            limiter.process_block(block).unwrap();
+        }
+
+        assert!(max_peak(&output) <= db_to_linear(-6.0) + 1e-6);
+    }
+
+    #[test]
+    fn test_streaming_adapter_compensates_latency() {
+        let sample_rate = 48000;
+        let samples = sine(440.0, 0.25, sample_rate, 4800);
+
+        // Below threshold the limiter is transparent, so the output should line up exactly
+        let adapter = StreamingAdapter::new(Limiter::default());
+        let output = adapter.process(&samples, 1, sample_rate).unwrap();
+
+        assert_eq!(output.len(), samples.len());
+        for (a, b) in samples.iter().zip(&output) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_true_peak_limiter_holds_inter_sample_peaks() {
+        let sample_rate = 44100;
+        // A quarter of the sample rate at 45 degrees peaks halfway between samples, 3 dB
+        // above the sample peak
+        let samples: Vec<f32> = (0..sample_rate as usize)
+            .map(|i| {
+                0.95 * (std::f32::consts::FRAC_PI_2 * i as f32 + std::f32::consts::FRAC_PI_4).sin()
+            })
+            .collect();
+
+        let sample_peak = Limiter::new(-1.0, 50.0, 5).unwrap();
+        let output = sample_peak.process(&samples, 1, sample_rate).unwrap();
+        let (_, true_peak) = measure_loudness_and_true_peak(1, sample_rate, &output).unwrap();
+        assert!(
+            true_peak > -1.0,
+            "Sample peaks under the threshold should pass, got {:.2} dBTP",
+            true_peak
+        );
+
+        let limiter = Limiter::new(-1.0, 50.0, 5).unwrap().with_true_peak();
+        let output = limiter.process(&samples, 1, sample_rate).unwrap();
+        assert_eq!(output.len(), samples.len());
+        let (_, true_peak) = measure_loudness_and_true_peak(1, sample_rate, &output).unwrap();
+        // Allow for rounding between the detector and the meter
+        assert!(
+            true_peak <= -1.0 + 1e-3,
+            "Expected true peak under -1.0 dBTP, got {:.3} dBTP",
+            true_peak
+        );
+    }
+
+    #[test]
+    fn test_process_block_requires_prepare() {
+        let mut limiter = Limiter::default();
+        let mut block = vec![0.0; 16];
+        assert!(limiter.process_block(&mut block).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::Result;
+
+use crate::dsp::{hann_window, spectrum};
+
+/// Zwicker-style short-term loudness model for clips too short for gated LUFS
+///
+/// Each 10 ms frame is weighted by the outer and middle ear, grouped into half-Bark bands,
+/// spread into an excitation pattern and converted to specific loudness. Short-term and
+/// long-term loudness follow with the time constants of Glasberg & Moore (2002), and the
+/// overall loudness of a clip is its peak long-term loudness.
+#[derive(Debug, Clone, Copy)]
+pub struct LoudnessModel {
+    full_scale_spl: f64,
+}
+
+/// Loudness of a clip as judged by the [`LoudnessModel`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PerceptualLoudness {
+    /// Peak short-term loudness in sone
+    pub short_term: f64,
+    /// Peak long-term loudness in sone
+    pub long_term: f64,
+    /// Loudness level of the peak long-term loudness in phon
+    pub level: f64,
+    /// Loudness level on the LUFS scale, equal to the integrated loudness of a steady 1 kHz tone
+    pub loudness: f64,
+}
+
+impl Default for LoudnessModel {
+    fn default() -> Self {
+        Self {
+            full_scale_spl: Self::DEFAULT_FULL_SCALE_SPL,
+        }
+    }
+}
+
+impl LoudnessModel {
+    /// Playback level in dB SPL assumed for a full-scale signal
+    pub const DEFAULT_FULL_SCALE_SPL: f64 = 100.0;
+    /// Time between analysis frames
+    pub const HOP_MS: f64 = 10.0;
+
+    /// Width of a band in Bark
+    const BAND_WIDTH: f64 = 0.5;
+    /// Number of bands, covering 0 to 24 Bark
+    const BANDS: usize = 48;
+    /// Zwicker's 0.08 scale of specific loudness, recalibrated for this band layout so a
+    /// steady 1 kHz tone at 80 dB SPL is 16 sone
+    const SPECIFIC_LOUDNESS_SCALE: f64 = 0.0987;
+
+    const SHORT_TERM_ATTACK_MS: f64 = 22.0;
+    const SHORT_TERM_RELEASE_MS: f64 = 50.0;
+    const LONG_TERM_ATTACK_MS: f64 = 100.0;
+    const LONG_TERM_RELEASE_MS: f64 = 2000.0;
+
+    pub fn new(full_scale_spl: f64) -> Result<Self> {
+        if !(60.0..=130.0).contains(&full_scale_spl) {
+            return Err(anyhow::anyhow!(
+                "Full scale level must be between 60 and 130 dB SPL (got: {} dB SPL)",
+                full_scale_spl
+            ));
+        }
+
+        Ok(Self { full_scale_spl })
+    }
+
+    /// Measure the perceived loudness of interleaved samples
+    pub fn measure(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<PerceptualLoudness> {
+        let frames = self.instantaneous_loudness(samples, channels, sample_rate);
+        let hop = Self::HOP_MS;
+
+        let mut short_term = 0.0_f64;
+        let mut long_term = 0.0_f64;
+        let mut peak_short_term = 0.0_f64;
+        let mut peak_long_term = 0.0_f64;
+
+        for instantaneous in frames {
+            short_term = smooth(
+                short_term,
+                instantaneous,
+                hop,
+                Self::SHORT_TERM_ATTACK_MS,
+                Self::SHORT_TERM_RELEASE_MS,
+            );
+            long_term = smooth(
+                long_term,
+                short_term,
+                hop,
+                Self::LONG_TERM_ATTACK_MS,
+                Self::LONG_TERM_RELEASE_MS,
+            );
+            peak_short_term = peak_short_term.max(short_term);
+            peak_long_term = peak_long_term.max(long_term);
+        }
+
+        if peak_long_term <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Clip is too quiet to measure its perceived loudness"
+            ));
+        }
+
+        let level = sone_to_phon(peak_long_term);
+
+        Ok(PerceptualLoudness {
+            short_term: peak_short_term,
+            long_term: peak_long_term,
+            level,
+            loudness: level - self.full_scale_spl,
+        })
+    }
+
+    /// Loudness on the LUFS scale of a loudness in sone
+    pub fn loudness_of(&self, sone: f64) -> f64 {
+        sone_to_phon(sone) - self.full_scale_spl
+    }
+
+    /// Instantaneous loudness in sone of every analysis frame
+    fn instantaneous_loudness(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Vec<f64> {
+        let fft_size = (sample_rate as usize / 22).next_power_of_two();
+        let hop = ((sample_rate as f64 * Self::HOP_MS / 1000.0) as usize).max(1);
+        let window = hann_window(fft_size);
+        let frame_count = samples.len() / channels;
+
+        // Scale bin power so the bins of a frame sum to the mean square of the signal
+        let window_power: f64 = window.iter().map(|&w| (w * w) as f64).sum();
+        let scale = db_to_intensity(self.full_scale_spl) / (fft_size as f64 * window_power);
+
+        // Ear-weighted power of every bin, doubled for the mirrored negative frequencies
+        let bin_width = sample_rate as f64 / fft_size as f64;
+        let bins: Vec<(usize, usize, f64)> = (1..=fft_size / 2)
+            .filter_map(|k| {
+                let frequency = k as f64 * bin_width;
+                let band = (bark(frequency) / Self::BAND_WIDTH) as usize;
+                let mirrored = if k == fft_size / 2 { 1.0 } else { 2.0 };
+                let weight = mirrored * db_to_intensity(ear_weighting(frequency)) * scale;
+                (band < Self::BANDS).then_some((k, band, weight))
+            })
+            .collect();
+
+        let centers: Vec<f64> = (0..Self::BANDS)
+            .map(|b| bark_to_hz((b as f64 + 0.5) * Self::BAND_WIDTH))
+            .collect();
+        let thresholds: Vec<f64> = centers
+            .iter()
+            .map(|&f| db_to_intensity(threshold_in_quiet(f) + ear_weighting(f)))
+            .collect();
+
+        let channel_signals: Vec<Vec<f32>> = (0..channels)
+            .map(|c| samples.iter().skip(c).step_by(channels).copied().collect())
+            .collect();
+
+        // One frame per hop, centred on the hop and zero padded past the ends of the clip
+        let frames = frame_count.div_ceil(hop);
+        let mut padded = vec![0.0; fft_size];
+        let mut loudness = Vec::with_capacity(frames);
+
+        for frame in 0..frames {
+            let center = frame * hop + hop / 2;
+            let start = center as isize - (fft_size / 2) as isize;
+
+            // Channels add in power, as in EBU R128
+            let mut band_intensity = vec![0.0; Self::BANDS];
+            for signal in &channel_signals {
+                for (i, x) in padded.iter_mut().enumerate() {
+                    let index = start + i as isize;
+                    *x = if index >= 0 && (index as usize) < frame_count {
+                        signal[index as usize]
+                    } else {
+                        0.0
+                    };
+                }
+
+                let frame_spectrum = spectrum(&padded, &window, fft_size);
+                for &(k, band, weight) in &bins {
+                    band_intensity[band] += frame_spectrum[k].norm_sqr() as f64 * weight;
+                }
+            }
+
+            let excitation = spread(&band_intensity, &centers);
+            let total: f64 = excitation
+                .iter()
+                .zip(&thresholds)
+                .map(|(&e, &threshold)| {
+                    Self::SPECIFIC_LOUDNESS_SCALE * specific_loudness(e, threshold)
+                })
+                .sum::<f64>()
+                * Self::BAND_WIDTH;
+            loudness.push(total);
+        }
+
+        loudness
+    }
+}
+
+/// One-pole attack/release smoothing of a loudness value over `hop_ms`
+fn smooth(current: f64, input: f64, hop_ms: f64, attack_ms: f64, release_ms: f64) -> f64 {
+    let time = if input > current {
+        attack_ms
+    } else {
+        release_ms
+    };
+    let alpha = 1.0 - (-hop_ms / time).exp();
+    current + alpha * (input - current)
+}
+
+/// Critical-band rate in Bark (Zwicker & Terhardt)
+fn bark(frequency: f64) -> f64 {
+    13.0 * (0.00076 * frequency).atan() + 3.5 * (frequency / 7500.0).powi(2).atan()
+}
+
+/// Inverse of [`bark`] by bisection, in Hz
+fn bark_to_hz(z: f64) -> f64 {
+    let (mut low, mut high) = (0.0, 30_000.0);
+    for _ in 0..60 {
+        let mid = (low + high) / 2.0;
+        if bark(mid) < z {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Outer and middle ear transfer in dB (Terhardt), 0 dB at 1 kHz
+fn ear_weighting(frequency: f64) -> f64 {
+    let weighting = |f: f64| {
+        let khz = f.max(20.0) / 1000.0;
+        -0.6 * 3.64 * khz.powf(-0.8) + 6.5 * (-0.6 * (khz - 3.3).powi(2)).exp()
+            - 1e-3 * khz.powf(3.6)
+    };
+    weighting(frequency) - weighting(1000.0)
+}
+
+/// Absolute threshold of hearing in dB SPL (Terhardt)
+fn threshold_in_quiet(frequency: f64) -> f64 {
+    let khz = frequency.max(20.0) / 1000.0;
+    3.64 * khz.powf(-0.8) - 6.5 * (-0.6 * (khz - 3.3).powi(2)).exp() + 1e-3 * khz.powi(4)
+}
+
+fn db_to_intensity(db: f64) -> f64 {
+    10f64.powf(db / 10.0)
+}
+
+/// Spread band intensities into an excitation pattern with Zwicker's masking slopes
+fn spread(intensity: &[f64], centers: &[f64]) -> Vec<f64> {
+    let width = LoudnessModel::BAND_WIDTH;
+    let mut excitation = vec![0.0; intensity.len()];
+
+    for (j, &masker) in intensity.iter().enumerate() {
+        if masker <= 0.0 {
+            continue;
+        }
+
+        let level = 10.0 * masker.log10();
+        // The upper slope flattens as the masker gets louder
+        let upper_slope = (-24.0 - 230.0 / centers[j] + 0.2 * level).min(0.0);
+
+        for (i, e) in excitation.iter_mut().enumerate() {
+            let dz = (i as f64 - j as f64) * width;
+            let attenuation = if dz >= 0.0 {
+                upper_slope * dz
+            } else {
+                27.0 * dz
+            };
+            *e += masker * db_to_intensity(attenuation);
+        }
+    }
+
+    excitation
+}
+
+/// Zwicker's specific loudness of a band, before scaling, from its excitation and threshold
+fn specific_loudness(excitation: f64, threshold: f64) -> f64 {
+    let loudness = threshold.powf(0.23) * ((0.5 + 0.5 * excitation / threshold).powf(0.23) - 1.0);
+    loudness.max(0.0)
+}
+
+/// Loudness level in phon of a loudness in sone
+fn sone_to_phon(sone: f64) -> f64 {
+    if sone >= 1.0 {
+        40.0 + 10.0 * sone.log2()
+    } else {
+        40.0 * (sone + 0.0005).powf(0.35)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_normalizer::measure_loudness;
+    use crate::test_signals::sine;
+
+    /// Peak amplitude of a sine wave with an RMS level of `level_db` dBFS
+    fn sine_amplitude(level_db: f32) -> f32 {
+        10f32.powf(level_db / 20.0) * std::f32::consts::SQRT_2
+    }
+
+    #[test]
+    fn test_steady_tone_matches_lufs() {
+        let sample_rate = 44100;
+        let samples = sine(
+            1000.0,
+            sine_amplitude(-20.0),
+            sample_rate,
+            sample_rate as usize * 3,
+        );
+
+        let perceptual = LoudnessModel::default()
+            .measure(&samples, 1, sample_rate)
+            .unwrap();
+        let lufs = measure_loudness(1, sample_rate, &samples).unwrap();
+
+        assert!(
+            (perceptual.loudness - lufs).abs() < 1.0,
+            "Expected about {:.1} LUFS, got {:.1}",
+            lufs,
+            perceptual.loudness
+        );
+    }
+
+    #[test]
+    fn test_level_tracks_gain() {
+        let sample_rate = 48000;
+        let model = LoudnessModel::default();
+        let frames = sample_rate as usize;
+        let quiet = sine(500.0, sine_amplitude(-30.0), sample_rate, frames);
+        let loud = sine(500.0, sine_amplitude(-20.0), sample_rate, frames);
+
+        let quiet = model.measure(&quiet, 1, sample_rate).unwrap();
+        let loud = model.measure(&loud, 1, sample_rate).unwrap();
+
+        let difference = loud.loudness - quiet.loudness;
+        assert!(
+            (difference - 10.0).abs() < 1.5,
+            "Expected a 10 dB gain to add about 10 phon, got {:.1}",
+            difference
+        );
+    }
+
+    #[test]
+    fn test_short_clips_sound_quieter() {
+        let sample_rate = 44100;
+        let model = LoudnessModel::default();
+        let stab_samples = sine(1000.0, sine_amplitude(-20.0), sample_rate, 2205);
+        let long_samples = sine(1000.0, sine_amplitude(-20.0), sample_rate, 44100);
+
+        let stab = model.measure(&stab_samples, 1, sample_rate).unwrap();
+        let long = model.measure(&long_samples, 1, sample_rate).unwrap();
+
+        // Gated LUFS cannot measure a 50 ms stab at all
+        assert!(measure_loudness(1, sample_rate, &stab_samples).is_err());
+        assert!(
+            stab.loudness < long.loudness - 3.0,
+            "Expected the stab ({:.1}) to be quieter than the tone ({:.1})",
+            stab.loudness,
+            long.loudness
+        );
+    }
+
+    #[test]
+    fn test_invalid_full_scale() {
+        assert!(LoudnessModel::new(40.0).is_err());
+        assert!(LoudnessModel::new(150.0).is_err());
+        assert!(LoudnessModel::new(94.0).is_ok());
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+
+use crate::audio_normalizer::{measure_loudness, Normalizer};
+use crate::dsp::{
+    average_power_spectrum, convolve_linear_phase, decode_file, design_fir, AudioProcessor,
+};
+
+/// Loudness and long-term spectral balance of a reference clip
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceProfile {
+    /// Integrated loudness in LUFS
+    pub loudness: f64,
+    /// Level of each third-octave band in dB relative to the mean band level,
+    /// `None` for bands above the Nyquist frequency
+    pub bands: Vec<Option<f64>>,
+}
+
+impl ReferenceProfile {
+    /// Size of the FFT used for the long-term spectrum
+    pub const ANALYSIS_FFT_SIZE: usize = 4096;
+
+    /// Measure the loudness and spectral balance of interleaved samples
+    pub fn analyze(samples: &[f32], channels: usize, sample_rate: u32) -> Result<Self> {
+        let loudness = measure_loudness(channels, sample_rate, samples)?;
+        let bands = band_levels(samples, channels, sample_rate);
+
+        Ok(Self { loudness, bands })
+    }
+
+    /// Decode and measure a reference clip from disk
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let (samples, track) = decode_file(path)
+            .with_context(|| format!("Failed to decode reference {}", path.display()))?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        Self::analyze(&samples, channels, sample_rate)
+    }
+}
+
+/// Matches clips to the loudness and, optionally, the tonal balance of a reference
+pub struct Matcher {
+    reference: ReferenceProfile,
+    match_tone: bool,
+    target_peak: f64,
+}
+
+impl Matcher {
+    /// Largest boost or cut the matching EQ may apply, in dB
+    pub const MAX_EQ_GAIN: f64 = 12.0;
+    /// Length of the matching EQ filter
+    pub const FIR_TAPS: usize = 2048;
+
+    pub fn new(reference: ReferenceProfile, match_tone: bool, target_peak: f64) -> Result<Self> {
+        // Validate the peak ceiling with the same limits as the normalizer
+        Normalizer::new(Normalizer::DEFAULT_TARGET_LOUDNESS, target_peak)?;
+
+        Ok(Self {
+            reference,
+            match_tone,
+            target_peak,
+        })
+    }
+
+    /// Target loudness, held under the hearing-safety cap even if the reference is louder
+    pub fn target_loudness(&self) -> f64 {
+        self.reference.loudness.min(Normalizer::MAX_TARGET_LOUDNESS)
+    }
+
+    /// Smoothed EQ curve in dB per band that moves a clip's balance towards the reference
+    pub fn eq_curve(&self, clip_bands: &[Option<f64>]) -> Vec<Option<f64>> {
+        let raw: Vec<Option<f64>> = self
+            .reference
+            .bands
+            .iter()
+            .zip(clip_bands)
+            .map(|(reference, clip)| Some(reference.as_ref()? - clip.as_ref()?))
+            .collect();
+
+        // Average each band with its neighbours so the curve follows the broad balance only
+        let smoothed: Vec<Option<f64>> = (0..raw.len())
+            .map(|i| {
+                raw[i]?;
+                let neighbours: Vec<f64> = raw[i.saturating_sub(1)..(i + 2).min(raw.len())]
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .collect();
+                Some(neighbours.iter().sum::<f64>() / neighbours.len() as f64)
+            })
+            .collect();
+
+        // Loudness is matched afterwards, so only the shape of the curve matters
+        let valid: Vec<f64> = smoothed.iter().flatten().copied().collect();
+        let mean = valid.iter().sum::<f64>() / valid.len().max(1) as f64;
+
+        smoothed
+            .iter()
+            .map(|gain| gain.map(|g| (g - mean).clamp(-Self::MAX_EQ_GAIN, Self::MAX_EQ_GAIN)))
+            .collect()
+    }
+
+    /// Apply the matching EQ to interleaved samples
+    fn match_tone(&self, samples: &[f32], channels: usize, sample_rate: u32) -> Vec<f32> {
+        let clip_bands = band_levels(samples, channels, sample_rate);
+        let curve = self.eq_curve(&clip_bands);
+        debug!("Matching EQ curve: {:?}", curve);
+
+        let centers = band_centers();
+        let points: Vec<(f64, f64)> = centers
+            .iter()
+            .zip(&curve)
+            .filter_map(|(&f, gain)| gain.map(|g| (f.log2(), g)))
+            .collect();
+
+        if points.is_empty() {
+            return samples.to_vec();
+        }
+
+        // Interpolate the band gains on a log frequency scale for every FFT bin
+        let bins = Self::FIR_TAPS / 2 + 1;
+        let magnitudes: Vec<f64> = (0..bins)
+            .map(|k| {
+                let frequency = (k as f64 * sample_rate as f64 / Self::FIR_TAPS as f64).max(1.0);
+                let x = frequency.log2();
+                let gain = match points.iter().position(|&(p, _)| p >= x) {
+                    Some(0) => points[0].1,
+                    Some(i) => {
+                        let (x0, g0) = points[i - 1];
+                        let (x1, g1) = points[i];
+                        g0 + (g1 - g0) * (x - x0) / (x1 - x0)
+                    }
+                    None => points[points.len() - 1].1,
+                };
+                10f64.powf(gain / 20.0)
+            })
+            .collect();
+
+        let fir = design_fir(&magnitudes);
+        convolve_linear_phase(samples, channels, &fir)
+    }
+}
+
+impl AudioProcessor for Matcher {
+    fn process(&self, samples: &[f32], channels: usize, sample_rate: u32) -> Result<Vec<f32>> {
+        let toned = if self.match_tone {
+            self.match_tone(samples, channels, sample_rate)
+        } else {
+            samples.to_vec()
+        };
+
+        debug!(
+            "Matching reference loudness of {:.1} LUFS (target {:.1} LUFS)",
+            self.reference.loudness,
+            self.target_loudness()
+        );
+
+        Normalizer::new(self.target_loudness(), self.target_peak)?.process(
+            channels,
+            sample_rate,
+            &toned,
+        )
+    }
+}
+
+/// Centre frequencies of the third-octave bands used for tonal matching, 50 Hz to 16 kHz
+fn band_centers() -> Vec<f64> {
+    (-13..=14)
+        .map(|n| 1000.0 * 2f64.powf(n as f64 / 3.0))
+        .collect()
+}
+
+/// Level of each third-octave band in dB relative to the mean band level
+fn band_levels(samples: &[f32], channels: usize, sample_rate: u32) -> Vec<Option<f64>> {
+    let fft_size = ReferenceProfile::ANALYSIS_FFT_SIZE;
+    let power = average_power_spectrum(samples, channels, fft_size);
+    let bin_width = sample_rate as f64 / fft_size as f64;
+
+    let levels: Vec<Option<f64>> = band_centers()
+        .iter()
+        .map(|&center| {
+            let low = center * 2f64.powf(-1.0 / 6.0);
+            let high = center * 2f64.powf(1.0 / 6.0);
+            if high > sample_rate as f64 / 2.0 {
+                return None;
+            }
+
+            let first = (low / bin_width).ceil() as usize;
+            let last = ((high / bin_width).ceil() as usize).min(power.len());
+            let energy: f64 = power[first.min(last)..last].iter().sum();
+
+            Some(10.0 * energy.max(1e-12).log10())
+        })
+        .collect();
+
+    let valid: Vec<f64> = levels.iter().flatten().copied().collect();
+    let mean = valid.iter().sum::<f64>() / valid.len().max(1) as f64;
+
+    levels.iter().map(|level| level.map(|l| l - mean)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::Biquad;
+    use crate::test_signals::noise;
+
+    #[test]
+    fn test_matches_reference_loudness() {
+        let sample_rate = 44100;
+        let reference: Vec<f32> = noise(sample_rate as usize * 2, 0.1);
+        let profile = ReferenceProfile::analyze(&reference, 1, sample_rate).unwrap();
+
+        let clip: Vec<f32> = reference.iter().map(|s| s * 0.1).collect();
+        let matcher = Matcher::new(profile.clone(), false, -1.0).unwrap();
+        let matched = matcher.process(&clip, 1, sample_rate).unwrap();
+
+        let loudness = measure_loudness(1, sample_rate, &matched).unwrap();
+        assert!(
+            (loudness - matcher.target_loudness()).abs() < 0.2,
+            "Expected {:.1} LUFS, got {:.1} LUFS",
+            matcher.target_loudness(),
+            loudness
+        );
+    }
+
+    #[test]
+    fn test_tone_matching_restores_highs() {
+        let sample_rate = 44100;
+        let reference = noise(sample_rate as usize * 2, 0.1);
+
+        // Dull copy of the reference with the highs rolled off
+        let mut lowpass = Biquad::lowpass(sample_rate, 1500.0, 0.707);
+        let dull: Vec<f32> = reference
+            .iter()
+            .map(|&s| lowpass.process(s as f64) as f32)
+            .collect();
+
+        let profile = ReferenceProfile::analyze(&reference, 1, sample_rate).unwrap();
+        let matcher = Matcher::new(profile.clone(), true, -1.0).unwrap();
+
+        let before = band_levels(&dull, 1, sample_rate);
+        let after = band_levels(&matcher.match_tone(&dull, 1, sample_rate), 1, sample_rate);
+
+        // The 8 kHz band should move closer to the reference
+        let band = band_centers()
+            .iter()
+            .position(|&f| (f - 8000.0).abs() < 200.0)
+            .unwrap();
+        let reference_level = profile.bands[band].unwrap();
+        let before_error = (reference_level - before[band].unwrap()).abs();
+        let after_error = (reference_level - after[band].unwrap()).abs();
+        assert!(
+            after_error < before_error - 6.0,
+            "Expected the 8 kHz band to move towards the reference ({:.1} dB -> {:.1} dB)",
+            before_error,
+            after_error
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use anyhow::Result;
+use symphonia::core::meta::{MetadataRevision, StandardTagKey, StandardVisualKey};
+
+/// Tags carried from the files clips are decoded from to the files they are written to
+///
+/// Only tags every format has a name for are kept. Loudness tags such as ReplayGain are
+/// left behind on purpose, as processing the clip makes them wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagKey {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Composer,
+    Genre,
+    Date,
+    TrackNumber,
+    DiscNumber,
+    Comment,
+    Copyright,
+}
+
+impl TagKey {
+    fn from_standard(key: StandardTagKey) -> Option<Self> {
+        Some(match key {
+            StandardTagKey::TrackTitle => TagKey::Title,
+            StandardTagKey::Artist => TagKey::Artist,
+            StandardTagKey::Album => TagKey::Album,
+            StandardTagKey::AlbumArtist => TagKey::AlbumArtist,
+            StandardTagKey::Composer => TagKey::Composer,
+            StandardTagKey::Genre => TagKey::Genre,
+            StandardTagKey::Date => TagKey::Date,
+            StandardTagKey::TrackNumber => TagKey::TrackNumber,
+            StandardTagKey::DiscNumber => TagKey::DiscNumber,
+            StandardTagKey::Comment => TagKey::Comment,
+            StandardTagKey::Copyright => TagKey::Copyright,
+            _ => return None,
+        })
+    }
+
+    /// Field name in Vorbis comments, as used by FLAC and Ogg files
+    pub fn vorbis_name(self) -> &'static str {
+        match self {
+            TagKey::Title => "TITLE",
+            TagKey::Artist => "ARTIST",
+            TagKey::Album => "ALBUM",
+            TagKey::AlbumArtist => "ALBUMARTIST",
+            TagKey::Composer => "COMPOSER",
+            TagKey::Genre => "GENRE",
+            TagKey::Date => "DATE",
+            TagKey::TrackNumber => "TRACKNUMBER",
+            TagKey::DiscNumber => "DISCNUMBER",
+            TagKey::Comment => "COMMENT",
+            TagKey::Copyright => "COPYRIGHT",
+        }
+    }
+
+    /// ID3v2.4 frame holding the tag
+    fn id3_frame(self) -> &'static [u8; 4] {
+        match self {
+            TagKey::Title => b"TIT2",
+            TagKey::Artist => b"TPE1",
+            TagKey::Album => b"TALB",
+            TagKey::AlbumArtist => b"TPE2",
+            TagKey::Composer => b"TCOM",
+            TagKey::Genre => b"TCON",
+            TagKey::Date => b"TDRC",
+            TagKey::TrackNumber => b"TRCK",
+            TagKey::DiscNumber => b"TPOS",
+            TagKey::Comment => b"COMM",
+            TagKey::Copyright => b"TCOP",
+        }
+    }
+
+    /// RIFF INFO chunk holding the tag, for the tags WAV files have a place for
+    fn riff_chunk(self) -> Option<&'static [u8; 4]> {
+        match self {
+            TagKey::Title => Some(b"INAM"),
+            TagKey::Artist => Some(b"IART"),
+            TagKey::Album => Some(b"IPRD"),
+            TagKey::Composer => Some(b"IMUS"),
+            TagKey::Genre => Some(b"IGNR"),
+            TagKey::Date => Some(b"ICRD"),
+            TagKey::TrackNumber => Some(b"IPRT"),
+            TagKey::Comment => Some(b"ICMT"),
+            TagKey::Copyright => Some(b"ICOP"),
+            TagKey::AlbumArtist | TagKey::DiscNumber => None,
+        }
+    }
+}
+
+/// Embedded picture, such as cover art
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    /// Picture type shared by ID3v2 and FLAC, 3 for the front cover
+    pub kind: u8,
+    /// MIME type of the image, such as `image/jpeg`
+    pub media_type: String,
+    pub description: String,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    /// Picture type of the front cover
+    pub const FRONT_COVER: u8 = 3;
+
+    /// Front cover image in `media_type`
+    pub fn front_cover(media_type: &str, data: Vec<u8>) -> Self {
+        Self {
+            kind: Self::FRONT_COVER,
+            media_type: media_type.to_string(),
+            description: String::new(),
+            data,
+        }
+    }
+
+    /// Picture type of a symphonia visual usage
+    fn kind_of(usage: Option<StandardVisualKey>) -> u8 {
+        match usage {
+            Some(StandardVisualKey::FileIcon) => 1,
+            Some(StandardVisualKey::OtherIcon) => 2,
+            Some(StandardVisualKey::FrontCover) => 3,
+            Some(StandardVisualKey::BackCover) => 4,
+            Some(StandardVisualKey::Leaflet) => 5,
+            Some(StandardVisualKey::Media) => 6,
+            Some(StandardVisualKey::LeadArtistPerformerSoloist) => 7,
+            Some(StandardVisualKey::ArtistPerformer) => 8,
+            Some(StandardVisualKey::Conductor) => 9,
+            Some(StandardVisualKey::BandOrchestra) => 10,
+            Some(StandardVisualKey::Composer) => 11,
+            Some(StandardVisualKey::Lyricist) => 12,
+            Some(StandardVisualKey::RecordingLocation) => 13,
+            Some(StandardVisualKey::RecordingSession) => 14,
+            Some(StandardVisualKey::Performance) => 15,
+            Some(StandardVisualKey::ScreenCapture) => 16,
+            Some(StandardVisualKey::Illustration) => 18,
+            Some(StandardVisualKey::BandArtistLogo) => 19,
+            Some(StandardVisualKey::PublisherStudioLogo) => 20,
+            None => 0,
+        }
+    }
+
+    /// Body of a FLAC PICTURE block, which Ogg files also carry base64 encoded in a
+    /// `METADATA_BLOCK_PICTURE` comment. The dimensions are left 0, meaning unknown.
+    pub(crate) fn flac_block(&self) -> Vec<u8> {
+        let mut block = Vec::with_capacity(32 + self.data.len());
+        block.extend((self.kind as u32).to_be_bytes());
+        for text in [&self.media_type, &self.description] {
+            block.extend((text.len() as u32).to_be_bytes());
+            block.extend(text.bytes());
+        }
+        block.extend([0; 16]);
+        block.extend((self.data.len() as u32).to_be_bytes());
+        block.extend(&self.data);
+        block
+    }
+}
+
+/// Tags and pictures of a clip, read along with its samples and written back with them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    tags: Vec<(TagKey, String)>,
+    pictures: Vec<Picture>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a value for `key`, after any it has already
+    pub fn with_tag(mut self, key: TagKey, value: &str) -> Result<Self> {
+        if value.contains('\0') {
+            return Err(anyhow::anyhow!(
+                "Tag values must not contain NUL characters (got: {:?})",
+                value
+            ));
+        }
+
+        self.add_tag(key, value);
+        Ok(self)
+    }
+
+    pub fn with_picture(mut self, picture: Picture) -> Self {
+        self.pictures.push(picture);
+        self
+    }
+
+    /// First value of `key`
+    pub fn get(&self, key: TagKey) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn tags(&self) -> &[(TagKey, String)] {
+        &self.tags
+    }
+
+    pub fn pictures(&self) -> &[Picture] {
+        &self.pictures
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.pictures.is_empty()
+    }
+
+    /// Keep a value unless the key has it already, as files often repeat their tags
+    /// across several tag formats
+    fn add_tag(&mut self, key: TagKey, value: &str) {
+        let value = value.trim_end_matches('\0');
+        if !value.is_empty() && !self.tags.iter().any(|(k, v)| *k == key && v == value) {
+            self.tags.push((key, value.to_string()));
+        }
+    }
+
+    /// Take the tags and pictures of a symphonia metadata revision
+    pub(crate) fn add_revision(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            if let Some(key) = tag.std_key.and_then(TagKey::from_standard) {
+                self.add_tag(key, &tag.value.to_string());
+            }
+        }
+
+        for visual in revision.visuals() {
+            let picture = Picture {
+                kind: Picture::kind_of(visual.usage),
+                media_type: visual.media_type.clone(),
+                description: visual
+                    .tags
+                    .iter()
+                    .find(|tag| tag.std_key == Some(StandardTagKey::Description))
+                    .map(|tag| tag.value.to_string())
+                    .unwrap_or_default(),
+                data: visual.data.to_vec(),
+            };
+            if !self.pictures.contains(&picture) {
+                self.pictures.push(picture);
+            }
+        }
+    }
+
+    /// Vorbis comments as `NAME=value`
+    pub(crate) fn vorbis_comments(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .map(|(key, value)| format!("{}={}", key.vorbis_name(), value))
+            .collect()
+    }
+
+    /// ID3v2.4 tag to put in front of MP3 frames, empty when there is nothing to write
+    pub(crate) fn id3v2(&self) -> Vec<u8> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        const UTF8: u8 = 3;
+        let mut frames = Vec::new();
+        let mut frame = |id: &[u8; 4], body: Vec<u8>| {
+            frames.extend(id);
+            frames.extend(syncsafe(body.len() as u32));
+            frames.extend([0, 0]);
+            frames.extend(body);
+        };
+
+        // Several values of one text frame are stored NUL separated in a single frame
+        let mut written = Vec::new();
+        for &(key, _) in &self.tags {
+            if written.contains(&key) {
+                continue;
+            }
+            written.push(key);
+
+            let values: Vec<&str> = self
+                .tags
+                .iter()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, value)| value.as_str())
+                .collect();
+            let mut body = vec![UTF8];
+            if key == TagKey::Comment {
+                // Language and an empty description come before the text, which is a
+                // single value
+                body.extend(b"eng\0");
+                body.extend(values.join("\n").bytes());
+            } else {
+                body.extend(values.join("\0").bytes());
+            }
+            frame(key.id3_frame(), body);
+        }
+
+        for picture in &self.pictures {
+            let mut body = vec![UTF8];
+            body.extend(picture.media_type.bytes());
+            body.push(0);
+            body.push(picture.kind);
+            body.extend(picture.description.bytes());
+            body.push(0);
+            body.extend(&picture.data);
+            frame(b"APIC", body);
+        }
+
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend(syncsafe(frames.len() as u32));
+        tag.extend(frames);
+        tag
+    }
+
+    /// Body of a `LIST` chunk of type `INFO` for WAV files, `None` when none of the
+    /// tags has a place in it. WAV files have no place for pictures.
+    pub(crate) fn riff_info(&self) -> Option<Vec<u8>> {
+        let mut info = b"INFO".to_vec();
+        let mut written = Vec::new();
+        for (key, value) in &self.tags {
+            let Some(id) = key.riff_chunk() else {
+                continue;
+            };
+            // Each chunk holds a single value
+            if written.contains(key) {
+                continue;
+            }
+            written.push(*key);
+
+            let size = value.len() + 1;
+            info.extend(id);
+            info.extend((size as u32).to_le_bytes());
+            info.extend(value.bytes());
+            info.push(0);
+            if size % 2 == 1 {
+                info.push(0);
+            }
+        }
+
+        (!written.is_empty()).then_some(info)
+    }
+}
+
+/// 28-bit size in the 7 bits per byte form ID3v2 uses
+fn syncsafe(size: u32) -> [u8; 4] {
+    [
+        (size >> 21) as u8 & 0x7f,
+        (size >> 14) as u8 & 0x7f,
+        (size >> 7) as u8 & 0x7f,
+        size as u8 & 0x7f,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id3v2_layout() {
+        let metadata = Metadata::new()
+            .with_tag(TagKey::Title, "Airhorn")
+            .unwrap()
+            .with_tag(TagKey::Artist, "A")
+            .unwrap()
+            .with_tag(TagKey::Artist, "B")
+            .unwrap();
+        let tag = metadata.id3v2();
+
+        assert_eq!(&tag[..6], b"ID3\x04\x00\x00");
+        assert_eq!(tag[6..10], syncsafe(tag.len() as u32 - 10));
+        assert_eq!(&tag[10..14], b"TIT2");
+        assert_eq!(&tag[20..28], b"\x03Airhorn");
+        // Both artists in one frame
+        assert_eq!(&tag[28..32], b"TPE1");
+        assert_eq!(&tag[38..], b"\x03A\0B");
+
+        assert!(Metadata::new().id3v2().is_empty());
+        assert_eq!(syncsafe(300), [0, 0, 2, 44]);
+    }
+
+    #[test]
+    fn test_riff_info_skips_tags_without_a_chunk() {
+        let metadata = Metadata::new()
+            .with_tag(TagKey::AlbumArtist, "Nobody")
+            .unwrap();
+        assert_eq!(metadata.riff_info(), None);
+
+        let info = metadata
+            .with_tag(TagKey::Title, "Bonk")
+            .unwrap()
+            .riff_info();
+        assert_eq!(info.unwrap(), b"INFOINAM\x05\0\0\0Bonk\0\0");
+    }
+
+    #[test]
+    fn test_invalid_tags() {
+        assert!(Metadata::new().with_tag(TagKey::Title, "a\0b").is_err());
+        // Empty and repeated values are dropped
+        let metadata = Metadata::new()
+            .with_tag(TagKey::Genre, "")
+            .unwrap()
+            .with_tag(TagKey::Genre, "Meme")
+            .unwrap()
+            .with_tag(TagKey::Genre, "Meme")
+            .unwrap();
+        assert_eq!(metadata.tags(), [(TagKey::Genre, "Meme".to_string())]);
+    }
+}
@@ -1,13 +1,265 @@
+use crate::audio_bass_tamer::BassTamer;
+use crate::audio_limiter::Limiter;
+use crate::audio_loudness::LoudnessModel;
 use crate::dsp::*;
 use anyhow::Error;
 use anyhow::{Context, Result};
 use ebur128::{EbuR128, Mode};
-use log::debug;
+use log::{debug, warn};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Normalizer {
     target_loudness: f64,
     target_peak: f64,
+    mode: NormalizationMode,
+    ceiling_mode: CeilingMode,
+    tolerance: f64,
+    bass_penalty: f64,
+}
+
+/// What level a clip is measured and normalized by
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NormalizationMode {
+    /// EBU R128 integrated loudness, normalized to the target loudness in LUFS
+    #[default]
+    Loudness,
+    /// Sample or true peak, normalized to `level` in dBFS (or dBTP)
+    Peak { level: f64, true_peak: bool },
+    /// RMS level, optionally A-weighted, normalized to `level` in dBFS
+    Rms { level: f64, a_weighted: bool },
+    /// ReplayGain 2.0 reference level, shifted by `pre_amp` in dB
+    ReplayGain { pre_amp: f64 },
+    /// Perceived loudness from the [`LoudnessModel`], normalized to the target loudness.
+    /// Judges sub-second clips that EBU R128 gating measures badly or not at all.
+    Perceptual,
+}
+
+impl NormalizationMode {
+    /// ReplayGain 2.0 reference level in LUFS
+    pub const REPLAYGAIN_REFERENCE: f64 = -18.0;
+
+    /// Check the mode's own target against the same hearing-safety caps as the normalizer
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            NormalizationMode::Loudness | NormalizationMode::Perceptual => Ok(()),
+            NormalizationMode::Peak { level, .. } => {
+                if !level.is_finite() {
+                    return Err(anyhow::anyhow!(
+                        "Peak level must be a finite number (got: {})",
+                        level
+                    ));
+                }
+
+                if level >= 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "Peak level must be negative (got: {} dBFS)",
+                        level
+                    ));
+                }
+
+                if level > Normalizer::MAX_TARGET_PEAK {
+                    return Err(anyhow::anyhow!(
+                        "Peak level `{}` dBFS exceeds maximum allowed value of `{}`",
+                        level,
+                        Normalizer::MAX_TARGET_PEAK
+                    ));
+                }
+
+                Ok(())
+            }
+            NormalizationMode::Rms { level, .. } => {
+                if !level.is_finite() {
+                    return Err(anyhow::anyhow!(
+                        "RMS level must be a finite number (got: {})",
+                        level
+                    ));
+                }
+
+                if level >= 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "RMS level must be negative (got: {} dBFS)",
+                        level
+                    ));
+                }
+
+                if level > Normalizer::MAX_TARGET_RMS {
+                    return Err(anyhow::anyhow!(
+                        "RMS level `{}` dBFS exceeds maximum allowed value of `{}`",
+                        level,
+                        Normalizer::MAX_TARGET_RMS
+                    ));
+                }
+
+                Ok(())
+            }
+            NormalizationMode::ReplayGain { pre_amp } => {
+                if !pre_amp.is_finite() {
+                    return Err(anyhow::anyhow!(
+                        "ReplayGain pre-amp must be a finite number (got: {})",
+                        pre_amp
+                    ));
+                }
+
+                let level = Self::REPLAYGAIN_REFERENCE + pre_amp;
+                if level > Normalizer::MAX_TARGET_LOUDNESS {
+                    return Err(anyhow::anyhow!(
+                        "ReplayGain pre-amp `{}` dB exceeds maximum allowed loudness of `{}`",
+                        pre_amp,
+                        Normalizer::MAX_TARGET_LOUDNESS
+                    ));
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Level the mode normalizes to, given the normalizer's target loudness
+    fn target(&self, target_loudness: f64) -> f64 {
+        match *self {
+            NormalizationMode::Loudness | NormalizationMode::Perceptual => target_loudness,
+            NormalizationMode::Peak { level, .. } => level,
+            NormalizationMode::Rms { level, .. } => level,
+            NormalizationMode::ReplayGain { pre_amp } => Self::REPLAYGAIN_REFERENCE + pre_amp,
+        }
+    }
+
+    /// Whether the mode is measured as the loudness held under the hearing-safety cap
+    fn is_loudness(&self) -> bool {
+        matches!(
+            self,
+            NormalizationMode::Loudness
+                | NormalizationMode::ReplayGain { .. }
+                | NormalizationMode::Perceptual
+        )
+    }
+
+    /// Loudness held under the hearing-safety cap, in LUFS
+    ///
+    /// This is the EBU R128 integrated loudness, except in perceptual mode, where short
+    /// clips may not have a gated loudness at all.
+    fn loudness(&self, channels: usize, sample_rate: u32, samples: &[f32]) -> Result<f64> {
+        match self {
+            NormalizationMode::Perceptual => Ok(LoudnessModel::default()
+                .measure(samples, channels, sample_rate)?
+                .loudness),
+            _ => measure_loudness(channels, sample_rate, samples),
+        }
+    }
+
+    /// Replace an integrated loudness already measured with the loudness of this mode
+    fn loudness_from(
+        &self,
+        channels: usize,
+        sample_rate: u32,
+        samples: &[f32],
+        integrated: f64,
+    ) -> Result<f64> {
+        match self {
+            NormalizationMode::Perceptual => self.loudness(channels, sample_rate, samples),
+            _ => Ok(integrated),
+        }
+    }
+
+    /// Measure the samples in the units of this mode
+    pub fn measure(&self, channels: usize, sample_rate: u32, samples: &[f32]) -> Result<f64> {
+        match *self {
+            NormalizationMode::Loudness
+            | NormalizationMode::ReplayGain { .. }
+            | NormalizationMode::Perceptual => self.loudness(channels, sample_rate, samples),
+            NormalizationMode::Peak { true_peak, .. } => {
+                if true_peak {
+                    let (_, peak) = measure_loudness_and_true_peak(channels, sample_rate, samples)?;
+                    Ok(peak)
+                } else {
+                    Ok(linear_to_db(max_peak(samples)))
+                }
+            }
+            NormalizationMode::Rms { a_weighted, .. } => {
+                if a_weighted {
+                    Ok(a_weighted_rms_db(samples, channels, sample_rate))
+                } else {
+                    Ok(rms_db(samples))
+                }
+            }
+        }
+    }
+}
+
+/// How the peak ceiling is enforced once the loudness gain is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CeilingMode {
+    /// Reduce the gain so the loudest sample stays under the ceiling.
+    /// Clips with a high crest factor can end up below the target loudness.
+    #[default]
+    ReduceGain,
+    /// Apply the full loudness gain and hold the ceiling with a true-peak limiter
+    Limit,
+}
+
+/// Loudness and peak measured after normalization
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalizationReport {
+    /// Integrated loudness of the input in LUFS, or its perceived loudness in perceptual mode
+    pub input_loudness: f64,
+    /// Integrated loudness of the output in LUFS, or its perceived loudness in perceptual mode
+    pub output_loudness: f64,
+    /// Output level in the units of the normalization mode
+    pub output_level: f64,
+    /// True peak of the output in dBTP
+    pub output_peak: f64,
+    /// Gain applied before any limiting, in dB, after any reduction for the peak ceiling
+    pub gain: f64,
+    /// Level the clip was normalized towards, in the units of the normalization mode
+    pub target: f64,
+    /// Whether the output came within the tolerance of the target
+    pub reached_target: bool,
+}
+
+impl NormalizationReport {
+    /// How far the output level fell short of the target, in dB
+    pub fn shortfall(&self) -> f64 {
+        (self.target - self.output_level).max(0.0)
+    }
+}
+
+/// Outcome of one clip of a batch, so the user can be told what became of every clip
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipReport {
+    pub name: String,
+    pub outcome: ClipOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClipOutcome {
+    Normalized(NormalizationReport),
+    /// Run through a processor that does not report on the result
+    Processed,
+    /// Left as it was, for the given reason
+    Skipped(String),
+}
+
+impl ClipReport {
+    pub fn normalized(name: &str, report: NormalizationReport) -> Self {
+        Self {
+            name: name.to_string(),
+            outcome: ClipOutcome::Normalized(report),
+        }
+    }
+
+    pub fn processed(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            outcome: ClipOutcome::Processed,
+        }
+    }
+
+    pub fn skipped(name: &str, reason: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            outcome: ClipOutcome::Skipped(reason.to_string()),
+        }
+    }
 }
 
 pub struct FakeProcessor;
@@ -28,6 +280,10 @@ impl Default for Normalizer {
         Self {
             target_loudness: Self::DEFAULT_TARGET_LOUDNESS,
             target_peak: Self::DEFAULT_TARGET_PEAK,
+            mode: NormalizationMode::default(),
+            ceiling_mode: CeilingMode::default(),
+            tolerance: Self::DEFAULT_TOLERANCE,
+            bass_penalty: 0.0,
         }
     }
 }
@@ -35,60 +291,474 @@ impl Default for Normalizer {
 impl Normalizer {
     /// Maximum allowed target loudness is hardcoded to prevent hearing damage
     pub const MAX_TARGET_LOUDNESS: f64 = -15.0;
+    /// Maximum allowed RMS level, a few dB above the loudness cap because plain RMS counts
+    /// the low bass that K-weighting discounts. The loudness cap still holds on top of it.
+    pub const MAX_TARGET_RMS: f64 = -12.0;
     /// Maximum allowed peak ceiling is hardcoded to prevent clipping
     pub const MAX_TARGET_PEAK: f64 = -0.1;
 
     pub const DEFAULT_TARGET_LOUDNESS: f64 = -18.0;
     pub const DEFAULT_TARGET_PEAK: f64 = -1.0;
+    /// Default distance from the target loudness that is considered on target, in LU
+    pub const DEFAULT_TOLERANCE: f64 = 0.5;
+    /// Maximum number of gain and limiter passes when limiting towards the target
+    pub const MAX_LIMIT_ITERATIONS: usize = 8;
+    /// Largest bass penalty in dB of loudness per dB of excess sub-bass
+    pub const MAX_BASS_PENALTY: f64 = 2.0;
+    /// Most a clip's target is lowered for its sub-bass, in dB
+    pub const MAX_BASS_PENALTY_DB: f64 = 6.0;
 
     pub fn new(target_loudness: f64, target_peak: f64) -> Result<Self> {
-        // Ensure values are negative
-        if target_loudness >= 0.0 {
-            return Err(anyhow::anyhow!(
-                "Target loudness must be negative (got: {} LUFS)",
-                target_loudness
-            ));
+        validate_targets(target_loudness, target_peak)?;
+
+        Ok(Self {
+            target_loudness,
+            target_peak,
+            mode: NormalizationMode::default(),
+            ceiling_mode: CeilingMode::default(),
+            tolerance: Self::DEFAULT_TOLERANCE,
+            bass_penalty: 0.0,
+        })
+    }
+
+    /// Aim at a different target loudness, keeping the other settings
+    pub fn with_target_loudness(mut self, target_loudness: f64) -> Result<Self> {
+        validate_targets(target_loudness, self.target_peak)?;
+        self.target_loudness = target_loudness;
+        Ok(self)
+    }
+
+    pub fn with_mode(mut self, mode: NormalizationMode) -> Result<Self> {
+        mode.validate()?;
+
+        // Gain is capped at the peak ceiling, so a peak level above it could never be reached
+        if let NormalizationMode::Peak { level, .. } = mode {
+            if level > self.target_peak {
+                return Err(anyhow::anyhow!(
+                    "Peak level `{}` dBFS is above the `{}` dBFS peak ceiling",
+                    level,
+                    self.target_peak
+                ));
+            }
         }
+        self.mode = mode;
+        Ok(self)
+    }
+
+    pub fn with_ceiling_mode(mut self, ceiling_mode: CeilingMode) -> Self {
+        self.ceiling_mode = ceiling_mode;
+        self
+    }
 
-        if target_peak >= 0.0 {
+    pub fn with_tolerance(mut self, tolerance: f64) -> Result<Self> {
+        if tolerance <= 0.0 {
             return Err(anyhow::anyhow!(
-                "Peak ceiling must be negative (got: {} dBFS)",
-                target_peak
+                "Tolerance must be positive (got: {} LU)",
+                tolerance
             ));
         }
 
-        // Check maximum allowed values
-        if target_loudness > Self::MAX_TARGET_LOUDNESS {
+        self.tolerance = tolerance;
+        Ok(self)
+    }
+
+    /// Lower the target of clips with excessive sub-100 Hz energy, by `penalty` dB per dB
+    /// over the [`BassTamer`] threshold, up to [`Self::MAX_BASS_PENALTY_DB`]
+    ///
+    /// K-weighting rolls off the lows, so bass-boosted clips reach the target loudness while
+    /// being physically punishing. Applies to single clips, not groups.
+    pub fn with_bass_penalty(mut self, penalty: f64) -> Result<Self> {
+        if !(0.0..=Self::MAX_BASS_PENALTY).contains(&penalty) {
             return Err(anyhow::anyhow!(
-                "Target loudness `{}` LUFS exceeds maximum allowed value of `{}`",
-                target_loudness,
-                Self::MAX_TARGET_LOUDNESS
+                "Bass penalty must be between 0 and {} dB per dB (got: {})",
+                Self::MAX_BASS_PENALTY,
+                penalty
             ));
         }
 
-        if target_peak > Self::MAX_TARGET_PEAK {
-            return Err(anyhow::anyhow!(
-                "Peak ceiling `{}` dBFS exceeds maximum allowed value of `{}`",
-                target_peak,
-                Self::MAX_TARGET_PEAK
-            ));
+        self.bass_penalty = penalty;
+        Ok(self)
+    }
+
+    /// How far the target is lowered for the clip's sub-bass, in dB
+    fn bass_penalty_db(&self, channels: usize, sample_rate: u32, samples: &[f32]) -> f64 {
+        if self.bass_penalty == 0.0 {
+            return 0.0;
         }
 
-        Ok(Self {
-            target_loudness,
-            target_peak,
-        })
+        let excess = BassTamer::default().excess(samples, channels, sample_rate);
+        let penalty = (excess * self.bass_penalty).min(Self::MAX_BASS_PENALTY_DB);
+        if penalty > 0.0 {
+            debug!(
+                "Sub-bass is {:.1} dB over the threshold, lowering the target by {:.1} dB",
+                excess, penalty
+            );
+        }
+        penalty
+    }
+
+    /// Peak normalization already sets the peak, so only the other modes are limited
+    fn uses_limiter(&self) -> bool {
+        self.ceiling_mode == CeilingMode::Limit
+            && !matches!(self.mode, NormalizationMode::Peak { .. })
     }
 
     /// Process an audio file and save the output as an MP3
     pub fn process(&self, channels: usize, sample_rate: u32, samples: &[f32]) -> Result<Vec<f32>> {
-        let current_loudness = measure_loudness(channels, sample_rate, samples)?;
-        let gain_to_target = calculate_gain_to_reach_target(current_loudness, self.target_loudness);
-
-        let processed_samples = apply_gain(samples, gain_to_target, self.target_peak)?;
+        let (processed_samples, _) = self.normalize(channels, sample_rate, samples)?;
 
         Ok(processed_samples)
     }
+
+    /// Normalize the samples and report the loudness and peak that were achieved
+    pub fn normalize(
+        &self,
+        channels: usize,
+        sample_rate: u32,
+        samples: &[f32],
+    ) -> Result<(Vec<f32>, NormalizationReport)> {
+        let current_loudness = self.mode.loudness(channels, sample_rate, samples)?;
+        let current_level = if self.mode.is_loudness() {
+            current_loudness
+        } else {
+            self.mode.measure(channels, sample_rate, samples)?
+        };
+        let target = self.mode.target(self.target_loudness)
+            - self.bass_penalty_db(channels, sample_rate, samples);
+
+        // Whatever the mode, never push the integrated loudness past the hearing-safety cap
+        let gain_to_target = calculate_gain_to_reach_target(current_level, target).min(
+            calculate_gain_to_reach_target(current_loudness, Self::MAX_TARGET_LOUDNESS),
+        );
+
+        let limit = self.uses_limiter();
+
+        let (processed_samples, gain) = if limit {
+            self.limit_to_target(
+                channels,
+                sample_rate,
+                samples,
+                target,
+                linear_to_db(gain_to_target),
+            )?
+        } else {
+            (
+                apply_gain(samples, gain_to_target, self.target_peak)?,
+                linear_to_db(peak_capped_gain(samples, gain_to_target, self.target_peak)),
+            )
+        };
+
+        let report = self.report(
+            channels,
+            sample_rate,
+            current_loudness,
+            &processed_samples,
+            gain,
+            target,
+        )?;
+
+        if !report.reached_target {
+            warn!(
+                "Output level {:.1} dB is {:.1} dB below the {:.1} dB target",
+                report.output_level,
+                target - report.output_level,
+                target
+            );
+        }
+
+        debug!("Normalization report: {:?}", report);
+
+        Ok((processed_samples, report))
+    }
+
+    /// Apply the full loudness gain and limit, re-adjusting until the output is on target
+    ///
+    /// Returns the limited samples and the last gain applied in dB
+    fn limit_to_target(
+        &self,
+        channels: usize,
+        sample_rate: u32,
+        samples: &[f32],
+        target: f64,
+        initial_gain: f64,
+    ) -> Result<(Vec<f32>, f64)> {
+        let mut gain = initial_gain;
+        let mut best: Option<(Vec<f32>, f64, f64)> = None;
+
+        for iteration in 0..Self::MAX_LIMIT_ITERATIONS {
+            let (limited, loudness) =
+                self.apply_gain_and_limit(channels, sample_rate, samples, gain)?;
+            let level = if self.mode.is_loudness() {
+                loudness
+            } else {
+                self.mode.measure(channels, sample_rate, &limited)?
+            };
+
+            debug!(
+                "Limit pass {}: gain {:.2} dB -> {:.2} dB, {:.2} LUFS",
+                iteration, gain, level, loudness
+            );
+
+            // Limiting raises the density of the clip, so keep it under the hearing-safety cap
+            if loudness > Self::MAX_TARGET_LOUDNESS {
+                gain -= loudness - Self::MAX_TARGET_LOUDNESS;
+                continue;
+            }
+
+            let error = (target - level).abs();
+            if best.as_ref().is_none_or(|(_, _, e)| error < *e) {
+                best = Some((limited, gain, error));
+            }
+
+            if error <= self.tolerance {
+                break;
+            }
+
+            gain += target - level;
+        }
+
+        match best {
+            Some((limited, gain, _)) => Ok((limited, gain)),
+            None => Err(anyhow::anyhow!(
+                "Could not reach the target without exceeding `{}` LUFS",
+                Self::MAX_TARGET_LOUDNESS
+            )),
+        }
+    }
+
+    /// Apply a fixed gain and hold the peak ceiling with the limiter
+    ///
+    /// Returns the limited samples and their integrated loudness in LUFS
+    fn apply_gain_and_limit(
+        &self,
+        channels: usize,
+        sample_rate: u32,
+        samples: &[f32],
+        gain: f64,
+    ) -> Result<(Vec<f32>, f64)> {
+        let gained: Vec<f32> = samples
+            .iter()
+            .map(|&s| (s as f64 * db_to_linear(gain)) as f32)
+            .collect();
+
+        let limiter = Limiter::new(
+            self.target_peak,
+            Limiter::DEFAULT_RELEASE_TIME,
+            Limiter::DEFAULT_LOOKAHEAD_MS,
+        )?
+        .with_true_peak();
+        let limited = limiter.process(&gained, channels, sample_rate)?;
+        let loudness = self.mode.loudness(channels, sample_rate, &limited)?;
+
+        Ok((limited, loudness))
+    }
+
+    /// Normalize a group of clips with one shared gain, preserving their relative loudness
+    ///
+    /// The group is measured as a whole (album gain), then the gain is lowered until every
+    /// member stays under the hearing-safety cap and, unless limiting, under the peak ceiling.
+    ///
+    /// Members without a loudness, such as silent clips or clips too short to measure, are
+    /// left out of the group and come back as errors, to be passed through unchanged.
+    pub fn normalize_group(&self, members: &[GroupMember]) -> Result<Vec<GroupResult>> {
+        let loudness = members
+            .iter()
+            .map(|m| self.mode.loudness(m.channels, m.sample_rate, m.samples))
+            .collect::<Vec<_>>();
+
+        let measurable = members
+            .iter()
+            .zip(&loudness)
+            .filter(|(_, member_loudness)| member_loudness.is_ok())
+            .map(|(member, _)| *member)
+            .collect::<Vec<_>>();
+
+        if measurable.is_empty() {
+            return Ok(loudness
+                .into_iter()
+                .filter_map(Result::err)
+                .map(|e| Err(unmeasurable(e)))
+                .collect());
+        }
+
+        let group_level = self.measure_group(&measurable)?;
+        let target = self.mode.target(self.target_loudness);
+        let mut gain = target - group_level;
+
+        for member_loudness in loudness.iter().flatten() {
+            gain = gain.min(Self::MAX_TARGET_LOUDNESS - member_loudness);
+        }
+
+        let limit = self.uses_limiter();
+
+        if !limit {
+            for member in &measurable {
+                gain = gain.min(self.target_peak - linear_to_db(max_peak(member.samples)));
+            }
+        }
+
+        let reached_target = target - group_level - gain <= self.tolerance;
+
+        debug!(
+            "Group of {} clips measured at {:.2} dB, applying shared gain of {:.2} dB",
+            measurable.len(),
+            group_level,
+            gain
+        );
+
+        let normalized = members
+            .iter()
+            .zip(loudness)
+            .map(|(member, input_loudness)| {
+                let input_loudness = input_loudness.map_err(unmeasurable)?;
+                let processed = if limit {
+                    let (limited, _) = self.apply_gain_and_limit(
+                        member.channels,
+                        member.sample_rate,
+                        member.samples,
+                        gain,
+                    )?;
+                    limited
+                } else {
+                    member
+                        .samples
+                        .iter()
+                        .map(|&s| (s as f64 * db_to_linear(gain)) as f32)
+                        .collect()
+                };
+
+                let report = self.report(
+                    member.channels,
+                    member.sample_rate,
+                    input_loudness,
+                    &processed,
+                    gain,
+                    target,
+                )?;
+
+                // Members keep their distance from the group level, so only the shared
+                // gain says whether the group got there
+                let report = NormalizationReport {
+                    reached_target,
+                    ..report
+                };
+
+                Ok((processed, report))
+            })
+            .collect();
+
+        Ok(normalized)
+    }
+
+    /// Measure a group of clips as if they were one recording, in the units of the mode
+    fn measure_group(&self, members: &[GroupMember]) -> Result<f64> {
+        match self.mode {
+            NormalizationMode::Loudness | NormalizationMode::ReplayGain { .. } => {
+                let meters = members
+                    .iter()
+                    .map(|m| {
+                        let mut ebu = EbuR128::new(
+                            m.channels as u32,
+                            m.sample_rate,
+                            Mode::I | Mode::HISTOGRAM,
+                        )
+                        .context("Failed to create EBU R128 analyzer")?;
+                        ebu.add_frames_f32(m.samples)
+                            .context("Failed to analyze audio samples")?;
+                        Ok(ebu)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let loudness = EbuR128::loudness_global_multiple(meters.iter())
+                    .context("Failed to calculate group loudness")?;
+
+                if !loudness.is_finite() {
+                    return Err(anyhow::anyhow!("Invalid group loudness value calculated"));
+                }
+
+                Ok(loudness)
+            }
+            NormalizationMode::Peak { .. } => {
+                let mut peak = f64::NEG_INFINITY;
+                for m in members {
+                    peak = peak.max(self.mode.measure(m.channels, m.sample_rate, m.samples)?);
+                }
+                Ok(peak)
+            }
+            NormalizationMode::Perceptual => {
+                // Loudness adds up in sone rather than phon, so average the loudness of every
+                // member in sone, weighted by its length
+                let model = LoudnessModel::default();
+                let mut sone = 0.0;
+                let mut count = 0.0;
+                for m in members {
+                    let loudness = model.measure(m.samples, m.channels, m.sample_rate)?;
+                    sone += loudness.long_term * m.samples.len() as f64;
+                    count += m.samples.len() as f64;
+                }
+                Ok(model.loudness_of(sone / count))
+            }
+            NormalizationMode::Rms { .. } => {
+                // Average the power of every member, weighted by its length
+                let mut power = 0.0;
+                let mut count = 0.0;
+                for m in members {
+                    let level = self.mode.measure(m.channels, m.sample_rate, m.samples)?;
+                    power += 10f64.powf(level / 10.0) * m.samples.len() as f64;
+                    count += m.samples.len() as f64;
+                }
+                Ok(10.0 * (power / count).log10())
+            }
+        }
+    }
+
+    /// Measure the processed samples for the normalization report
+    fn report(
+        &self,
+        channels: usize,
+        sample_rate: u32,
+        input_loudness: f64,
+        processed: &[f32],
+        gain: f64,
+        target: f64,
+    ) -> Result<NormalizationReport> {
+        let (output_loudness, output_peak) =
+            measure_loudness_and_true_peak(channels, sample_rate, processed)?;
+        let output_loudness =
+            self.mode
+                .loudness_from(channels, sample_rate, processed, output_loudness)?;
+        let output_level = if self.mode.is_loudness() {
+            output_loudness
+        } else {
+            self.mode.measure(channels, sample_rate, processed)?
+        };
+
+        Ok(NormalizationReport {
+            input_loudness,
+            output_loudness,
+            output_level,
+            output_peak,
+            gain,
+            target,
+            reached_target: target - output_level <= self.tolerance,
+        })
+    }
+}
+
+/// Error for a group member that was left out of the group measurement
+fn unmeasurable(e: Error) -> Error {
+    e.context("Clip cannot be measured, leaving it unchanged")
+}
+
+/// Normalized samples and report of a group member, or why it was left unchanged
+pub type GroupResult = Result<(Vec<f32>, NormalizationReport)>;
+
+/// A decoded clip that is normalized as part of a group
+#[derive(Debug, Clone, Copy)]
+pub struct GroupMember<'a> {
+    pub samples: &'a [f32],
+    pub channels: usize,
+    pub sample_rate: u32,
 }
 
 impl AudioProcessor for Normalizer {
@@ -97,21 +767,200 @@ impl AudioProcessor for Normalizer {
     }
 }
 
-/// Apply the calculated gain to the audio samples
+/// Ensure the loudness and peak targets stay within the hearing-safety limits
+fn validate_targets(target_loudness: f64, target_peak: f64) -> Result<()> {
+    // Ensure values are negative
+    if target_loudness >= 0.0 {
+        return Err(anyhow::anyhow!(
+            "Target loudness must be negative (got: {} LUFS)",
+            target_loudness
+        ));
+    }
+
+    if target_peak >= 0.0 {
+        return Err(anyhow::anyhow!(
+            "Peak ceiling must be negative (got: {} dBFS)",
+            target_peak
+        ));
+    }
+
+    // Check maximum allowed values
+    if target_loudness > Normalizer::MAX_TARGET_LOUDNESS {
+        return Err(anyhow::anyhow!(
+            "Target loudness `{}` LUFS exceeds maximum allowed value of `{}`",
+            target_loudness,
+            Normalizer::MAX_TARGET_LOUDNESS
+        ));
+    }
+
+    if target_peak > Normalizer::MAX_TARGET_PEAK {
+        return Err(anyhow::anyhow!(
+            "Peak ceiling `{}` dBFS exceeds maximum allowed value of `{}`",
+            target_peak,
+            Normalizer::MAX_TARGET_PEAK
+        ));
+    }
+
+    Ok(())
+}
+
+/// Normalizer that follows loudness over a sliding window, for block-based use
 ///
-/// This function also limits the gain to the target peak if it is exceeded
-fn apply_gain(samples: &[f32], gain: f64, target_peak: f64) -> Result<Vec<f32>> {
-    // Convert target peak from dB to linear scale
-    let peak_limit = db_to_linear(target_peak);
+/// Gain is smoothed towards the target and an internal [`Limiter`] holds the peak ceiling.
+pub struct StreamingNormalizer {
+    target_loudness: f64,
+    window_ms: u32,
+    limiter: Limiter,
+    state: Option<StreamingNormalizerState>,
+}
 
-    // Find the maximum peak in the input
-    let current_peak = max_peak(samples);
+struct StreamingNormalizerState {
+    meter: EbuR128,
+    sample_rate: u32,
+    channels: usize,
+    frames: u64,
+    gain: f64,
+    target_gain: f64,
+    smoothing_coeff: f64,
+}
 
-    // Calculate the maximum allowed gain to stay under peak ceiling
-    let max_gain = peak_limit / current_peak;
+impl Default for StreamingNormalizer {
+    fn default() -> Self {
+        Self::new(
+            Normalizer::DEFAULT_TARGET_LOUDNESS,
+            Normalizer::DEFAULT_TARGET_PEAK,
+            Self::DEFAULT_WINDOW_MS,
+        )
+        .expect("default streaming normalizer settings are valid")
+    }
+}
 
-    // Use the smaller of the calculated gain and max allowed gain
-    let final_gain = gain.min(max_gain);
+impl StreamingNormalizer {
+    pub const DEFAULT_WINDOW_MS: u32 = 3000;
+    /// Shortest stretch of audio that is measured before the gain starts moving
+    pub const MIN_WINDOW_MS: u32 = 400;
+    /// Maximum boost applied to quiet passages
+    pub const MAX_GAIN_DB: f64 = 20.0;
+    /// Windows quieter than this are treated as silence and hold the current gain
+    pub const SILENCE_GATE: f64 = -60.0;
+    /// Time constant of the gain smoothing
+    pub const SMOOTHING_MS: f64 = 500.0;
+
+    pub fn new(target_loudness: f64, target_peak: f64, window_ms: u32) -> Result<Self> {
+        validate_targets(target_loudness, target_peak)?;
+
+        if window_ms < Self::MIN_WINDOW_MS {
+            return Err(anyhow::anyhow!(
+                "Loudness window must be at least {} ms (got: {} ms)",
+                Self::MIN_WINDOW_MS,
+                window_ms
+            ));
+        }
+
+        let limiter = Limiter::new(
+            target_peak,
+            Limiter::DEFAULT_RELEASE_TIME,
+            Limiter::DEFAULT_LOOKAHEAD_MS,
+        )?;
+
+        Ok(Self {
+            target_loudness,
+            window_ms,
+            limiter,
+            state: None,
+        })
+    }
+}
+
+impl StreamingProcessor for StreamingNormalizer {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) -> Result<()> {
+        let mut meter = EbuR128::new(channels as u32, sample_rate, Mode::M)
+            .context("Failed to create EBU R128 analyzer")?;
+        meter
+            .set_max_window(self.window_ms)
+            .context("Failed to set loudness window")?;
+
+        let smoothing_samples = Self::SMOOTHING_MS * 0.001 * sample_rate as f64;
+
+        self.limiter.prepare(channels, sample_rate)?;
+        self.state = Some(StreamingNormalizerState {
+            meter,
+            sample_rate,
+            channels,
+            frames: 0,
+            gain: 1.0,
+            target_gain: 1.0,
+            smoothing_coeff: (-1.0 / smoothing_samples).exp(),
+        });
+
+        Ok(())
+    }
+
+    fn process_block(&mut self, block: &mut [f32]) -> Result<()> {
+        let state = self
+            .state
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Normalizer must be prepared before processing"))?;
+
+        if !block.len().is_multiple_of(state.channels) {
+            return Err(anyhow::anyhow!(
+                "Block length {} is not a multiple of {} channels",
+                block.len(),
+                state.channels
+            ));
+        }
+
+        state
+            .meter
+            .add_frames_f32(block)
+            .context("Failed to analyze audio samples")?;
+        state.frames += (block.len() / state.channels) as u64;
+
+        // Measure over what has been heard so far until the window fills up
+        let elapsed_ms = (state.frames * 1000 / state.sample_rate as u64) as u32;
+        if elapsed_ms >= Self::MIN_WINDOW_MS {
+            let loudness = state
+                .meter
+                .loudness_window(elapsed_ms.min(self.window_ms))
+                .context("Failed to calculate window loudness")?;
+
+            if loudness.is_finite() && loudness > Self::SILENCE_GATE {
+                let gain_db = (self.target_loudness - loudness).min(Self::MAX_GAIN_DB);
+                state.target_gain = db_to_linear(gain_db);
+            }
+        }
+
+        for frame in block.chunks_exact_mut(state.channels) {
+            state.gain =
+                state.target_gain + (state.gain - state.target_gain) * state.smoothing_coeff;
+            for sample in frame {
+                *sample = (*sample as f64 * state.gain) as f32;
+            }
+        }
+
+        self.limiter.process_block(block)
+    }
+
+    fn latency(&self) -> usize {
+        self.limiter.latency()
+    }
+
+    fn reset(&mut self) {
+        if let Some(state) = self.state.as_mut() {
+            state.meter.reset();
+            state.frames = 0;
+            state.gain = 1.0;
+            state.target_gain = 1.0;
+        }
+        self.limiter.reset();
+    }
+}
+
+/// Apply the calculated gain to the audio samples
+///
+/// This function also limits the gain to the target peak if it is exceeded
+fn apply_gain(samples: &[f32], gain: f64, target_peak: f64) -> Result<Vec<f32>> {
+    let final_gain = peak_capped_gain(samples, gain, target_peak);
 
     debug!(
         "Applying gain: {:.2} dB (limited from {:.2} dB due to peak ceiling)",
@@ -128,8 +977,23 @@ fn apply_gain(samples: &[f32], gain: f64, target_peak: f64) -> Result<Vec<f32>>
     Ok(normalized_samples)
 }
 
+/// Linear gain lowered, if needed, so the loudest sample stays under the peak ceiling
+fn peak_capped_gain(samples: &[f32], gain: f64, target_peak: f64) -> f64 {
+    // Convert target peak from dB to linear scale
+    let peak_limit = db_to_linear(target_peak);
+
+    // Find the maximum peak in the input
+    let current_peak = max_peak(samples);
+
+    // Calculate the maximum allowed gain to stay under peak ceiling
+    let max_gain = peak_limit / current_peak;
+
+    // Use the smaller of the calculated gain and max allowed gain
+    gain.min(max_gain)
+}
+
 /// Measure the loudness of the audio samples
-fn measure_loudness(channels: usize, sample_rate: u32, samples: &[f32]) -> Result<f64> {
+pub(crate) fn measure_loudness(channels: usize, sample_rate: u32, samples: &[f32]) -> Result<f64> {
     let mut ebu = EbuR128::new(channels as u32, sample_rate, Mode::I | Mode::HISTOGRAM)
         .context("Failed to create EBU R128 analyzer")?;
 
@@ -147,6 +1011,33 @@ fn measure_loudness(channels: usize, sample_rate: u32, samples: &[f32]) -> Resul
     Ok(current_loudness)
 }
 
+/// Measure the integrated loudness in LUFS and the true peak in dBTP of the audio samples
+pub(crate) fn measure_loudness_and_true_peak(
+    channels: usize,
+    sample_rate: u32,
+    samples: &[f32],
+) -> Result<(f64, f64)> {
+    let mut ebu = EbuR128::new(channels as u32, sample_rate, Mode::I | Mode::TRUE_PEAK)
+        .context("Failed to create EBU R128 analyzer")?;
+
+    ebu.add_frames_f32(samples)
+        .context("Failed to analyze audio samples")?;
+
+    let loudness = ebu
+        .loudness_global()
+        .context("Failed to calculate global loudness")?;
+
+    let mut true_peak = 0.0_f64;
+    for channel in 0..channels as u32 {
+        true_peak = true_peak.max(
+            ebu.true_peak(channel)
+                .context("Failed to calculate true peak")?,
+        );
+    }
+
+    Ok((loudness, linear_to_db(true_peak)))
+}
+
 fn calculate_gain_to_reach_target(current_loudness: f64, target_loudness: f64) -> f64 {
     let gain_db = target_loudness - current_loudness;
     db_to_linear(gain_db)
@@ -157,6 +1048,7 @@ mod tests {
     use std::path::Path;
 
     use super::*;
+    use crate::test_signals::sine;
 
     #[test]
     fn test_process_audio_stream() -> Result<()> {
@@ -303,4 +1195,382 @@ mod tests {
         let result = Normalizer::new(-15.0, -1.0);
         assert!(result.is_ok(), "Should accept valid negative parameters");
     }
+
+    #[test]
+    fn test_streaming_normalizer_follows_target() {
+        let sample_rate = 44100;
+        let samples = sine(1000.0, 0.05, sample_rate as u32, sample_rate * 6);
+
+        let adapter = StreamingAdapter::new(StreamingNormalizer::default());
+        let processed = AudioProcessor::process(&adapter, &samples, 1, sample_rate as u32).unwrap();
+        assert_eq!(processed.len(), samples.len());
+
+        // Once settled, the last seconds should sit close to the target loudness
+        let tail = &processed[processed.len() - sample_rate * 2..];
+        let loudness = measure_loudness(1, sample_rate as u32, tail).unwrap();
+        assert!(
+            (loudness - Normalizer::DEFAULT_TARGET_LOUDNESS).abs() < 1.0,
+            "Expected loudness near {:.1} LUFS, got {:.1} LUFS",
+            Normalizer::DEFAULT_TARGET_LOUDNESS,
+            loudness
+        );
+        assert!(max_peak(&processed) <= db_to_linear(Normalizer::DEFAULT_TARGET_PEAK) + 1e-6);
+    }
+
+    #[test]
+    fn test_streaming_normalizer_invalid_window() {
+        let result = StreamingNormalizer::new(-18.0, -1.0, 100);
+        assert!(result.is_err(), "Should error when the window is too short");
+    }
+
+    #[test]
+    fn test_streaming_normalizer_rejects_partial_frames() {
+        let mut normalizer = StreamingNormalizer::default();
+        normalizer.prepare(2, 48000).unwrap();
+
+        let mut block = vec![0.0; 1023];
+        assert!(
+            normalizer.process_block(&mut block).is_err(),
+            "Should error when a block ends mid-frame"
+        );
+    }
+
+    /// Quiet tone with sharp spikes, so peak-limited gain falls well short of the target
+    fn spiky_tone(sample_rate: u32, seconds: usize) -> Vec<f32> {
+        (0..sample_rate as usize * seconds)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                let tone = 0.02 * (2.0 * std::f32::consts::PI * 440.0 * t).sin();
+                if i % (sample_rate as usize / 4) == 0 {
+                    0.9
+                } else {
+                    tone
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_limit_mode_reaches_target() {
+        let sample_rate = 44100;
+        let samples = spiky_tone(sample_rate, 4);
+
+        let (_, reduced) = Normalizer::new(-18.0, -1.0)
+            .unwrap()
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+        assert!(
+            reduced.output_loudness < -18.0 - Normalizer::DEFAULT_TOLERANCE,
+            "Gain reduction alone should undershoot the target, got {:.1} LUFS",
+            reduced.output_loudness
+        );
+
+        let (limited, report) = Normalizer::new(-18.0, -1.0)
+            .unwrap()
+            .with_ceiling_mode(CeilingMode::Limit)
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+        assert_eq!(limited.len(), samples.len());
+        assert!(
+            (report.output_loudness + 18.0).abs() <= Normalizer::DEFAULT_TOLERANCE,
+            "Expected loudness within tolerance of -18.0 LUFS, got {:.1} LUFS",
+            report.output_loudness
+        );
+        assert!(
+            report.output_peak <= -1.0,
+            "Expected true peak under -1.0 dBTP, got {:.2} dBTP",
+            report.output_peak
+        );
+    }
+
+    #[test]
+    fn test_reduce_gain_reports_applied_gain() {
+        let sample_rate = 44100;
+        let samples = spiky_tone(sample_rate, 4);
+
+        let (processed, report) = Normalizer::new(-18.0, -1.0)
+            .unwrap()
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+        let applied = linear_to_db(max_peak(&processed) / max_peak(&samples));
+        assert!(
+            (report.gain - applied).abs() < 0.01,
+            "Expected the applied {:.2} dB gain, got {:.2} dB",
+            applied,
+            report.gain
+        );
+        assert!(!report.reached_target);
+        assert!(report.shortfall() > Normalizer::DEFAULT_TOLERANCE);
+    }
+
+    #[test]
+    fn test_invalid_tolerance() {
+        assert!(Normalizer::default().with_tolerance(0.0).is_err());
+        assert!(Normalizer::default().with_tolerance(0.25).is_ok());
+    }
+
+    #[test]
+    fn test_normalization_modes_reach_level() {
+        let sample_rate = 44100;
+        let samples = sine(1000.0, 0.1, sample_rate, sample_rate as usize * 2);
+
+        for mode in [
+            // Kept low enough that the loudness cap does not kick in
+            NormalizationMode::Peak {
+                level: -16.0,
+                true_peak: false,
+            },
+            NormalizationMode::Rms {
+                level: -24.0,
+                a_weighted: false,
+            },
+            NormalizationMode::Rms {
+                level: -24.0,
+                a_weighted: true,
+            },
+            NormalizationMode::ReplayGain { pre_amp: -2.0 },
+        ] {
+            let (_, report) = Normalizer::default()
+                .with_mode(mode)
+                .unwrap()
+                .normalize(1, sample_rate, &samples)
+                .unwrap();
+            let expected = mode.target(Normalizer::DEFAULT_TARGET_LOUDNESS);
+            assert!(
+                (report.output_level - expected).abs() < 0.2,
+                "{:?}: expected {:.1} dB, got {:.1} dB",
+                mode,
+                expected,
+                report.output_level
+            );
+        }
+    }
+
+    #[test]
+    fn test_peak_mode_respects_loudness_cap() {
+        // A square wave normalized to its peak would be far louder than the cap
+        let sample_rate = 44100;
+        let samples: Vec<f32> = (0..sample_rate as usize * 2)
+            .map(|i| if (i / 50) % 2 == 0 { 0.1 } else { -0.1 })
+            .collect();
+
+        let (_, report) = Normalizer::new(-18.0, -0.5)
+            .unwrap()
+            .with_mode(NormalizationMode::Peak {
+                level: -0.5,
+                true_peak: false,
+            })
+            .unwrap()
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+
+        assert!(
+            report.output_loudness <= Normalizer::MAX_TARGET_LOUDNESS + 0.1,
+            "Expected loudness capped at {:.1} LUFS, got {:.1} LUFS",
+            Normalizer::MAX_TARGET_LOUDNESS,
+            report.output_loudness
+        );
+    }
+
+    #[test]
+    fn test_perceptual_mode_normalizes_short_clips() {
+        // A 150 ms stab is too short for a gated integrated loudness
+        let sample_rate = 44100;
+        let samples = sine(800.0, 0.05, sample_rate, sample_rate as usize * 3 / 20);
+
+        assert!(Normalizer::default()
+            .process(1, sample_rate, &samples)
+            .is_err());
+
+        let (_, report) = Normalizer::new(-20.0, -1.0)
+            .unwrap()
+            .with_mode(NormalizationMode::Perceptual)
+            .unwrap()
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+
+        assert!(
+            (report.output_level - -20.0).abs() < 1.0,
+            "Expected about -20.0 LUFS, got {:.1}",
+            report.output_level
+        );
+    }
+
+    #[test]
+    fn test_perceptual_group_averages_in_sone() {
+        let sample_rate = 44100;
+        let quiet = sine(1000.0, 0.01, sample_rate, sample_rate as usize);
+        let loud = sine(1000.0, 0.1, sample_rate, sample_rate as usize);
+        let members = [&quiet, &loud].map(|samples| GroupMember {
+            samples,
+            channels: 1,
+            sample_rate,
+        });
+
+        let model = LoudnessModel::default();
+        let quiet = model.measure(&quiet, 1, sample_rate).unwrap();
+        let loud = model.measure(&loud, 1, sample_rate).unwrap();
+
+        let group_level = Normalizer::default()
+            .with_mode(NormalizationMode::Perceptual)
+            .unwrap()
+            .measure_group(&members)
+            .unwrap();
+
+        let expected = model.loudness_of((quiet.long_term + loud.long_term) / 2.0);
+        assert!(
+            (group_level - expected).abs() < 1e-9,
+            "Expected {:.2} LUFS, got {:.2}",
+            expected,
+            group_level
+        );
+        // Averaging in phon would land halfway between the members, under the sone average
+        assert!(group_level > (quiet.loudness + loud.loudness) / 2.0 + 1.0);
+    }
+
+    #[test]
+    fn test_invalid_mode_levels() {
+        let invalid = [
+            NormalizationMode::Peak {
+                level: 0.0,
+                true_peak: true,
+            },
+            NormalizationMode::Rms {
+                level: -9.0,
+                a_weighted: false,
+            },
+            NormalizationMode::ReplayGain { pre_amp: 6.0 },
+            NormalizationMode::Peak {
+                level: f64::NAN,
+                true_peak: false,
+            },
+            NormalizationMode::Rms {
+                level: f64::NEG_INFINITY,
+                a_weighted: true,
+            },
+            NormalizationMode::ReplayGain { pre_amp: f64::NAN },
+            // Above the default -1 dBTP ceiling, which the gain is capped at
+            NormalizationMode::Peak {
+                level: -0.5,
+                true_peak: true,
+            },
+        ];
+
+        for mode in invalid {
+            assert!(
+                Normalizer::default().with_mode(mode).is_err(),
+                "{:?} should be rejected",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_group_preserves_relative_loudness() {
+        let sample_rate = 44100;
+        let tone = |amplitude| sine(440.0, amplitude, sample_rate, sample_rate as usize * 2);
+        let quiet = tone(0.01);
+        let loud = tone(0.1);
+        let spiky = spiky_tone(sample_rate, 2);
+
+        let members = [&quiet, &loud, &spiky].map(|samples| GroupMember {
+            samples,
+            channels: 1,
+            sample_rate,
+        });
+
+        let normalized = Normalizer::default()
+            .normalize_group(&members)
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(normalized.len(), 3);
+
+        let (_, quiet_report) = &normalized[0];
+        let (_, loud_report) = &normalized[1];
+        let input_difference = loud_report.input_loudness - quiet_report.input_loudness;
+        let output_difference = loud_report.output_loudness - quiet_report.output_loudness;
+        assert!(
+            (input_difference - output_difference).abs() < 0.1,
+            "Expected a {:.1} LU difference to be kept, got {:.1} LU",
+            input_difference,
+            output_difference
+        );
+
+        // The spiky member constrains the shared gain so nothing clips
+        for (samples, report) in &normalized {
+            assert!(max_peak(samples) <= db_to_linear(Normalizer::DEFAULT_TARGET_PEAK) + 1e-6);
+            assert_eq!(report.gain, normalized[0].1.gain);
+        }
+    }
+
+    #[test]
+    fn test_group_skips_silent_member() {
+        let sample_rate = 44100;
+        let tone = sine(440.0, 0.05, sample_rate, sample_rate as usize * 2);
+        let silence = vec![0.0; sample_rate as usize];
+
+        let member = |samples| GroupMember {
+            samples,
+            channels: 1,
+            sample_rate,
+        };
+        let normalizer = Normalizer::default();
+        let alone = normalizer.normalize_group(&[member(&tone)]).unwrap();
+        let normalized = normalizer
+            .normalize_group(&[member(&tone), member(&silence)])
+            .unwrap();
+        assert_eq!(normalized.len(), 2);
+
+        // The silent member neither fails the group nor changes its gain
+        let (_, report) = normalized[0].as_ref().unwrap();
+        let (_, alone_report) = alone[0].as_ref().unwrap();
+        assert_eq!(report.gain, alone_report.gain);
+        assert!(normalized[1].is_err(), "Silent member should be skipped");
+    }
+
+    #[test]
+    fn test_empty_group() {
+        let normalized = Normalizer::default().normalize_group(&[]).unwrap();
+        assert!(normalized.is_empty());
+    }
+
+    #[test]
+    fn test_bass_penalty_lowers_target() {
+        let sample_rate = 44100;
+        let samples: Vec<f32> = (0..sample_rate as usize * 2)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                0.3 * (2.0 * std::f32::consts::PI * 50.0 * t).sin()
+                    + 0.05 * (2.0 * std::f32::consts::PI * 1000.0 * t).sin()
+            })
+            .collect();
+
+        let (_, plain) = Normalizer::default()
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+        let (_, penalized) = Normalizer::default()
+            .with_bass_penalty(1.0)
+            .unwrap()
+            .normalize(1, sample_rate, &samples)
+            .unwrap();
+
+        let penalty = plain.output_loudness - penalized.output_loudness;
+        assert!(
+            penalty > 1.0 && penalty <= Normalizer::MAX_BASS_PENALTY_DB + 0.1,
+            "Expected a bass penalty of up to {} dB, got {:.1} dB",
+            Normalizer::MAX_BASS_PENALTY_DB,
+            penalty
+        );
+        assert!(Normalizer::default().with_bass_penalty(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_with_target_loudness_keeps_caps() {
+        assert!(Normalizer::default().with_target_loudness(-22.0).is_ok());
+        assert!(Normalizer::default()
+            .with_target_loudness(Normalizer::MAX_TARGET_LOUDNESS + 1.0)
+            .is_err());
+    }
 }
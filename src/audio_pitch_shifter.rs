@@ -0,0 +1,126 @@
+use anyhow::{Error, Result};
+
+use crate::dsp::{resample, time_stretch, AudioProcessor};
+
+/// Shifts the pitch of clips while keeping their duration
+///
+/// The clip is first time-stretched by the pitch factor, which keeps its pitch, and then
+/// resampled back to its original length, which moves the pitch by that factor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchShifter {
+    semitones: i32,
+    cents: f64,
+}
+
+impl PitchShifter {
+    /// Largest shift either way, an octave
+    pub const MAX_SEMITONES: f64 = 12.0;
+
+    /// Shift by `semitones` plus `cents`, up when positive and down when negative
+    pub fn new(semitones: i32, cents: f64) -> Result<Self> {
+        if !cents.is_finite() || cents.abs() >= 100.0 {
+            return Err(anyhow::anyhow!(
+                "Cents must be between -100 and 100 (got: {})",
+                cents
+            ));
+        }
+
+        let shifter = Self { semitones, cents };
+        if shifter.shift().abs() > Self::MAX_SEMITONES {
+            return Err(anyhow::anyhow!(
+                "Pitch shift must be within {} semitones (got: {:.2})",
+                Self::MAX_SEMITONES,
+                shifter.shift()
+            ));
+        }
+
+        Ok(shifter)
+    }
+
+    /// Total shift in semitones
+    pub fn shift(&self) -> f64 {
+        self.semitones as f64 + self.cents / 100.0
+    }
+
+    /// Short description of the shift for naming variants, such as `+3st` or `-2st+25c`
+    pub fn label(&self) -> String {
+        if self.cents == 0.0 {
+            format!("{:+}st", self.semitones)
+        } else {
+            format!("{:+}st{:+}c", self.semitones, self.cents)
+        }
+    }
+}
+
+impl AudioProcessor for PitchShifter {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let factor = 2f64.powf(self.shift() / 12.0);
+        if factor == 1.0 {
+            return Ok(samples.to_vec());
+        }
+
+        let stretched = time_stretch(samples, channels, sample_rate, factor)?;
+        let mut output = resample(&stretched, channels, 1.0 / factor)?;
+
+        // Rounding in both steps can leave the clip a frame off its original length
+        output.resize(samples.len(), 0.0);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::rms_db;
+    use crate::test_signals::sine;
+
+    /// Upward zero crossings per second of the middle of the clip
+    fn pitch(samples: &[f32], sample_rate: u32) -> f64 {
+        let middle = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        let crossings = middle.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0);
+        crossings.count() as f64 * sample_rate as f64 / middle.len() as f64
+    }
+
+    #[test]
+    fn test_shifts_pitch_and_keeps_duration() {
+        let sample_rate = 44100;
+        let clip = sine(220.0, 0.5, sample_rate, sample_rate as usize * 2);
+
+        for (semitones, cents, expected) in [(12, 0.0, 440.0), (-7, 0.0, 146.8), (3, 50.0, 269.4)] {
+            let shifter = PitchShifter::new(semitones, cents).unwrap();
+            let shifted = shifter.process(&clip, 1, sample_rate).unwrap();
+            assert_eq!(shifted.len(), clip.len());
+
+            let found = pitch(&shifted, sample_rate);
+            assert!(
+                (found - expected).abs() < expected * 0.01,
+                "Expected {} Hz after shifting {}, found {:.1} Hz",
+                expected,
+                shifter.label(),
+                found
+            );
+            let middle = &shifted[shifted.len() / 4..shifted.len() * 3 / 4];
+            assert!((rms_db(middle) - rms_db(&clip)).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(PitchShifter::new(3, 0.0).unwrap().label(), "+3st");
+        assert_eq!(PitchShifter::new(-2, 25.0).unwrap().label(), "-2st+25c");
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(PitchShifter::new(13, 0.0).is_err());
+        assert!(PitchShifter::new(-12, -50.0).is_err());
+        assert!(PitchShifter::new(0, 100.0).is_err());
+        assert!(PitchShifter::new(0, f64::NAN).is_err());
+        assert!(PitchShifter::new(-12, 0.0).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Error, Result};
+use log::debug;
+
+use crate::dsp::{analyze_stereo, db_to_linear, max_peak, AudioProcessor, StereoAnalysis};
+
+/// Repairs stereo clips that cancel in mono or are lopsided left/right
+///
+/// A clip with one channel inverted can all but disappear for anyone listening on a mono
+/// device, such as a phone speaker.
+#[derive(Debug, Clone, Copy)]
+pub struct StereoFixer {
+    max_imbalance: f64,
+    fold_imbalance: f64,
+}
+
+/// A repair applied to a stereo clip
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoFix {
+    /// Invert the right channel so the channels add up instead of cancelling
+    FlipPolarity,
+    /// Lower the louder channel by this many dB
+    Rebalance(f64),
+    /// Replace both channels with their sum
+    FoldToMono,
+}
+
+impl Default for StereoFixer {
+    fn default() -> Self {
+        Self {
+            max_imbalance: Self::DEFAULT_MAX_IMBALANCE,
+            fold_imbalance: Self::DEFAULT_FOLD_IMBALANCE,
+        }
+    }
+}
+
+impl StereoFixer {
+    /// Correlation below which one channel is taken to be inverted
+    pub const INVERTED_CORRELATION: f64 = -0.5;
+    /// Default left/right imbalance in dB that is left alone
+    pub const DEFAULT_MAX_IMBALANCE: f64 = 3.0;
+    /// Default left/right imbalance in dB above which the clip is folded to mono instead of
+    /// rebalanced, as one side is all but silent
+    pub const DEFAULT_FOLD_IMBALANCE: f64 = 20.0;
+
+    pub fn new(max_imbalance: f64, fold_imbalance: f64) -> Result<Self> {
+        if max_imbalance <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Maximum imbalance must be positive (got: {} dB)",
+                max_imbalance
+            ));
+        }
+
+        if fold_imbalance < max_imbalance {
+            return Err(anyhow::anyhow!(
+                "Fold imbalance must be at least the maximum imbalance (got: {} dB < {} dB)",
+                fold_imbalance,
+                max_imbalance
+            ));
+        }
+
+        Ok(Self {
+            max_imbalance,
+            fold_imbalance,
+        })
+    }
+
+    /// The repairs the clip needs, in the order they are applied
+    pub fn plan(&self, analysis: &StereoAnalysis) -> Vec<StereoFix> {
+        let mut fixes = Vec::new();
+
+        if analysis.correlation < Self::INVERTED_CORRELATION {
+            fixes.push(StereoFix::FlipPolarity);
+        }
+
+        // Flipping the polarity leaves the level of each channel as it was
+        let imbalance = analysis.balance.abs();
+        if imbalance > self.fold_imbalance {
+            fixes.push(StereoFix::FoldToMono);
+        } else if imbalance > self.max_imbalance {
+            fixes.push(StereoFix::Rebalance(imbalance));
+        }
+
+        fixes
+    }
+}
+
+impl AudioProcessor for StereoFixer {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let Some(analysis) = analyze_stereo(samples, channels, sample_rate) else {
+            return Ok(samples.to_vec());
+        };
+
+        let mut output = samples.to_vec();
+        for fix in self.plan(&analysis) {
+            debug!("Applying stereo fix {:?} to {:?}", fix, analysis);
+
+            match fix {
+                StereoFix::FlipPolarity => {
+                    output
+                        .chunks_exact_mut(2)
+                        .for_each(|frame| frame[1] = -frame[1]);
+                }
+                StereoFix::Rebalance(cut) => {
+                    // Only the louder channel is lowered, so peaks never rise past the ceiling
+                    let louder = if analysis.balance > 0.0 { 0 } else { 1 };
+                    let gain = db_to_linear(-cut) as f32;
+                    output
+                        .chunks_exact_mut(2)
+                        .for_each(|frame| frame[louder] *= gain);
+                }
+                StereoFix::FoldToMono => {
+                    let mut mono: Vec<f32> = output
+                        .chunks_exact(2)
+                        .map(|frame| 0.5 * (frame[0] + frame[1]))
+                        .collect();
+
+                    // Keep the energy of the clip, without raising its peak
+                    let energy = |s: &[f32]| s.iter().map(|&x| (x as f64).powi(2)).sum::<f64>();
+                    let (stereo_energy, mono_energy) = (0.5 * energy(&output), energy(&mono));
+                    let (stereo_peak, mono_peak) = (max_peak(&output), max_peak(&mono));
+                    if mono_energy > 0.0 && mono_peak > 0.0 {
+                        let gain = (stereo_energy / mono_energy)
+                            .sqrt()
+                            .min(stereo_peak / mono_peak) as f32;
+                        mono.iter_mut().for_each(|s| *s *= gain);
+                    }
+
+                    output = mono.iter().flat_map(|&s| [s, s]).collect();
+                }
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::sine;
+
+    fn stereo(left: &[f32], right_gain: f32) -> Vec<f32> {
+        left.iter().flat_map(|&s| [s, s * right_gain]).collect()
+    }
+
+    #[test]
+    fn test_flips_inverted_channel() {
+        let sample_rate = 44100;
+        let clip = stereo(&sine(440.0, 0.5, sample_rate, sample_rate as usize), -1.0);
+
+        let fixer = StereoFixer::default();
+        let processed = fixer.process(&clip, 2, sample_rate).unwrap();
+        let after = analyze_stereo(&processed, 2, sample_rate).unwrap();
+
+        assert!(after.correlation > 0.99, "Got {:?}", after);
+        assert!(after.mono_loss.abs() < 0.1, "Got {:?}", after);
+    }
+
+    #[test]
+    fn test_rebalances_lopsided_clip() {
+        let sample_rate = 44100;
+        let clip = stereo(&sine(440.0, 0.5, sample_rate, sample_rate as usize), 0.3);
+
+        let fixer = StereoFixer::default();
+        let before = analyze_stereo(&clip, 2, sample_rate).unwrap();
+        assert!(matches!(
+            fixer.plan(&before).as_slice(),
+            [StereoFix::Rebalance(_)]
+        ));
+
+        let processed = fixer.process(&clip, 2, sample_rate).unwrap();
+        let after = analyze_stereo(&processed, 2, sample_rate).unwrap();
+        assert!(after.balance.abs() < 0.1, "Got {:?}", after);
+        assert!(max_peak(&processed) <= max_peak(&clip));
+    }
+
+    #[test]
+    fn test_folds_one_sided_clip() {
+        let sample_rate = 44100;
+        let clip = stereo(&sine(440.0, 0.5, sample_rate, sample_rate as usize), 0.0);
+
+        let processed = StereoFixer::default()
+            .process(&clip, 2, sample_rate)
+            .unwrap();
+
+        assert!(processed.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+        assert!(max_peak(&processed) <= max_peak(&clip) + 1e-6);
+    }
+
+    #[test]
+    fn test_leaves_healthy_clips_alone() {
+        let sample_rate = 44100;
+        let mono = sine(440.0, 0.5, sample_rate, sample_rate as usize);
+        let fixer = StereoFixer::default();
+
+        assert_eq!(fixer.process(&mono, 1, sample_rate).unwrap(), mono);
+
+        let clip = stereo(&mono, 0.9);
+        assert_eq!(fixer.process(&clip, 2, sample_rate).unwrap(), clip);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(StereoFixer::new(0.0, 20.0).is_err());
+        assert!(StereoFixer::new(6.0, 3.0).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{Error, Result};
+use log::debug;
+
+use crate::audio_normalizer::measure_loudness;
+use crate::dsp::{db_to_linear, linear_to_db, rms_db, AudioProcessor};
+
+/// Reshapes the attack and sustain of sounds from the difference of two envelope followers
+///
+/// A fast follower jumps ahead of a slow one at every onset and drops below it in the
+/// decay, so their difference tells the transient from the body of the sound. The
+/// output is brought back to the loudness of the input, so softening the attack does not
+/// make the clip quieter. Run it before the [`Limiter`], which then has less to catch.
+///
+/// [`Limiter`]: crate::audio_limiter::Limiter
+pub struct TransientShaper {
+    attack: f64,
+    sustain: f64,
+}
+
+impl Default for TransientShaper {
+    fn default() -> Self {
+        Self {
+            attack: 0.0,
+            sustain: 0.0,
+        }
+    }
+}
+
+impl TransientShaper {
+    /// Largest attack or sustain amount, in dB of gain per dB of envelope difference
+    pub const MAX_AMOUNT: f64 = 1.0;
+    /// Most gain or cut applied at any moment, in dB
+    pub const MAX_GAIN_DB: f64 = 24.0;
+
+    const FAST_ATTACK_MS: f64 = 1.0;
+    const FAST_RELEASE_MS: f64 = 20.0;
+    const SLOW_ATTACK_MS: f64 = 30.0;
+    const SLOW_RELEASE_MS: f64 = 200.0;
+    /// Envelope level treated as silence, so the gain does not chase the noise floor
+    const FLOOR: f64 = 1e-5;
+
+    /// `attack` and `sustain` scale the gain at onsets and in the decay, between -1 and 1.
+    /// Negative values soften, positive values emphasise.
+    pub fn new(attack: f64, sustain: f64) -> Result<Self> {
+        for (name, amount) in [("Attack", attack), ("Sustain", sustain)] {
+            if amount.abs() > Self::MAX_AMOUNT {
+                return Err(anyhow::anyhow!(
+                    "{} must be between -{} and {} (got: {})",
+                    name,
+                    Self::MAX_AMOUNT,
+                    Self::MAX_AMOUNT,
+                    amount
+                ));
+            }
+        }
+
+        Ok(Self { attack, sustain })
+    }
+
+    /// Takes the startle out of punchy sound effects, keeping a little more of their body
+    pub fn soften_attack() -> Self {
+        Self {
+            attack: -0.6,
+            sustain: 0.2,
+        }
+    }
+
+    /// Loudness used to restore the level, falling back to RMS for clips too short to gate
+    fn level(samples: &[f32], channels: usize, sample_rate: u32) -> f64 {
+        match measure_loudness(channels, sample_rate, samples) {
+            Ok(loudness) if loudness.is_finite() => loudness,
+            _ => rms_db(samples),
+        }
+    }
+}
+
+impl AudioProcessor for TransientShaper {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        if self.attack == 0.0 && self.sustain == 0.0 {
+            return Ok(samples.to_vec());
+        }
+
+        let coeff = |ms: f64| (-1.0 / (ms * 0.001 * sample_rate as f64)).exp();
+        let follow = |envelope: f64, level: f64, attack: f64, release: f64| {
+            let c = if level > envelope { attack } else { release };
+            c * envelope + (1.0 - c) * level
+        };
+        let (fast_attack, fast_release) =
+            (coeff(Self::FAST_ATTACK_MS), coeff(Self::FAST_RELEASE_MS));
+        let (slow_attack, slow_release) =
+            (coeff(Self::SLOW_ATTACK_MS), coeff(Self::SLOW_RELEASE_MS));
+
+        // The envelopes follow the loudest channel, so the gain is linked across channels
+        let (mut fast, mut slow) = (0.0_f64, 0.0_f64);
+        let mut output = Vec::with_capacity(samples.len());
+        for frame in samples.chunks(channels) {
+            let level = frame.iter().map(|s| s.abs() as f64).fold(0.0, f64::max);
+            fast = follow(fast, level, fast_attack, fast_release);
+            slow = follow(slow, level, slow_attack, slow_release);
+
+            let difference =
+                linear_to_db(fast.max(Self::FLOOR)) - linear_to_db(slow.max(Self::FLOOR));
+            let gain_db = if difference > 0.0 {
+                self.attack * difference
+            } else {
+                -self.sustain * difference
+            };
+            let gain = db_to_linear(gain_db.clamp(-Self::MAX_GAIN_DB, Self::MAX_GAIN_DB));
+
+            output.extend(frame.iter().map(|&s| (s as f64 * gain) as f32));
+        }
+
+        let makeup = Self::level(samples, channels, sample_rate)
+            - Self::level(&output, channels, sample_rate);
+        if makeup.is_finite() {
+            debug!("Transient shaper restoring {:.2} dB of loudness", makeup);
+            let gain = db_to_linear(makeup) as f32;
+            output.iter_mut().for_each(|s| *s *= gain);
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::max_peak;
+
+    /// Drum-like hits: a 10 ms crack over a body that rings for 200 ms
+    fn hits(sample_rate: u32) -> Vec<f32> {
+        let period = sample_rate as usize / 4;
+        (0..sample_rate as usize * 2)
+            .map(|i| {
+                let t = (i % period) as f32 / sample_rate as f32;
+                let envelope = 0.8 * (-t / 0.01).exp() + 0.2 * (-t / 0.2).exp();
+                envelope * (2.0 * std::f32::consts::PI * 200.0 * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_soften_attack_lowers_peaks_not_loudness() {
+        let sample_rate = 44100;
+        let clip = hits(sample_rate);
+        let processed = TransientShaper::soften_attack()
+            .process(&clip, 1, sample_rate)
+            .unwrap();
+
+        let crest = |s: &[f32]| linear_to_db(max_peak(s)) - rms_db(s);
+        assert!(
+            crest(&clip) - crest(&processed) > 2.0,
+            "Expected a lower crest factor ({:.1} dB -> {:.1} dB)",
+            crest(&clip),
+            crest(&processed)
+        );
+
+        let loudness_change = TransientShaper::level(&processed, 1, sample_rate)
+            - TransientShaper::level(&clip, 1, sample_rate);
+        assert!(loudness_change.abs() < 0.1);
+    }
+
+    #[test]
+    fn test_neutral_shaper_is_transparent() {
+        let clip = hits(44100);
+        let processed = TransientShaper::default().process(&clip, 1, 44100).unwrap();
+        assert_eq!(processed, clip);
+    }
+
+    #[test]
+    fn test_invalid_amounts() {
+        assert!(TransientShaper::new(-1.5, 0.0).is_err());
+        assert!(TransientShaper::new(0.0, 2.0).is_err());
+        assert!(TransientShaper::new(-1.0, 1.0).is_ok());
+    }
+}
@@ -1,14 +1,34 @@
 use anyhow::Result;
 use poise::serenity_prelude as serenity;
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use earpeace::audio_normalizer::Normalizer;
+use earpeace::audio_analyzer::ClipAnalysis;
+use earpeace::audio_bass_tamer::BassTamer;
+use earpeace::audio_declicker::DeClicker;
+use earpeace::audio_deharsher::DeHarsher;
+use earpeace::audio_denoiser::DeNoiser;
+use earpeace::audio_duration_fitter::DurationFitter;
+use earpeace::audio_file::{Mp3Mode, Mp3Options};
+use earpeace::audio_hum_remover::HumRemover;
+use earpeace::audio_leveler::Leveler;
 use earpeace::audio_limiter::Limiter;
+use earpeace::audio_matcher::Matcher;
+use earpeace::audio_normalizer::{
+    CeilingMode, ClipOutcome, ClipReport, NormalizationMode, Normalizer,
+};
+use earpeace::audio_pitch_shifter::PitchShifter;
+use earpeace::audio_stereo_fixer::StereoFixer;
+use earpeace::audio_transient_shaper::TransientShaper;
 use earpeace::discord::DiscordClient;
+use earpeace::dsp::{AudioProcessor, ProcessorChain};
 // Type aliases for convenience
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
+/// Longest reply sent, leaving room under Discord's 2000 character message limit
+const MAX_MESSAGE_LEN: usize = 1900;
+
 // User data, which is stored and accessible in all command invocations
 pub struct Data {
     discord_client: Arc<DiscordClient>,
@@ -31,22 +51,144 @@ async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
     }
 }
 
+/// How sound levels are measured when normalizing
+#[derive(Debug, poise::ChoiceParameter)]
+enum LevelMode {
+    #[name = "Loudness (LUFS)"]
+    Loudness,
+    #[name = "Sample peak"]
+    SamplePeak,
+    #[name = "True peak"]
+    TruePeak,
+    #[name = "RMS"]
+    Rms,
+    #[name = "A-weighted RMS"]
+    RmsA,
+    #[name = "ReplayGain 2.0"]
+    ReplayGain,
+    #[name = "Perceived loudness (short clips)"]
+    Perceptual,
+}
+
+impl LevelMode {
+    /// Peak modes normalize to the peak ceiling, RMS modes to the target loudness
+    fn normalization_mode(&self, target_loudness: f64, target_peak: f64) -> NormalizationMode {
+        match self {
+            LevelMode::Loudness => NormalizationMode::Loudness,
+            LevelMode::SamplePeak => NormalizationMode::Peak {
+                level: target_peak,
+                true_peak: false,
+            },
+            LevelMode::TruePeak => NormalizationMode::Peak {
+                level: target_peak,
+                true_peak: true,
+            },
+            LevelMode::Rms => NormalizationMode::Rms {
+                level: target_loudness,
+                a_weighted: false,
+            },
+            LevelMode::RmsA => NormalizationMode::Rms {
+                level: target_loudness,
+                a_weighted: true,
+            },
+            LevelMode::ReplayGain => NormalizationMode::ReplayGain { pre_amp: 0.0 },
+            LevelMode::Perceptual => NormalizationMode::Perceptual,
+        }
+    }
+}
+
+/// How the bitrate of uploaded MP3 sounds is chosen
+#[derive(Debug, poise::ChoiceParameter)]
+enum Mp3Bitrate {
+    #[name = "Constant (CBR)"]
+    Cbr,
+    #[name = "Average (ABR)"]
+    Abr,
+    #[name = "Variable (VBR)"]
+    Vbr,
+}
+
+impl Mp3Bitrate {
+    fn mp3_mode(&self) -> Mp3Mode {
+        match self {
+            Mp3Bitrate::Cbr => Mp3Mode::Cbr,
+            Mp3Bitrate::Abr => Mp3Mode::Abr,
+            Mp3Bitrate::Vbr => Mp3Mode::Vbr,
+        }
+    }
+}
+
 /// Normalize all soundboard sounds in the current guild
+// Every slash command option is a parameter
+#[allow(clippy::too_many_arguments)]
 #[poise::command(slash_command, guild_only)]
 async fn normalize(
     ctx: Context<'_>,
     #[description = "Target loudness in LUFS (default: -18.0)"] target_loudness: Option<f64>,
+    #[description = "Reach the target by limiting peaks instead of lowering gain (default: false)"]
+    limit: Option<bool>,
+    #[description = "How sound levels are measured (default: Loudness)"] mode: Option<LevelMode>,
+    #[description = "Normalize all sounds together, keeping their relative loudness (default: false)"]
+    group: Option<bool>,
+    #[description = "Only normalize outliers, towards the median loudness of this server (default: false)"]
+    relative: Option<bool>,
+    #[description = "Distance from the median in LU that is left alone (default: 2.0)"]
+    tolerance: Option<f64>,
+    #[description = "Lower the target of bass-heavy sounds by this many dB per dB of excess sub-bass (default: 0.0)"]
+    bass_penalty: Option<f64>,
+    #[description = "Repair inverted and lopsided stereo before measuring (default: false)"]
+    fix_stereo: Option<bool>,
+    #[description = "Reduce background noise of each sound before normalizing it (default: false)"]
+    denoise: Option<bool>,
+    #[description = "Even out quiet and loud parts of each sound before normalizing it (default: false)"]
+    leveler: Option<bool>,
+    #[description = "Speed up and trim sounds longer than the soundboard allows (default: false)"]
+    fit_duration: Option<bool>,
+    #[description = "How the bitrate of uploaded MP3 sounds is chosen (default: Constant)"]
+    mp3_mode: Option<Mp3Bitrate>,
+    #[description = "MP3 bitrate in kbps for constant and average bitrates (default: 192)"]
+    mp3_bitrate: Option<u32>,
+    #[description = "MP3 quality from 0, the best, to 9 for variable bitrates (default: 2)"]
+    mp3_vbr_quality: Option<u8>,
 ) -> Result<(), Error> {
     // Defer the response since this might take a while
     ctx.defer().await?;
 
     let guild_id = ctx.guild_id().unwrap().to_string();
 
+    if relative.unwrap_or(false) && group.unwrap_or(false) {
+        ctx.say("❌ Invalid options: sounds are normalized either as a group or relative to the median, not both")
+            .await?;
+        return Ok(());
+    }
+
+    let leveler = leveler.unwrap_or(false);
+    if leveler && (group.unwrap_or(false) || relative.unwrap_or(false)) {
+        ctx.say(
+            "❌ Invalid options: the leveler only works when each sound is normalized on its own",
+        )
+        .await?;
+        return Ok(());
+    }
+
     let target_loudness = target_loudness.unwrap_or(Normalizer::DEFAULT_TARGET_LOUDNESS);
     let target_peak = Normalizer::DEFAULT_TARGET_PEAK;
 
-    let audio_normalizer = match Normalizer::new(target_loudness, target_peak) {
-        Ok(normalizer) => normalizer,
+    let ceiling_mode = if limit.unwrap_or(false) {
+        CeilingMode::Limit
+    } else {
+        CeilingMode::ReduceGain
+    };
+
+    let mode = mode
+        .unwrap_or(LevelMode::Loudness)
+        .normalization_mode(target_loudness, target_peak);
+
+    let audio_normalizer = match Normalizer::new(target_loudness, target_peak)
+        .and_then(|normalizer| normalizer.with_mode(mode))
+        .and_then(|normalizer| normalizer.with_bass_penalty(bass_penalty.unwrap_or(0.0)))
+    {
+        Ok(normalizer) => normalizer.with_ceiling_mode(ceiling_mode),
         Err(e) => {
             let error_message = format!("❌ Invalid options: {}", e);
             ctx.say(error_message).await?;
@@ -54,6 +196,42 @@ async fn normalize(
         }
     };
 
+    // Noise would be brought up with a quiet sound, and inverted or lopsided channels can
+    // make a sound vanish for listeners on mono devices
+    let mut pre = ProcessorChain::new();
+    if denoise.unwrap_or(false) {
+        pre = pre.then(DeNoiser::default());
+    }
+    if fix_stereo.unwrap_or(false) {
+        pre = pre.then(StereoFixer::default());
+    }
+
+    if leveler {
+        match Leveler::new(
+            target_loudness,
+            Leveler::DEFAULT_MAX_BOOST,
+            Leveler::DEFAULT_MAX_CUT,
+        ) {
+            Ok(leveler) => pre = pre.then(leveler),
+            Err(e) => {
+                ctx.say(format!("❌ Invalid options: {}", e)).await?;
+                return Ok(());
+            }
+        }
+    }
+    if fit_duration.unwrap_or(false) {
+        pre = pre.then(DurationFitter::default());
+    }
+
+    let mp3_mode = mp3_mode.unwrap_or(Mp3Bitrate::Cbr).mp3_mode();
+    let mp3_options = match Mp3Options::new(mp3_mode, mp3_bitrate, mp3_vbr_quality) {
+        Ok(mp3_options) => mp3_options,
+        Err(e) => {
+            ctx.say(format!("❌ Invalid options: {}", e)).await?;
+            return Ok(());
+        }
+    };
+
     ctx.say("Starting sound normalization process...").await?;
 
     let sounds = ctx
@@ -63,18 +241,503 @@ async fn normalize(
         .await?;
 
     // Process all guild sounds
-    match ctx
+    let discord_client = &ctx
         .data()
         .discord_client
-        .process_guild_sounds(&audio_normalizer, sounds, &guild_id)
+        .as_ref()
+        .clone()
+        .with_mp3_options(mp3_options);
+    let result = if relative.unwrap_or(false) {
+        let tolerance = tolerance.unwrap_or(DiscordClient::DEFAULT_RELATIVE_TOLERANCE);
+        discord_client
+            .process_guild_sounds_relative(&pre, &audio_normalizer, sounds, &guild_id, tolerance)
+            .await
+    } else if group.unwrap_or(false) {
+        discord_client
+            .process_guild_sounds_group(&pre, &audio_normalizer, sounds, &guild_id)
+            .await
+    } else {
+        discord_client
+            .normalize_guild_sounds(&pre, &audio_normalizer, sounds, &guild_id)
+            .await
+    };
+
+    match result {
+        Ok(reports) => {
+            ctx.say(report_message(&reports)).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error normalizing sounds: {}", e))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reply to a normalization, naming the sounds that fell short of the target or were skipped
+fn report_message(reports: &[ClipReport]) -> String {
+    let normalized = reports
+        .iter()
+        .filter(|report| matches!(report.outcome, ClipOutcome::Normalized(_)))
+        .count();
+    let mut message = format!(
+        "✅ Normalized {} of {} soundboard sounds!",
+        normalized,
+        reports.len()
+    );
+
+    let lines = reports.iter().filter_map(|report| match &report.outcome {
+        ClipOutcome::Normalized(normalized) if !normalized.reached_target => Some(format!(
+            "\n⚠️ '{}' is {:.1} dB short of the {:.1} dB target",
+            report.name,
+            normalized.shortfall(),
+            normalized.target
+        )),
+        ClipOutcome::Normalized(_) | ClipOutcome::Processed => None,
+        ClipOutcome::Skipped(reason) => Some(format!("\n⏭️ '{}' skipped: {}", report.name, reason)),
+    });
+    push_lines(&mut message, lines);
+
+    message
+}
+
+/// Reply to a batch run through a processor, naming the sounds processed and skipped
+fn processed_message(action: &str, reports: &[ClipReport]) -> String {
+    let processed = reports
+        .iter()
+        .filter(|report| report.outcome == ClipOutcome::Processed)
+        .count();
+    let mut message = format!(
+        "✅ {} {} of {} soundboard sounds!",
+        action,
+        processed,
+        reports.len()
+    );
+
+    let lines = reports.iter().map(|report| match &report.outcome {
+        ClipOutcome::Skipped(reason) => format!("\n⏭️ '{}' skipped: {}", report.name, reason),
+        _ => format!("\n• {}", report.name),
+    });
+    push_lines(&mut message, lines);
+
+    message
+}
+
+/// Append lines to a reply for as long as it stays under [`MAX_MESSAGE_LEN`], marking
+/// where the rest were cut off
+fn push_lines(message: &mut String, lines: impl IntoIterator<Item = String>) {
+    for line in lines {
+        if message.len() + line.len() > MAX_MESSAGE_LEN {
+            message.push_str("\n…");
+            return;
+        }
+        message.push_str(&line);
+    }
+}
+
+/// Match all soundboard sounds in the current guild to a reference sound
+#[poise::command(slash_command, guild_only, rename = "match")]
+async fn match_sounds(
+    ctx: Context<'_>,
+    #[description = "Name of the soundboard sound to match"] reference: String,
+    #[description = "Also match the tone of the reference (default: false)"] tone: Option<bool>,
+) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    let mut sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    let Some(position) = sounds.iter().position(|sound| sound.name == reference) else {
+        ctx.say(format!("❌ No soundboard sound named '{}'", reference))
+            .await?;
+        return Ok(());
+    };
+    let reference_sound = sounds.remove(position);
+
+    ctx.say("Starting sound matching process...").await?;
+
+    let matcher = match discord_client
+        .reference_profile(&reference_sound)
+        .await
+        .and_then(|profile| {
+            Matcher::new(
+                profile,
+                tone.unwrap_or(false),
+                Normalizer::DEFAULT_TARGET_PEAK,
+            )
+        }) {
+        Ok(matcher) => matcher,
+        Err(e) => {
+            ctx.say(format!("❌ Error measuring reference: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Process every sound except the reference
+    match discord_client
+        .process_guild_sounds(&matcher, sounds, &guild_id)
         .await
     {
-        Ok(_) => {
-            ctx.say("✅ Successfully normalized all soundboard sounds!")
+        Ok(reports) => {
+            let action = format!("Matched to '{}'", reference);
+            ctx.say(processed_message(&action, &reports)).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error matching sounds: {}", e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Analyze all soundboard sounds in the current guild and report problem sounds
+#[poise::command(slash_command, guild_only)]
+async fn analyze(ctx: Context<'_>) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    let sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    let analyses = match discord_client.analyze_guild_sounds(sounds).await {
+        Ok(analyses) => analyses,
+        Err(e) => {
+            ctx.say(format!("❌ Error analyzing sounds: {}", e)).await?;
+            return Ok(());
+        }
+    };
+
+    let flagged: Vec<String> = analyses
+        .iter()
+        .filter(|(_, analysis)| !analysis.flags().is_empty())
+        .map(|(sound, analysis)| format!("\n• **{}**: {}", sound.name, analysis.flags().join(", ")))
+        .collect();
+
+    if flagged.is_empty() {
+        ctx.say(format!(
+            "✅ No problems found in {} sounds!",
+            analyses.len()
+        ))
+        .await?;
+    } else {
+        let mut message = format!("🔎 Flagged {} of {} sounds:", flagged.len(), analyses.len());
+        push_lines(&mut message, flagged);
+        ctx.say(message).await?;
+    }
+
+    Ok(())
+}
+
+/// Tame the 2-5 kHz band of harsh soundboard sounds in the current guild
+#[poise::command(slash_command, guild_only)]
+async fn deharsh(
+    ctx: Context<'_>,
+    #[description = "Share of energy in the 2-5 kHz band above which a sound is cut, in dB (default: -6.0)"]
+    threshold: Option<f64>,
+    #[description = "Deepest cut in dB (default: 9.0)"] max_cut: Option<f64>,
+) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let threshold = threshold.unwrap_or(DeHarsher::DEFAULT_THRESHOLD);
+    let max_cut = max_cut.unwrap_or(DeHarsher::DEFAULT_MAX_CUT);
+
+    let deharsher = match DeHarsher::new(threshold, max_cut) {
+        Ok(deharsher) => deharsher,
+        Err(e) => {
+            let error_message = format!("❌ Invalid options: {}", e);
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+
+    ctx.say("Looking for harsh sounds...").await?;
+
+    process_flagged_sounds(ctx, &deharsher, "harsh", |analysis| {
+        analysis.harshness.ratio > threshold
+    })
+    .await
+}
+
+/// Tame the sub-100 Hz energy of bass-heavy soundboard sounds in the current guild
+#[poise::command(slash_command, guild_only)]
+async fn tame_bass(
+    ctx: Context<'_>,
+    #[description = "Sub-100 Hz energy relative to the K-weighted total above which a sound is cut, in dB (default: -3.0)"]
+    threshold: Option<f64>,
+    #[description = "Deepest cut in dB (default: 12.0)"] max_cut: Option<f64>,
+) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let threshold = threshold.unwrap_or(BassTamer::DEFAULT_THRESHOLD);
+    let max_cut = max_cut.unwrap_or(BassTamer::DEFAULT_MAX_CUT);
+
+    let tamer = match BassTamer::new(threshold, max_cut) {
+        Ok(tamer) => tamer,
+        Err(e) => {
+            let error_message = format!("❌ Invalid options: {}", e);
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+
+    ctx.say("Looking for bass-heavy sounds...").await?;
+
+    process_flagged_sounds(ctx, &tamer, "bass-heavy", |analysis| {
+        analysis.bass.ratio > threshold
+    })
+    .await
+}
+
+/// Repair soundboard sounds in the current guild that cancel in mono or are lopsided left/right
+#[poise::command(slash_command, guild_only)]
+async fn fix_stereo(
+    ctx: Context<'_>,
+    #[description = "Left/right imbalance in dB that is left alone (default: 3.0)"]
+    max_imbalance: Option<f64>,
+    #[description = "Left/right imbalance in dB above which a sound is folded to mono (default: 20.0)"]
+    fold_imbalance: Option<f64>,
+) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let fixer = match StereoFixer::new(
+        max_imbalance.unwrap_or(StereoFixer::DEFAULT_MAX_IMBALANCE),
+        fold_imbalance.unwrap_or(StereoFixer::DEFAULT_FOLD_IMBALANCE),
+    ) {
+        Ok(fixer) => fixer,
+        Err(e) => {
+            let error_message = format!("❌ Invalid options: {}", e);
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+
+    ctx.say("Looking for out-of-phase and lopsided sounds...")
+        .await?;
+
+    process_flagged_sounds(ctx, &fixer, "out-of-phase or lopsided", |analysis| {
+        analysis
+            .stereo
+            .is_some_and(|stereo| !fixer.plan(&stereo).is_empty())
+    })
+    .await
+}
+
+/// Remove mains hum from the soundboard sounds of the current guild
+#[poise::command(slash_command, guild_only)]
+async fn dehum(ctx: Context<'_>) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    ctx.say("Looking for hum...").await?;
+
+    let sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    let (flagged, removals): (Vec<_>, HashMap<_, _>) = discord_client
+        .analyze_guild_sounds(sounds)
+        .await?
+        .into_iter()
+        .filter_map(|(sound, analysis)| {
+            let hum = analysis.hum?;
+            let partials: Vec<String> = hum
+                .partials
+                .iter()
+                .map(|partial| format!("{:.0} Hz", partial.frequency))
+                .collect();
+            let removal = format!("{} Hz hum at {}", hum.mains, partials.join(", "));
+            let removal = (sound.name.clone(), removal);
+            Some((sound, removal))
+        })
+        .unzip();
+
+    if flagged.is_empty() {
+        ctx.say("✅ No hum found!").await?;
+        return Ok(());
+    }
+
+    match discord_client
+        .process_guild_sounds(&HumRemover::default(), flagged, &guild_id)
+        .await
+    {
+        Ok(reports) => {
+            let removed = reports
+                .iter()
+                .filter(|report| report.outcome == ClipOutcome::Processed)
+                .count();
+            let mut message = format!(
+                "✅ Removed hum from {} of {} sounds:",
+                removed,
+                reports.len()
+            );
+            let lines = reports.iter().map(|report| match &report.outcome {
+                ClipOutcome::Skipped(reason) => {
+                    format!("\n⏭️ '{}' skipped: {}", report.name, reason)
+                }
+                _ => format!("\n• **{}**: {}", report.name, removals[&report.name]),
+            });
+            push_lines(&mut message, lines);
+            ctx.say(message).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error processing sounds: {}", e))
                 .await?;
         }
+    }
+
+    Ok(())
+}
+
+/// Reduce the background noise of all soundboard sounds in the current guild
+#[poise::command(slash_command, guild_only)]
+async fn denoise(
+    ctx: Context<'_>,
+    #[description = "Deepest reduction in dB (default: 12.0)"] strength: Option<f64>,
+    #[description = "How much the reduction follows the previous frame, between 0 and 1 (default: 0.5)"]
+    smoothing: Option<f64>,
+) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    let denoiser = match DeNoiser::new(
+        strength.unwrap_or(DeNoiser::DEFAULT_STRENGTH),
+        smoothing.unwrap_or(DeNoiser::DEFAULT_SMOOTHING),
+    ) {
+        Ok(denoiser) => denoiser,
         Err(e) => {
-            ctx.say(format!("❌ Error normalizing sounds: {}", e))
+            let error_message = format!("❌ Invalid options: {}", e);
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+
+    ctx.say("Starting noise reduction...").await?;
+
+    let sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    match discord_client
+        .process_guild_sounds(&denoiser, sounds, &guild_id)
+        .await
+    {
+        Ok(reports) => {
+            ctx.say(processed_message("Denoised", &reports)).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error processing sounds: {}", e))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Repair clicks and pops in the soundboard sounds of the current guild
+#[poise::command(slash_command, guild_only)]
+async fn declick(ctx: Context<'_>) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    ctx.say("Looking for clicks...").await?;
+
+    let sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    let (flagged, clicks): (Vec<_>, HashMap<_, _>) = discord_client
+        .analyze_guild_sounds(sounds)
+        .await?
+        .into_iter()
+        .filter(|(_, analysis)| analysis.clicks.total() > 0)
+        .map(|(sound, analysis)| {
+            let clicks = (sound.name.clone(), analysis.clicks.total());
+            (sound, clicks)
+        })
+        .unzip();
+
+    if flagged.is_empty() {
+        ctx.say("✅ No clicks found!").await?;
+        return Ok(());
+    }
+
+    match discord_client
+        .process_guild_sounds(&DeClicker::default(), flagged, &guild_id)
+        .await
+    {
+        Ok(reports) => {
+            let repaired = reports
+                .iter()
+                .filter(|report| report.outcome == ClipOutcome::Processed)
+                .count();
+            let mut message = format!(
+                "✅ Repaired clicks in {} of {} sounds:",
+                repaired,
+                reports.len()
+            );
+            let lines = reports.iter().map(|report| match &report.outcome {
+                ClipOutcome::Skipped(reason) => {
+                    format!("\n⏭️ '{}' skipped: {}", report.name, reason)
+                }
+                _ => format!(
+                    "\n• **{}**: {} clicks repaired",
+                    report.name, clicks[&report.name]
+                ),
+            });
+            push_lines(&mut message, lines);
+            ctx.say(message).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error processing sounds: {}", e))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the processor over the guild's sounds that the predicate flags and report them
+async fn process_flagged_sounds(
+    ctx: Context<'_>,
+    processor: &dyn AudioProcessor,
+    problem: &str,
+    is_flagged: impl Fn(&ClipAnalysis) -> bool,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    let sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    let flagged: Vec<_> = discord_client
+        .analyze_guild_sounds(sounds)
+        .await?
+        .into_iter()
+        .filter(|(_, analysis)| is_flagged(analysis))
+        .map(|(sound, _)| sound)
+        .collect();
+
+    if flagged.is_empty() {
+        ctx.say(format!("✅ No {} sounds found!", problem)).await?;
+        return Ok(());
+    }
+
+    // Only the flagged sounds are processed
+    match discord_client
+        .process_guild_sounds(processor, flagged, &guild_id)
+        .await
+    {
+        Ok(reports) => {
+            ctx.say(processed_message("Tamed", &reports)).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error processing sounds: {}", e))
                 .await?;
         }
     }
@@ -89,6 +752,8 @@ async fn limit(
     #[description = "Threshold in dB (default: -1.0)"] threshold: Option<f64>,
     #[description = "Release time in ms (default: 50.0)"] release_time: Option<f64>,
     #[description = "Lookahead time in ms (default: 5)"] lookahead: Option<usize>,
+    #[description = "Soften the attack of punchy sounds first, keeping their loudness (default: false)"]
+    soften_attack: Option<bool>,
 ) -> Result<(), Error> {
     // Defer the response since this might take a while
     ctx.defer().await?;
@@ -108,6 +773,15 @@ async fn limit(
         }
     };
 
+    // Softened attacks leave the limiter less to catch
+    let processor = if soften_attack.unwrap_or(false) {
+        ProcessorChain::new()
+            .then(TransientShaper::soften_attack())
+            .then(limiter)
+    } else {
+        ProcessorChain::new().then(limiter)
+    };
+
     ctx.say("Starting sound limiting process...").await?;
 
     let sounds = ctx
@@ -120,16 +794,73 @@ async fn limit(
     match ctx
         .data()
         .discord_client
-        .process_guild_sounds(&limiter, sounds, &guild_id)
+        .process_guild_sounds(&processor, sounds, &guild_id)
+        .await
+    {
+        Ok(reports) => {
+            ctx.say(processed_message("Limited", &reports)).await?;
+        }
+        Err(e) => {
+            ctx.say(format!("❌ Error limiting sounds: {}", e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a pitched-up or pitched-down copy of a soundboard sound, keeping the original
+#[poise::command(slash_command, guild_only)]
+async fn pitch_shift(
+    ctx: Context<'_>,
+    #[description = "Name of the soundboard sound to shift"] sound: String,
+    #[description = "Semitones to shift by, negative to shift down"] semitones: i32,
+    #[description = "Cents to shift by on top of the semitones (default: 0)"] cents: Option<f64>,
+    #[description = "Name of the new sound (default: the original name with the shift)"]
+    name: Option<String>,
+) -> Result<(), Error> {
+    // Defer the response since this might take a while
+    ctx.defer().await?;
+
+    let guild_id = ctx.guild_id().unwrap().to_string();
+    let discord_client = &ctx.data().discord_client;
+
+    let shifter = match PitchShifter::new(semitones, cents.unwrap_or(0.0)) {
+        Ok(shifter) => shifter,
+        Err(e) => {
+            ctx.say(format!("❌ Invalid options: {}", e)).await?;
+            return Ok(());
+        }
+    };
+
+    let sounds = discord_client.get_guild_sounds(&guild_id).await?;
+    let Some(source) = sounds.iter().find(|s| s.name == sound) else {
+        ctx.say(format!("❌ No soundboard sound named '{}'", sound))
+            .await?;
+        return Ok(());
+    };
+
+    let name = name.unwrap_or_else(|| DiscordClient::variant_name(&sound, &shifter.label()));
+    if sounds.iter().any(|s| s.name == name) {
+        ctx.say(format!(
+            "❌ There is already a soundboard sound named '{}'",
+            name
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    ctx.say(format!("Shifting '{}' by {}...", sound, shifter.label()))
+        .await?;
+
+    match discord_client
+        .create_sound_variant(&shifter, source, &guild_id, &name)
         .await
     {
         Ok(_) => {
-            ctx.say("✅ Successfully limited all soundboard sounds!")
-                .await?;
+            ctx.say(format!("✅ Added '{}'!", name)).await?;
         }
         Err(e) => {
-            ctx.say(format!("❌ Error limiting sounds: {}", e))
-                .await?;
+            ctx.say(format!("❌ Error shifting sound: {}", e)).await?;
         }
     }
 
@@ -156,7 +887,19 @@ async fn main() {
         Arc::new(DiscordClient::new(&token).expect("Failed to create Discord client"));
 
     let options = poise::FrameworkOptions {
-        commands: vec![normalize(), limit()],
+        commands: vec![
+            normalize(),
+            match_sounds(),
+            analyze(),
+            deharsh(),
+            tame_bass(),
+            fix_stereo(),
+            declick(),
+            denoise(),
+            dehum(),
+            limit(),
+            pitch_shift(),
+        ],
         on_error: |error| Box::pin(on_error(error)),
         ..Default::default()
     };
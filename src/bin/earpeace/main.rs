@@ -1,18 +1,35 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
 use earpeace::audio_file::AudioFile;
-use earpeace::audio_file::Mp3File;
-use earpeace::dsp::decode_file;
-use earpeace::dsp::AudioProcessor;
+use earpeace::audio_file::{Mp3File, Mp3Mode, Mp3Options, Mp3Stereo, WavFile};
+use earpeace::audio_flac::FlacFile;
+use earpeace::audio_metadata::Metadata;
+use earpeace::dsp::{decode_file, decode_file_with_metadata, AudioProcessor, ProcessorChain};
 use env_logger::{Builder, Target};
-use log::{info, LevelFilter};
+use log::{info, warn, LevelFilter};
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use symphonia::core::formats::Track;
 
-use earpeace::audio_normalizer::Normalizer;
-use earpeace::discord::DiscordClient;
+use earpeace::audio_analyzer::ClipAnalysis;
+use earpeace::audio_bass_tamer::BassTamer;
+use earpeace::audio_declicker::DeClicker;
+use earpeace::audio_deharsher::DeHarsher;
+use earpeace::audio_denoiser::DeNoiser;
+use earpeace::audio_duration_fitter::DurationFitter;
+use earpeace::audio_hum_remover::HumRemover;
+use earpeace::audio_leveler::Leveler;
+use earpeace::audio_limiter::Limiter;
+use earpeace::audio_matcher::{Matcher, ReferenceProfile};
+use earpeace::audio_normalizer::{
+    CeilingMode, ClipOutcome, ClipReport, GroupMember, NormalizationMode, Normalizer,
+};
+use earpeace::audio_pitch_shifter::PitchShifter;
+use earpeace::audio_stereo_fixer::StereoFixer;
+use earpeace::audio_transient_shaper::TransientShaper;
+use earpeace::discord::{DiscordClient, SoundboardSound, UploadFormat};
 
 #[derive(Parser)]
 #[command(
@@ -61,6 +78,242 @@ enum Commands {
             allow_negative_numbers = true
         )]
         peak_ceiling: f64,
+
+        /// Reach the target loudness by limiting peaks instead of reducing gain
+        #[arg(long)]
+        limit: bool,
+
+        /// How clip levels are measured (default: loudness)
+        #[arg(short = 'm', long, value_enum, default_value_t = ModeArg::Loudness)]
+        mode: ModeArg,
+
+        /// Target level in dB for the peak and rms modes, or the ReplayGain pre-amp
+        /// (default: the peak ceiling for peak modes, the target loudness for rms modes, 0 for replay-gain)
+        #[arg(long, allow_negative_numbers = true)]
+        level: Option<f64>,
+
+        /// Normalize all clips together with one shared gain, keeping their relative loudness
+        #[arg(long, conflicts_with = "relative")]
+        group: bool,
+
+        /// Normalize outliers to the median loudness of the guild's soundboard
+        #[arg(long)]
+        relative: bool,
+
+        /// Distance from the guild median in LU within which sounds are left alone (default: 2)
+        #[arg(long, default_value_t = DiscordClient::DEFAULT_RELATIVE_TOLERANCE)]
+        tolerance: f64,
+
+        /// Lower the target of bass-heavy clips by this many dB per dB of excess sub-bass (default: 0)
+        #[arg(long, default_value_t = 0.0)]
+        bass_penalty: f64,
+
+        /// Repair inverted and lopsided stereo clips before they are measured
+        #[arg(long)]
+        fix_stereo: bool,
+
+        /// Reduce background noise before normalizing, so it is not brought up with the clip
+        #[arg(long)]
+        denoise: bool,
+
+        /// Ride the gain of uneven clips towards the target before normalizing them
+        #[arg(long, conflicts_with_all = ["group", "relative"])]
+        leveler: bool,
+
+        /// Speed up and trim clips longer than the soundboard allows before normalizing them
+        #[arg(long)]
+        fit_duration: bool,
+
+        /// Longest clip in seconds when fitting durations (default: 5.2)
+        #[arg(long, default_value_t = DurationFitter::DISCORD_MAX_DURATION)]
+        max_duration: f64,
+
+        /// Fastest a clip may be sped up before it is trimmed instead (default: 1.15)
+        #[arg(long, default_value_t = DurationFitter::DEFAULT_MAX_SPEED_UP)]
+        max_speed_up: f64,
+
+        /// Store the EBU R128 loudness of WAV files in a BWF bext chunk
+        #[arg(long)]
+        bext: bool,
+
+        /// File format of sounds uploaded to Discord (default: mp3)
+        #[arg(long, value_enum, default_value_t = UploadFormatArg::Mp3)]
+        upload_format: UploadFormatArg,
+
+        /// How the bitrate of MP3 files is chosen (default: cbr)
+        #[arg(long, value_enum, default_value_t = Mp3ModeArg::Cbr)]
+        mp3_mode: Mp3ModeArg,
+
+        /// MP3 bitrate in kbps for CBR and ABR (default: 192)
+        #[arg(long)]
+        bitrate: Option<u32>,
+
+        /// MP3 VBR quality from 0, the best, to 9 (default: 2)
+        #[arg(long)]
+        vbr_quality: Option<u8>,
+
+        /// How the channels of stereo MP3 files are coded (default: joint)
+        #[arg(long, value_enum, default_value_t = Mp3StereoArg::Joint)]
+        stereo: Mp3StereoArg,
+
+        /// Cut MP3 files above this frequency in Hz instead of LAME's choice
+        #[arg(long)]
+        lowpass: Option<u32>,
+
+        /// MP3 encoder quality from 0, the best and slowest, to 9 (default: 0)
+        #[arg(long, default_value_t = 0)]
+        mp3_quality: u8,
+    },
+    /// Match audio files to the loudness and tone of a reference clip
+    Match {
+        /// Reference clip to match
+        #[arg(short, long)]
+        reference: PathBuf,
+
+        /// Directory containing local audio files to match
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Also match the long-term spectral balance of the reference
+        #[arg(long)]
+        tone: bool,
+
+        /// Target peak output in dB (default: -1)
+        #[arg(
+            short = 'p',
+            long = "peak-ceiling",
+            default_value = "-1.0",
+            allow_negative_numbers = true
+        )]
+        peak_ceiling: f64,
+    },
+    /// Analyze audio files and flag problem clips
+    Analyze {
+        /// Directory containing local audio files to analyze
+        #[arg(short, long)]
+        input_dir: Option<String>,
+    },
+    /// Tame the 2-5 kHz band of harsh clips with a dynamic EQ
+    Deharsh {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Share of energy in the 2-5 kHz band above which a clip is cut, in dB (default: -6)
+        #[arg(
+            short = 't',
+            long,
+            default_value_t = DeHarsher::DEFAULT_THRESHOLD,
+            allow_negative_numbers = true
+        )]
+        threshold: f64,
+
+        /// Deepest cut in dB (default: 9)
+        #[arg(long, default_value_t = DeHarsher::DEFAULT_MAX_CUT)]
+        max_cut: f64,
+    },
+    /// Tame the sub-100 Hz energy of bass-heavy clips with a dynamic low shelf
+    TameBass {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Sub-100 Hz energy relative to the K-weighted total above which a clip is cut, in dB (default: -3)
+        #[arg(
+            short = 't',
+            long,
+            default_value_t = BassTamer::DEFAULT_THRESHOLD,
+            allow_negative_numbers = true
+        )]
+        threshold: f64,
+
+        /// Deepest cut in dB (default: 12)
+        #[arg(long, default_value_t = BassTamer::DEFAULT_MAX_CUT)]
+        max_cut: f64,
+    },
+    /// Repair stereo clips that cancel in mono or are lopsided left/right
+    FixStereo {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Left/right imbalance in dB that is left alone (default: 3)
+        #[arg(long, default_value_t = StereoFixer::DEFAULT_MAX_IMBALANCE)]
+        max_imbalance: f64,
+
+        /// Left/right imbalance in dB above which a clip is folded to mono (default: 20)
+        #[arg(long, default_value_t = StereoFixer::DEFAULT_FOLD_IMBALANCE)]
+        fold_imbalance: f64,
+    },
+    /// Reduce background noise with a noise profile learned from each clip
+    Denoise {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Deepest reduction in dB (default: 12)
+        #[arg(long, default_value_t = DeNoiser::DEFAULT_STRENGTH)]
+        strength: f64,
+
+        /// How much the reduction follows the previous frame, between 0 and 1 (default: 0.5)
+        #[arg(long, default_value_t = DeNoiser::DEFAULT_SMOOTHING)]
+        smoothing: f64,
+
+        /// Start in seconds of a stretch holding only noise (default: the quietest frames)
+        #[arg(long, requires = "noise_end")]
+        noise_start: Option<f64>,
+
+        /// End in seconds of a stretch holding only noise
+        #[arg(long, requires = "noise_start")]
+        noise_end: Option<f64>,
+    },
+    /// Notch out 50/60 Hz mains hum and its harmonics
+    Dehum {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+    },
+    /// Soften or emphasise the attack and sustain of clips, then limit their peaks
+    Shape {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Gain at onsets, between -1 (softer) and 1 (punchier) (default: 0)
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        attack: f64,
+
+        /// Gain in the decay, between -1 (drier) and 1 (fuller) (default: 0)
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        sustain: f64,
+
+        /// Use the preset that takes the startle out of punchy sound effects
+        #[arg(long, conflicts_with_all = ["attack", "sustain"])]
+        soften_attack: bool,
+    },
+    /// Repair clicks and pops in clips
+    Declick {
+        /// Directory containing local audio files to process
+        #[arg(short, long)]
+        input_dir: Option<String>,
+    },
+    /// Make pitched-up or pitched-down variants of clips, keeping the originals
+    Pitch {
+        /// Directory containing local audio files to make variants of
+        #[arg(short, long)]
+        input_dir: Option<String>,
+
+        /// Name of the soundboard sound to make a variant of
+        #[arg(long, required_unless_present = "input_dir")]
+        sound: Option<String>,
+
+        /// Semitones to shift by, negative to shift down
+        #[arg(long, allow_negative_numbers = true)]
+        semitones: i32,
+
+        /// Cents to shift by on top of the semitones (default: 0)
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        cents: f64,
     },
     /// List all sounds in the Discord soundboard
     Ls,
@@ -72,6 +325,52 @@ enum Commands {
     },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum ModeArg {
+    /// EBU R128 integrated loudness
+    Loudness,
+    /// Sample peak level
+    SamplePeak,
+    /// True peak level
+    TruePeak,
+    /// RMS level
+    Rms,
+    /// A-weighted RMS level
+    RmsA,
+    /// ReplayGain 2.0 reference level
+    ReplayGain,
+    /// Perceived loudness, for sub-second clips
+    Perceptual,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UploadFormatArg {
+    /// MP3, 192 kbps CBR unless the MP3 options say otherwise
+    Mp3,
+    /// Ogg Opus, much smaller (needs the opus feature)
+    Opus,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mp3ModeArg {
+    /// Constant bitrate
+    Cbr,
+    /// Average bitrate
+    Abr,
+    /// Variable bitrate following a quality
+    Vbr,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mp3StereoArg {
+    /// Left/right or mid/side frame by frame
+    Joint,
+    /// Always left/right
+    Separate,
+    /// Always mid/side
+    Forced,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load .env file before parsing CLI args
@@ -85,42 +384,319 @@ async fn main() -> Result<()> {
             input_dir,
             target_loudness,
             peak_ceiling,
-        } => match (input_dir, &cli.discord_token, &cli.guild_id) {
-            (Some(dir), None, None) => {
-                let audio = Normalizer::new(*target_loudness, *peak_ceiling)?;
-                process_directory(&audio, dir)?;
-            }
-            (None, Some(token), Some(guild)) => {
-                let audio = Normalizer::new(*target_loudness, *peak_ceiling)?;
-                let discord_client = DiscordClient::new(token)?;
-                let sounds = discord_client.get_guild_sounds(guild).await?;
-                discord_client
-                    .process_guild_sounds(&audio, sounds, guild)
-                    .await?;
+            limit,
+            mode,
+            level,
+            group,
+            relative,
+            tolerance,
+            bass_penalty,
+            fix_stereo,
+            denoise,
+            leveler,
+            fit_duration,
+            max_duration,
+            max_speed_up,
+            bext,
+            upload_format,
+            mp3_mode,
+            bitrate,
+            vbr_quality,
+            stereo,
+            lowpass,
+            mp3_quality,
+        } => {
+            let sounds_mode = if *relative {
+                SoundsMode::Relative(*tolerance)
+            } else if *group {
+                SoundsMode::Group
+            } else {
+                SoundsMode::Each
+            };
+
+            let audio = Normalizer::new(*target_loudness, *peak_ceiling)?
+                .with_mode(normalization_mode(
+                    *mode,
+                    *level,
+                    *target_loudness,
+                    *peak_ceiling,
+                ))?
+                .with_ceiling_mode(ceiling_mode(*limit))
+                .with_bass_penalty(*bass_penalty)?;
+            let mp3_options = mp3_options(
+                *mp3_mode,
+                *bitrate,
+                *vbr_quality,
+                *stereo,
+                *lowpass,
+                *mp3_quality,
+            )?;
+            let output = Output {
+                loudness_metadata: *bext,
+                mp3_options,
+            };
+            let mut pre = ProcessorChain::new();
+            if *denoise {
+                pre = pre.then(DeNoiser::default());
+            }
+            if *fix_stereo {
+                pre = pre.then(StereoFixer::default());
+            }
+            if *leveler {
+                pre = pre.then(Leveler::new(
+                    *target_loudness,
+                    Leveler::DEFAULT_MAX_BOOST,
+                    Leveler::DEFAULT_MAX_CUT,
+                )?);
+            }
+            if *fit_duration {
+                pre = pre.then(DurationFitter::new(*max_duration, *max_speed_up)?);
             }
-            (None, token_opt, guild_opt) => {
-                let token = token_opt
-                    .clone()
-                    .or_else(|| env::var("DISCORD_TOKEN").ok())
-                    .ok_or_else(|| anyhow::anyhow!("Discord token not provided in CLI or .env"))?;
 
-                let guild = guild_opt
-                    .clone()
-                    .or_else(|| env::var("GUILD_ID").ok())
-                    .ok_or_else(|| anyhow::anyhow!("Guild ID not provided in CLI or .env"))?;
+            let reports = match (input_dir, &cli.discord_token, &cli.guild_id) {
+                (Some(dir), None, None) => {
+                    if *relative {
+                        return Err(anyhow::anyhow!(
+                            "Relative normalization needs a Discord soundboard to measure"
+                        ));
+                    } else if *group {
+                        process_directory_group(&pre, &audio, dir, &output)?
+                    } else {
+                        normalize_directory(&pre, &audio, dir, &output)?
+                    }
+                }
+                (None, Some(token), Some(guild)) => {
+                    let discord_client = discord_client(token, *upload_format, mp3_options)?;
+                    let sounds = discord_client.get_guild_sounds(guild).await?;
+                    process_sounds(&discord_client, &pre, &audio, sounds_mode, sounds, guild)
+                        .await?
+                }
+                (None, token_opt, guild_opt) => {
+                    let token = token_opt
+                        .clone()
+                        .or_else(|| env::var("DISCORD_TOKEN").ok())
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("Discord token not provided in CLI or .env")
+                        })?;
 
-                let discord_client = DiscordClient::new(&token)?;
-                let audio = Normalizer::new(*target_loudness, *peak_ceiling)?;
-                let sounds = discord_client.get_guild_sounds(&guild).await?;
-                discord_client
-                    .process_guild_sounds(&audio, sounds, &guild)
-                    .await?;
+                    let guild = guild_opt
+                        .clone()
+                        .or_else(|| env::var("GUILD_ID").ok())
+                        .ok_or_else(|| anyhow::anyhow!("Guild ID not provided in CLI or .env"))?;
+
+                    let discord_client = discord_client(&token, *upload_format, mp3_options)?;
+                    let sounds = discord_client.get_guild_sounds(&guild).await?;
+                    process_sounds(&discord_client, &pre, &audio, sounds_mode, sounds, &guild)
+                        .await?
+                }
+                _ => {
+                    info!("Please provide either an input directory (-i) or Discord credentials");
+                    std::process::exit(1);
+                }
+            };
+
+            print_reports(&reports);
+        }
+        Commands::Match {
+            reference,
+            input_dir,
+            tone,
+            peak_ceiling,
+        } => {
+            let profile = ReferenceProfile::from_file(reference)?;
+            info!(
+                "Reference {} measures {:.1} LUFS",
+                reference.display(),
+                profile.loudness
+            );
+            let matcher = Matcher::new(profile, *tone, *peak_ceiling)?;
+
+            match input_dir {
+                Some(dir) => process_directory(&matcher, dir, &Output::default())?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    let discord_client = DiscordClient::new(&token)?;
+                    let sounds = discord_client.get_guild_sounds(&guild).await?;
+                    discord_client
+                        .process_guild_sounds(&matcher, sounds, &guild)
+                        .await?;
+                }
+            }
+        }
+        Commands::Analyze { input_dir } => {
+            let analyses = match input_dir {
+                Some(dir) => analyze_directory(dir)?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    let discord_client = DiscordClient::new(&token)?;
+                    let sounds = discord_client.get_guild_sounds(&guild).await?;
+                    discord_client
+                        .analyze_guild_sounds(sounds)
+                        .await?
+                        .into_iter()
+                        .map(|(sound, analysis)| (sound.name, analysis))
+                        .collect()
+                }
+            };
+
+            print_analysis(&analyses);
+        }
+        Commands::Deharsh {
+            input_dir,
+            threshold,
+            max_cut,
+        } => {
+            let deharsher = DeHarsher::new(*threshold, *max_cut)?;
+            let is_harsh = |analysis: &ClipAnalysis| analysis.harshness.ratio > *threshold;
+
+            match input_dir {
+                Some(dir) => process_flagged_directory(&deharsher, dir, is_harsh)?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    process_flagged_sounds(&deharsher, &token, &guild, is_harsh).await?;
+                }
             }
-            _ => {
-                info!("Please provide either an input directory (-i) or Discord credentials");
-                std::process::exit(1);
+        }
+        Commands::TameBass {
+            input_dir,
+            threshold,
+            max_cut,
+        } => {
+            let tamer = BassTamer::new(*threshold, *max_cut)?;
+            let is_heavy = |analysis: &ClipAnalysis| analysis.bass.ratio > *threshold;
+
+            match input_dir {
+                Some(dir) => process_flagged_directory(&tamer, dir, is_heavy)?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    process_flagged_sounds(&tamer, &token, &guild, is_heavy).await?;
+                }
             }
-        },
+        }
+        Commands::FixStereo {
+            input_dir,
+            max_imbalance,
+            fold_imbalance,
+        } => {
+            let fixer = StereoFixer::new(*max_imbalance, *fold_imbalance)?;
+            let needs_fix = |analysis: &ClipAnalysis| {
+                analysis
+                    .stereo
+                    .is_some_and(|stereo| !fixer.plan(&stereo).is_empty())
+            };
+
+            match input_dir {
+                Some(dir) => process_flagged_directory(&fixer, dir, needs_fix)?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    process_flagged_sounds(&fixer, &token, &guild, needs_fix).await?;
+                }
+            }
+        }
+        Commands::Denoise {
+            input_dir,
+            strength,
+            smoothing,
+            noise_start,
+            noise_end,
+        } => {
+            let denoiser = match (noise_start, noise_end) {
+                (Some(start), Some(end)) => {
+                    DeNoiser::new(*strength, *smoothing)?.with_noise_range(*start, *end)?
+                }
+                _ => DeNoiser::new(*strength, *smoothing)?,
+            };
+
+            match input_dir {
+                Some(dir) => process_directory(&denoiser, dir, &Output::default())?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    let discord_client = DiscordClient::new(&token)?;
+                    let sounds = discord_client.get_guild_sounds(&guild).await?;
+                    discord_client
+                        .process_guild_sounds(&denoiser, sounds, &guild)
+                        .await?;
+                }
+            }
+        }
+        Commands::Dehum { input_dir } => {
+            let remover = HumRemover::default();
+            let has_hum = |analysis: &ClipAnalysis| analysis.hum.is_some();
+
+            match input_dir {
+                Some(dir) => process_flagged_directory(&remover, dir, has_hum)?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    process_flagged_sounds(&remover, &token, &guild, has_hum).await?;
+                }
+            }
+        }
+        Commands::Shape {
+            input_dir,
+            attack,
+            sustain,
+            soften_attack,
+        } => {
+            let shaper = if *soften_attack {
+                TransientShaper::soften_attack()
+            } else {
+                TransientShaper::new(*attack, *sustain)?
+            };
+            let chain = ProcessorChain::new().then(shaper).then(Limiter::default());
+
+            match input_dir {
+                Some(dir) => process_directory(&chain, dir, &Output::default())?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    let discord_client = DiscordClient::new(&token)?;
+                    let sounds = discord_client.get_guild_sounds(&guild).await?;
+                    discord_client
+                        .process_guild_sounds(&chain, sounds, &guild)
+                        .await?;
+                }
+            }
+        }
+        Commands::Declick { input_dir } => {
+            let declicker = DeClicker::default();
+            let has_clicks = |analysis: &ClipAnalysis| analysis.clicks.total() > 0;
+
+            match input_dir {
+                Some(dir) => process_flagged_directory(&declicker, dir, has_clicks)?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    process_flagged_sounds(&declicker, &token, &guild, has_clicks).await?;
+                }
+            }
+        }
+        Commands::Pitch {
+            input_dir,
+            sound,
+            semitones,
+            cents,
+        } => {
+            let shifter = PitchShifter::new(*semitones, *cents)?;
+
+            match input_dir {
+                Some(dir) => process_directory_variants(&shifter, dir, &shifter.label())?,
+                None => {
+                    let (token, guild) = credentials(cli.discord_token, cli.guild_id)?;
+                    let name = sound
+                        .as_deref()
+                        .ok_or_else(|| anyhow::anyhow!("Name the sound to shift with --sound"))?;
+                    let discord_client = DiscordClient::new(&token)?;
+                    let sounds = discord_client.get_guild_sounds(&guild).await?;
+                    let source = sounds
+                        .iter()
+                        .find(|s| s.name == name)
+                        .ok_or_else(|| anyhow::anyhow!("No soundboard sound named '{}'", name))?;
+
+                    let variant = DiscordClient::variant_name(name, &shifter.label());
+                    discord_client
+                        .create_sound_variant(&shifter, source, &guild, &variant)
+                        .await?;
+                }
+            }
+        }
         Commands::Ls => {
             let token = cli
                 .discord_token
@@ -186,32 +762,496 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn process_directory(processor: &dyn AudioProcessor, dir: &str) -> Result<()> {
+/// How the sounds of a guild are normalized relative to each other
+#[derive(Clone, Copy)]
+enum SoundsMode {
+    /// Every sound on its own
+    Each,
+    /// All sounds with one shared gain
+    Group,
+    /// Outliers to the guild median, with a tolerance in LU
+    Relative(f64),
+}
+
+async fn process_sounds(
+    discord_client: &DiscordClient,
+    pre: &dyn AudioProcessor,
+    normalizer: &Normalizer,
+    mode: SoundsMode,
+    sounds: Vec<SoundboardSound>,
+    guild_id: &str,
+) -> Result<Vec<ClipReport>> {
+    match mode {
+        SoundsMode::Each => {
+            discord_client
+                .normalize_guild_sounds(pre, normalizer, sounds, guild_id)
+                .await
+        }
+        SoundsMode::Group => {
+            discord_client
+                .process_guild_sounds_group(pre, normalizer, sounds, guild_id)
+                .await
+        }
+        SoundsMode::Relative(tolerance) => {
+            discord_client
+                .process_guild_sounds_relative(pre, normalizer, sounds, guild_id, tolerance)
+                .await
+        }
+    }
+}
+
+/// Process every file in the directory with `processor`, overwriting it
+fn process_directory(processor: &dyn AudioProcessor, dir: &str, output: &Output) -> Result<()> {
+    for path in audio_files(dir)? {
+        info!("Processing file: {}", path.display());
+        let (samples, track, metadata) = decode_file_with_metadata(&path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let processed_samples = processor.process(&samples, channels, sample_rate)?;
+
+        let _ =
+            output
+                .file_for(&path, &track, metadata)
+                .write(&processed_samples, &track, &path)?;
+    }
+
+    Ok(())
+}
+
+/// Write processed copies of the files in a directory next to them, named with `suffix`
+///
+/// Files already named with `suffix` are variants from an earlier run and are skipped.
+fn process_directory_variants(
+    processor: &dyn AudioProcessor,
+    dir: &str,
+    suffix: &str,
+) -> Result<()> {
+    for path in audio_files(dir)? {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if stem.ends_with(&format!(" {}", suffix)) {
+            info!("Skipping variant: {}", path.display());
+            continue;
+        }
+
+        info!("Processing file: {}", path.display());
+        let (samples, track, metadata) = decode_file_with_metadata(&path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let processed = processor.process(&samples, channels, sample_rate)?;
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let output_path = path.with_file_name(format!("{} {}.{}", stem, suffix, extension));
+        Output::default().file_for(&path, &track, metadata).write(
+            &processed,
+            &track,
+            &output_path,
+        )?;
+        info!("Wrote variant: {}", output_path.display());
+    }
+
+    Ok(())
+}
+
+/// Normalize every file in the directory on its own, after running `pre` over it
+fn normalize_directory(
+    pre: &dyn AudioProcessor,
+    normalizer: &Normalizer,
+    dir: &str,
+    output: &Output,
+) -> Result<Vec<ClipReport>> {
+    let mut reports = Vec::new();
+    for path in audio_files(dir)? {
+        info!("Processing file: {}", path.display());
+        let (samples, track, metadata) = decode_file_with_metadata(&path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let samples = pre.process(&samples, channels, sample_rate)?;
+        let (normalized_samples, report) = normalizer.normalize(channels, sample_rate, &samples)?;
+
+        let _ =
+            output
+                .file_for(&path, &track, metadata)
+                .write(&normalized_samples, &track, &path)?;
+        reports.push(ClipReport::normalized(&path.display().to_string(), report));
+    }
+
+    Ok(reports)
+}
+
+/// Normalize every file in the directory with one shared gain, after running `pre` over
+/// every file
+fn process_directory_group(
+    pre: &dyn AudioProcessor,
+    normalizer: &Normalizer,
+    dir: &str,
+    output: &Output,
+) -> Result<Vec<ClipReport>> {
+    let mut decoded = Vec::new();
+    let mut reports = Vec::new();
+    for path in audio_files(dir)? {
+        info!("Measuring file: {}", path.display());
+        let prepared = decode_file_with_metadata(&path).and_then(|(samples, track, metadata)| {
+            let channels = track.codec_params.channels.unwrap().count();
+            let sample_rate = track.codec_params.sample_rate.unwrap();
+            Ok((
+                pre.process(&samples, channels, sample_rate)?,
+                track,
+                metadata,
+            ))
+        });
+        match prepared {
+            Ok((samples, track, metadata)) => decoded.push((path, samples, track, metadata)),
+            Err(e) => {
+                warn!("Skipping {}: {}", path.display(), e);
+                reports.push(ClipReport::skipped(&path.display().to_string(), e));
+            }
+        }
+    }
+
+    let members = decoded
+        .iter()
+        .map(|(_, samples, track, _)| GroupMember {
+            samples,
+            channels: track.codec_params.channels.unwrap().count(),
+            sample_rate: track.codec_params.sample_rate.unwrap(),
+        })
+        .collect::<Vec<_>>();
+
+    let normalized = normalizer.normalize_group(&members)?;
+
+    for ((path, _, track, metadata), normalized) in decoded.into_iter().zip(normalized) {
+        let name = path.display().to_string();
+        let (samples, report) = match normalized {
+            Ok(normalized) => normalized,
+            Err(e) => {
+                warn!("Skipping {}: {:#}", name, e);
+                reports.push(ClipReport::skipped(&name, format!("{:#}", e)));
+                continue;
+            }
+        };
+
+        info!(
+            "Writing {} ({:.1} LUFS -> {:.1} LUFS)",
+            path.display(),
+            report.input_loudness,
+            report.output_loudness
+        );
+        let _ = output
+            .file_for(&path, &track, metadata)
+            .write(&samples, &track, &path)?;
+        reports.push(ClipReport::normalized(&name, report));
+    }
+
+    Ok(reports)
+}
+
+/// Analyze every file in the directory
+fn analyze_directory(dir: &str) -> Result<Vec<(String, ClipAnalysis)>> {
+    let mut analyses = Vec::new();
+    for path in audio_files(dir)? {
+        let (samples, track) = decode_file(&path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let analysis = ClipAnalysis::analyze(&samples, channels, sample_rate)?;
+        analyses.push((path.display().to_string(), analysis));
+    }
+
+    Ok(analyses)
+}
+
+/// Run the processor over the files in the directory that the predicate flags
+fn process_flagged_directory(
+    processor: &dyn AudioProcessor,
+    dir: &str,
+    is_flagged: impl Fn(&ClipAnalysis) -> bool,
+) -> Result<()> {
+    for path in audio_files(dir)? {
+        let (samples, track, metadata) = decode_file_with_metadata(&path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let analysis = ClipAnalysis::analyze(&samples, channels, sample_rate)?;
+        if !is_flagged(&analysis) {
+            continue;
+        }
+
+        info!("Processing flagged file: {}", path.display());
+        let processed = processor.process(&samples, channels, sample_rate)?;
+
+        let _ = Output::default()
+            .file_for(&path, &track, metadata)
+            .write(&processed, &track, &path)?;
+    }
+
+    Ok(())
+}
+
+/// Run the processor over the guild's sounds that the predicate flags
+async fn process_flagged_sounds(
+    processor: &dyn AudioProcessor,
+    token: &str,
+    guild: &str,
+    is_flagged: impl Fn(&ClipAnalysis) -> bool,
+) -> Result<()> {
+    let discord_client = DiscordClient::new(token)?;
+    let sounds = discord_client.get_guild_sounds(guild).await?;
+
+    // Only re-encode the sounds that need it
+    let flagged = discord_client
+        .analyze_guild_sounds(sounds)
+        .await?
+        .into_iter()
+        .filter(|(_, analysis)| is_flagged(analysis))
+        .map(|(sound, _)| sound)
+        .collect::<Vec<_>>();
+    for sound in &flagged {
+        info!("Flagged sound: {}", sound.name);
+    }
+
+    discord_client
+        .process_guild_sounds(processor, flagged, guild)
+        .await?;
+
+    Ok(())
+}
+
+fn print_analysis(analyses: &[(String, ClipAnalysis)]) {
+    println!("\n🔎 Sound Analysis 🔎\n");
+
+    if analyses.is_empty() {
+        println!("No sounds found.");
+        return;
+    }
+
+    let max_name_len = analyses.iter().map(|(name, _)| name.len()).max().unwrap();
+
+    for (name, analysis) in analyses {
+        let loudness = match analysis.loudness {
+            Some(loudness) => format!("{:>6.1} LUFS", loudness),
+            None => "  too short".to_string(),
+        };
+        let stereo = match &analysis.stereo {
+            Some(stereo) => format!(
+                "corr {:>5.2} L/R {:>5.1} dB",
+                stereo.correlation, stereo.balance
+            ),
+            None => format!("{:<23}", "mono"),
+        };
+
+        println!(
+            "{:<width$} │ {} │ {:>5.1} dBTP │ 2-5 kHz {:>5.1} dB │ <100 Hz {:>5.1} dB │ {} │ {:>3} clicks │ {}",
+            name,
+            loudness,
+            analysis.true_peak,
+            analysis.harshness.ratio,
+            analysis.bass.ratio,
+            stereo,
+            analysis.clicks.total(),
+            analysis.flags().join(", "),
+            width = max_name_len
+        );
+    }
+    println!();
+}
+
+/// How processed local files are written back
+#[derive(Default)]
+struct Output {
+    loudness_metadata: bool,
+    mp3_options: Mp3Options,
+}
+
+impl Output {
+    /// Writer for a processed file, carrying over the tags it was decoded with. WAV and
+    /// FLAC files keep their format and sample format, the rest are written as MP3.
+    fn file_for(&self, path: &Path, track: &Track, metadata: Metadata) -> Box<dyn AudioFile> {
+        if has_extension(path, "flac") {
+            Box::new(FlacFile::matching(track).with_metadata(metadata))
+        } else if has_extension(path, "wav") {
+            let mut wav = WavFile::matching(track).with_metadata(metadata);
+            if self.loudness_metadata {
+                wav = wav.with_loudness_metadata();
+            }
+            Box::new(wav)
+        } else {
+            Box::new(
+                Mp3File::new()
+                    .with_options(self.mp3_options)
+                    .with_metadata(metadata),
+            )
+        }
+    }
+}
+
+fn has_extension(path: &Path, wanted: &str) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(wanted))
+}
+
+/// MP3 encoder settings of the command line
+fn mp3_options(
+    mode: Mp3ModeArg,
+    bitrate: Option<u32>,
+    vbr_quality: Option<u8>,
+    stereo: Mp3StereoArg,
+    lowpass: Option<u32>,
+    quality: u8,
+) -> Result<Mp3Options> {
+    let mode = match mode {
+        Mp3ModeArg::Cbr => Mp3Mode::Cbr,
+        Mp3ModeArg::Abr => Mp3Mode::Abr,
+        Mp3ModeArg::Vbr => Mp3Mode::Vbr,
+    };
+    let stereo = match stereo {
+        Mp3StereoArg::Joint => Mp3Stereo::Joint,
+        Mp3StereoArg::Separate => Mp3Stereo::Separate,
+        Mp3StereoArg::Forced => Mp3Stereo::Forced,
+    };
+
+    let mut options = Mp3Options::new(mode, bitrate, vbr_quality)?
+        .with_stereo(stereo)
+        .with_quality(quality)?;
+    if let Some(lowpass) = lowpass {
+        options = options.with_lowpass(lowpass)?;
+    }
+    Ok(options)
+}
+
+/// Discord client that uploads sounds in `upload_format`
+fn discord_client(
+    token: &str,
+    upload_format: UploadFormatArg,
+    mp3_options: Mp3Options,
+) -> Result<DiscordClient> {
+    let upload_format = match upload_format {
+        UploadFormatArg::Mp3 => UploadFormat::Mp3,
+        UploadFormatArg::Opus => UploadFormat::Opus,
+    };
+    Ok(DiscordClient::new(token)?
+        .with_upload_format(upload_format)?
+        .with_mp3_options(mp3_options))
+}
+
+/// Discord token and guild ID from the command line, falling back to the environment
+fn credentials(token: Option<String>, guild: Option<String>) -> Result<(String, String)> {
+    let token = token
+        .or_else(|| env::var("DISCORD_TOKEN").ok())
+        .ok_or_else(|| anyhow::anyhow!("Discord token not provided in CLI or .env"))?;
+    let guild = guild
+        .or_else(|| env::var("GUILD_ID").ok())
+        .ok_or_else(|| anyhow::anyhow!("Guild ID not provided in CLI or .env"))?;
+
+    Ok((token, guild))
+}
+
+/// List the audio files in a directory that can be normalized
+fn audio_files(dir: &str) -> Result<Vec<PathBuf>> {
     let dir_path = Path::new(dir);
     if !dir_path.is_dir() {
         return Err(anyhow::anyhow!("Provided path is not a directory"));
     }
 
+    let mut files = Vec::new();
     for entry in fs::read_dir(dir_path)? {
         let entry = entry?;
         let path = entry.path();
 
         if let Some(extension) = path.extension() {
-            if matches!(extension.to_str(), Some("mp3")) {
-                info!("Processing file: {}", path.display());
-                let (samples, track) = decode_file(&path)?;
-                let channels = track.codec_params.channels.unwrap().count();
-                let sample_rate = track.codec_params.sample_rate.unwrap();
+            if matches!(extension.to_str(), Some("mp3" | "wav" | "flac")) {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
 
-                let normalized_samples = processor.process(&samples, channels, sample_rate)?;
+fn print_reports(reports: &[ClipReport]) {
+    println!("\n📊 Normalization Report 📊\n");
 
-                let mp3 = Mp3File::new();
-                let _ = mp3.write(&normalized_samples, &track, &path)?;
+    if reports.is_empty() {
+        println!("No sounds found.");
+        return;
+    }
+
+    let max_name_len = reports.iter().map(|r| r.name.len()).max().unwrap();
+
+    for report in reports {
+        let outcome = match &report.outcome {
+            ClipOutcome::Normalized(normalized) => {
+                let target = if normalized.reached_target {
+                    "on target".to_string()
+                } else {
+                    format!(
+                        "{:.1} dB short of the {:.1} dB target",
+                        normalized.shortfall(),
+                        normalized.target
+                    )
+                };
+                format!(
+                    "{:>6.1} -> {:>6.1} LUFS │ gain {:>+6.1} dB │ {:>5.1} dBTP │ {}",
+                    normalized.input_loudness,
+                    normalized.output_loudness,
+                    normalized.gain,
+                    normalized.output_peak,
+                    target
+                )
             }
-        }
+            ClipOutcome::Processed => "processed".to_string(),
+            ClipOutcome::Skipped(reason) => format!("skipped: {}", reason),
+        };
+
+        println!(
+            "{:<width$} │ {}",
+            report.name,
+            outcome,
+            width = max_name_len
+        );
     }
+    println!();
+}
 
-    Ok(())
+fn ceiling_mode(limit: bool) -> CeilingMode {
+    if limit {
+        CeilingMode::Limit
+    } else {
+        CeilingMode::ReduceGain
+    }
+}
+
+fn normalization_mode(
+    mode: ModeArg,
+    level: Option<f64>,
+    target_loudness: f64,
+    peak_ceiling: f64,
+) -> NormalizationMode {
+    match mode {
+        ModeArg::Loudness => NormalizationMode::Loudness,
+        ModeArg::SamplePeak => NormalizationMode::Peak {
+            level: level.unwrap_or(peak_ceiling),
+            true_peak: false,
+        },
+        ModeArg::TruePeak => NormalizationMode::Peak {
+            level: level.unwrap_or(peak_ceiling),
+            true_peak: true,
+        },
+        ModeArg::Rms => NormalizationMode::Rms {
+            level: level.unwrap_or(target_loudness),
+            a_weighted: false,
+        },
+        ModeArg::RmsA => NormalizationMode::Rms {
+            level: level.unwrap_or(target_loudness),
+            a_weighted: true,
+        },
+        ModeArg::ReplayGain => NormalizationMode::ReplayGain {
+            pre_amp: level.unwrap_or(0.0),
+        },
+        ModeArg::Perceptual => NormalizationMode::Perceptual,
+    }
 }
 
 fn set_log_level(level_str: &str) {
@@ -7,15 +7,46 @@ use reqwest::{
 };
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
+use symphonia::core::formats::Track;
 use tempfile::tempdir;
 use tokio::fs;
 
+#[cfg(feature = "opus")]
+use crate::audio_file::OggOpusFile;
 use crate::{
+    audio_analyzer::ClipAnalysis,
     audio_converter::{AudioConverter, OpusFile},
     audio_file::AudioFile,
-    dsp::AudioProcessor,
+    audio_matcher::ReferenceProfile,
+    audio_normalizer::{
+        ClipReport, GroupMember, NormalizationMode, NormalizationReport, Normalizer,
+    },
+    dsp::{median, AudioProcessor, ProcessorChain},
 };
-use crate::{audio_file::Mp3File, dsp::decode_file};
+use crate::{
+    audio_file::{Mp3File, Mp3Options},
+    dsp::decode_file,
+};
+
+/// File format processed sounds are uploaded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UploadFormat {
+    /// 192 kbps MP3, which every build can write
+    #[default]
+    Mp3,
+    /// Ogg Opus, as Discord stores its own uploads, at a fraction of the size of the MP3.
+    /// Needs the `opus` feature.
+    Opus,
+}
+
+impl UploadFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            UploadFormat::Mp3 => "audio/mp3",
+            UploadFormat::Opus => "audio/ogg",
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct SoundboardSound {
@@ -39,12 +70,39 @@ pub struct SoundboardDownload {
     pub mime_type: String,
 }
 
+/// A downloaded soundboard sound with its decoded samples
+struct DecodedSound {
+    sound: SoundboardSound,
+    samples: Vec<f32>,
+    track: Track,
+}
+
+impl DecodedSound {
+    fn channels(&self) -> usize {
+        self.track.codec_params.channels.unwrap().count()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.track.codec_params.sample_rate.unwrap()
+    }
+}
+
+#[derive(Clone)]
 pub struct DiscordClient {
     client: ReqwestClient,
     base_url: String,
+    upload_format: UploadFormat,
+    mp3_options: Mp3Options,
 }
 
 impl DiscordClient {
+    /// Default distance from the guild median, in LU, within which sounds are left alone
+    pub const DEFAULT_RELATIVE_TOLERANCE: f64 = 2.0;
+    /// Longest sound name Discord accepts on the soundboard, in characters
+    pub const MAX_SOUND_NAME_LEN: usize = 32;
+    /// Largest file Discord accepts on the soundboard, in bytes
+    pub const MAX_UPLOAD_SIZE: usize = 512 * 1024;
+
     pub fn new(token: &str) -> Result<Self> {
         let mut headers = HeaderMap::new();
         headers.insert(
@@ -57,49 +115,378 @@ impl DiscordClient {
         Ok(Self {
             client,
             base_url: "https://discord.com/api/v10".to_string(),
+            upload_format: UploadFormat::default(),
+            mp3_options: Mp3Options::default(),
+        })
+    }
+
+    /// Upload processed sounds in `upload_format`, failing when this build cannot write it
+    pub fn with_upload_format(mut self, upload_format: UploadFormat) -> Result<Self> {
+        if upload_format == UploadFormat::Opus && !cfg!(feature = "opus") {
+            return Err(anyhow::anyhow!(
+                "Opus uploads need earpeace built with the `opus` feature"
+            ));
+        }
+
+        self.upload_format = upload_format;
+        Ok(self)
+    }
+
+    /// Encode MP3 uploads with `mp3_options`
+    pub fn with_mp3_options(mut self, mp3_options: Mp3Options) -> Self {
+        self.mp3_options = mp3_options;
+        self
+    }
+
+    /// Encoder for processed sounds, keeping them within the upload limit so oversized
+    /// sounds fail before anything is sent to Discord
+    fn encoder(&self) -> Result<Box<dyn AudioFile>> {
+        Ok(match self.upload_format {
+            #[cfg(feature = "opus")]
+            UploadFormat::Opus => {
+                Box::new(OggOpusFile::new().with_max_size(Self::MAX_UPLOAD_SIZE)?)
+            }
+            _ => Box::new(
+                Mp3File::new()
+                    .with_options(self.mp3_options)
+                    .with_max_size(Self::MAX_UPLOAD_SIZE)?,
+            ),
         })
     }
 
+    /// Run every sound through the processor, reporting which sounds were processed
     pub async fn process_guild_sounds(
         &self,
         processor: &dyn AudioProcessor,
         sounds: Vec<SoundboardSound>,
         guild_id: &str,
-    ) -> Result<()> {
+    ) -> Result<Vec<ClipReport>> {
         // Create temporary directory for processing
         let temp_dir = tempdir()?;
 
+        let mut reports = Vec::with_capacity(sounds.len());
         for sound in sounds {
-            // Download sound
-            let (download, temp_path) = self
-                .download_soundboard_sound(&sound, temp_dir.path())
-                .await?;
+            let processed = match self.fetch_sound_file(&sound, temp_dir.path()).await {
+                Ok(processing_path) => {
+                    self.process_and_upload_sound(
+                        processor,
+                        &processing_path,
+                        guild_id,
+                        &sound.name,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+
+            match processed {
+                Ok(_) => {
+                    info!("Successfully processed and uploaded sound: {}", sound.name);
+                    reports.push(ClipReport::processed(&sound.name));
+                }
+                Err(e) => {
+                    warn!("Failed to process sound '{}': {}", sound.name, e);
+                    reports.push(ClipReport::skipped(&sound.name, e));
+                }
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Normalize every sound on its own after running `pre` over it, reporting what became
+    /// of each sound
+    pub async fn normalize_guild_sounds(
+        &self,
+        pre: &dyn AudioProcessor,
+        normalizer: &Normalizer,
+        sounds: Vec<SoundboardSound>,
+        guild_id: &str,
+    ) -> Result<Vec<ClipReport>> {
+        // Create temporary directory for processing
+        let temp_dir = tempdir()?;
+
+        let mut reports = Vec::with_capacity(sounds.len());
+        for sound in sounds {
+            let normalized = self
+                .normalize_and_upload_sound(pre, normalizer, &sound, temp_dir.path(), guild_id)
+                .await;
+            reports.push(clip_report(&sound.name, normalized));
+        }
 
-            // Define the MP3 output path
-            let mp3_path = temp_path.with_extension("mp3");
+        Ok(reports)
+    }
+
+    /// Normalize the sounds as one group with a shared gain, keeping their relative loudness
+    ///
+    /// `pre` runs over every sound before the group is measured.
+    pub async fn process_guild_sounds_group(
+        &self,
+        pre: &dyn AudioProcessor,
+        normalizer: &Normalizer,
+        sounds: Vec<SoundboardSound>,
+        guild_id: &str,
+    ) -> Result<Vec<ClipReport>> {
+        // Create temporary directory for processing
+        let temp_dir = tempdir()?;
 
-            // Convert to MP3 if needed
-            let processing_path = if download.mime_type == "audio/ogg" {
-                let opus_file = OpusFile::new();
-                opus_file.convert(&temp_path, &mp3_path)?;
-                mp3_path
-            } else {
-                temp_path
+        // Every sound has to be decoded before the shared gain can be measured
+        let (decoded, mut reports) = self
+            .fetch_and_decode_sounds(sounds, temp_dir.path(), pre)
+            .await;
+
+        let members = decoded
+            .iter()
+            .map(|d| GroupMember {
+                samples: &d.samples,
+                channels: d.channels(),
+                sample_rate: d.sample_rate(),
+            })
+            .collect::<Vec<_>>();
+
+        let normalized = normalizer.normalize_group(&members)?;
+
+        for (decoded, normalized) in decoded.iter().zip(normalized) {
+            let (samples, report) = match normalized {
+                Ok(normalized) => normalized,
+                Err(e) => {
+                    warn!("Skipping sound '{}': {:#}", decoded.sound.name, e);
+                    reports.push(ClipReport::skipped(&decoded.sound.name, format!("{:#}", e)));
+                    continue;
+                }
             };
 
-            // Normalize the sound
-            match self
-                .process_and_upload_sound(processor, &processing_path, guild_id, &sound.name)
+            debug!("Group normalized '{}': {:?}", decoded.sound.name, report);
+            let uploaded = self
+                .upload_processed_sound(guild_id, decoded, &samples)
                 .await
-            {
-                Ok(_) => info!("Successfully processed and uploaded sound: {}", sound.name),
-                Err(e) => warn!("Failed to process sound '{}': {}", sound.name, e),
+                .map(|_| report);
+            reports.push(clip_report(&decoded.sound.name, uploaded));
+        }
+
+        Ok(reports)
+    }
+
+    /// Analyze every sound in the guild, then normalize the outliers to the guild's median loudness
+    ///
+    /// Sounds within `tolerance` LU of the median are left untouched. The median is used as the
+    /// normalizer's target loudness, clamped to [`Normalizer::MAX_TARGET_LOUDNESS`]. `pre` runs
+    /// over every sound before it is measured.
+    pub async fn process_guild_sounds_relative(
+        &self,
+        pre: &dyn AudioProcessor,
+        normalizer: &Normalizer,
+        sounds: Vec<SoundboardSound>,
+        guild_id: &str,
+        tolerance: f64,
+    ) -> Result<Vec<ClipReport>> {
+        if tolerance < 0.0 {
+            return Err(anyhow::anyhow!(
+                "Tolerance must not be negative (got: {} LU)",
+                tolerance
+            ));
+        }
+
+        // Create temporary directory for processing
+        let temp_dir = tempdir()?;
+
+        let (decoded, mut reports) = self
+            .fetch_and_decode_sounds(sounds, temp_dir.path(), pre)
+            .await;
+
+        let mut analyzed = Vec::with_capacity(decoded.len());
+        for d in decoded {
+            match NormalizationMode::Loudness.measure(d.channels(), d.sample_rate(), &d.samples) {
+                Ok(loudness) => analyzed.push((d, loudness)),
+                Err(e) => {
+                    warn!("Failed to analyze sound '{}': {}", d.sound.name, e);
+                    reports.push(ClipReport::skipped(&d.sound.name, e));
+                }
+            }
+        }
+
+        let levels = analyzed.iter().map(|(_, l)| *l).collect::<Vec<_>>();
+        let Some((median, target, outliers)) = relative_outliers(&levels, tolerance) else {
+            info!("No sounds could be analyzed in guild {}", guild_id);
+            return Ok(reports);
+        };
+
+        info!(
+            "Guild {} median loudness is {:.1} LUFS, normalizing outliers to {:.1} LUFS",
+            guild_id, median, target
+        );
+
+        let normalizer = normalizer
+            .clone()
+            .with_mode(NormalizationMode::Loudness)?
+            .with_target_loudness(target)?;
+
+        for ((decoded, loudness), outlier) in analyzed.iter().zip(outliers) {
+            if !outlier {
+                debug!(
+                    "Skipping '{}' at {:.1} LUFS, within {:.1} LU of the median",
+                    decoded.sound.name, loudness, tolerance
+                );
+                reports.push(ClipReport::skipped(
+                    &decoded.sound.name,
+                    format!("within {:.1} LU of the median", tolerance),
+                ));
+                continue;
             }
+
+            let uploaded = match normalizer.normalize(
+                decoded.channels(),
+                decoded.sample_rate(),
+                &decoded.samples,
+            ) {
+                Ok((samples, report)) => self
+                    .upload_processed_sound(guild_id, decoded, &samples)
+                    .await
+                    .map(|_| report),
+                Err(e) => Err(e),
+            };
+            reports.push(clip_report(&decoded.sound.name, uploaded));
         }
 
+        Ok(reports)
+    }
+
+    /// Download a soundboard sound and measure it for use as a matching reference
+    pub async fn reference_profile(&self, sound: &SoundboardSound) -> Result<ReferenceProfile> {
+        let temp_dir = tempdir()?;
+        let processing_path = self.fetch_sound_file(sound, temp_dir.path()).await?;
+
+        ReferenceProfile::from_file(&processing_path)
+    }
+
+    /// Download and analyze every sound, skipping any that cannot be measured
+    pub async fn analyze_guild_sounds(
+        &self,
+        sounds: Vec<SoundboardSound>,
+    ) -> Result<Vec<(SoundboardSound, ClipAnalysis)>> {
+        let temp_dir = tempdir()?;
+        let (decoded, _) = self
+            .fetch_and_decode_sounds(sounds, temp_dir.path(), &ProcessorChain::new())
+            .await;
+
+        let mut analyses = Vec::with_capacity(decoded.len());
+        for sound in decoded {
+            match ClipAnalysis::analyze(&sound.samples, sound.channels(), sound.sample_rate()) {
+                Ok(analysis) => analyses.push((sound.sound, analysis)),
+                Err(e) => warn!("Failed to analyze sound '{}': {}", sound.sound.name, e),
+            }
+        }
+
+        Ok(analyses)
+    }
+
+    /// Download and decode every sound and run `pre` over it before any of them is
+    /// processed, reporting the sounds that could not be fetched or decoded as skipped
+    async fn fetch_and_decode_sounds(
+        &self,
+        sounds: Vec<SoundboardSound>,
+        output_dir: &Path,
+        pre: &dyn AudioProcessor,
+    ) -> (Vec<DecodedSound>, Vec<ClipReport>) {
+        let mut decoded = Vec::with_capacity(sounds.len());
+        let mut skipped = Vec::new();
+        for sound in sounds {
+            let fetched = match self.fetch_sound_file(&sound, output_dir).await {
+                Ok(processing_path) => decode_file(&processing_path)
+                    .and_then(|(samples, track)| Ok((prepare(pre, samples, &track)?, track))),
+                Err(e) => Err(e),
+            };
+
+            match fetched {
+                Ok((samples, track)) => decoded.push(DecodedSound {
+                    sound,
+                    samples,
+                    track,
+                }),
+                Err(e) => {
+                    warn!("Failed to fetch sound '{}': {}", sound.name, e);
+                    skipped.push(ClipReport::skipped(&sound.name, e));
+                }
+            }
+        }
+
+        (decoded, skipped)
+    }
+
+    /// Encode processed samples and replace the original sound
+    async fn upload_processed_sound(
+        &self,
+        guild_id: &str,
+        decoded: &DecodedSound,
+        samples: &[f32],
+    ) -> Result<()> {
+        let bytes = self.encoder()?.write_to_buffer(samples, &decoded.track)?;
+        self.replace_sound(guild_id, &decoded.sound.name, &bytes)
+            .await
+    }
+
+    /// Process a sound and upload the result as a new sound called `name`, leaving the
+    /// original sound as it is
+    pub async fn create_sound_variant(
+        &self,
+        processor: &dyn AudioProcessor,
+        sound: &SoundboardSound,
+        guild_id: &str,
+        name: &str,
+    ) -> Result<()> {
+        let temp_dir = tempdir()?;
+        let processing_path = self.fetch_sound_file(sound, temp_dir.path()).await?;
+
+        let (samples, track) = decode_file(&processing_path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+        let processed = processor.process(&samples, channels, sample_rate)?;
+        let bytes = self.encoder()?.write_to_buffer(&processed, &track)?;
+
+        // The variant takes over the emoji of the original
+        self.create_soundboard_sound(
+            std::slice::from_ref(sound),
+            guild_id,
+            &sound.sound_id,
+            name,
+            &bytes,
+            self.upload_format.content_type(),
+        )
+        .await?;
+
+        info!("Created sound '{}' from '{}'", name, sound.name);
         Ok(())
     }
 
+    /// Name for a variant of a sound, shortening the original name so the `suffix` fits
+    pub fn variant_name(name: &str, suffix: &str) -> String {
+        let room = Self::MAX_SOUND_NAME_LEN.saturating_sub(suffix.chars().count() + 1);
+        let base: String = name.chars().take(room).collect();
+        format!("{} {}", base.trim_end(), suffix)
+    }
+
+    /// Download a sound and convert it to MP3 if needed, returning the path to decode
+    async fn fetch_sound_file(
+        &self,
+        sound: &SoundboardSound,
+        output_dir: &Path,
+    ) -> Result<PathBuf> {
+        // Download sound
+        let (download, temp_path) = self.download_soundboard_sound(sound, output_dir).await?;
+
+        // Define the MP3 output path
+        let mp3_path = temp_path.with_extension("mp3");
+
+        // Convert to MP3 if needed
+        if download.mime_type == "audio/ogg" {
+            let opus_file = OpusFile::new();
+            opus_file.convert(&temp_path, &mp3_path)?;
+            Ok(mp3_path)
+        } else {
+            Ok(temp_path)
+        }
+    }
+
     async fn process_and_upload_sound(
         &self,
         processor: &dyn AudioProcessor,
@@ -114,10 +501,37 @@ impl DiscordClient {
 
         let normalized_samples = processor.process(&samples, channels, sample_rate)?;
 
-        let mp3 = Mp3File::new();
-        let bytes = mp3.write_to_buffer(&normalized_samples, &track)?;
+        let bytes = self
+            .encoder()?
+            .write_to_buffer(&normalized_samples, &track)?;
+
+        self.replace_sound(guild_id, sound_name, &bytes).await
+    }
+
+    async fn normalize_and_upload_sound(
+        &self,
+        pre: &dyn AudioProcessor,
+        normalizer: &Normalizer,
+        sound: &SoundboardSound,
+        output_dir: &Path,
+        guild_id: &str,
+    ) -> Result<NormalizationReport> {
+        let processing_path = self.fetch_sound_file(sound, output_dir).await?;
+        let (samples, track) = decode_file(&processing_path)?;
+        let channels = track.codec_params.channels.unwrap().count();
+        let sample_rate = track.codec_params.sample_rate.unwrap();
+
+        let prepared = prepare(pre, samples, &track)?;
+        let (normalized, report) = normalizer.normalize(channels, sample_rate, &prepared)?;
+
+        let bytes = self.encoder()?.write_to_buffer(&normalized, &track)?;
+        self.replace_sound(guild_id, &sound.name, &bytes).await?;
+
+        Ok(report)
+    }
 
-        // Discord expects MP3 files
+    /// Upload the processed sound in place of the existing sound with the same name
+    async fn replace_sound(&self, guild_id: &str, sound_name: &str, bytes: &[u8]) -> Result<()> {
         let sounds = self.get_guild_sounds(guild_id).await?;
         let existing_sound = sounds.iter().find(|s| s.name == sound_name);
 
@@ -131,8 +545,8 @@ impl DiscordClient {
                     guild_id,
                     &original_sound_id,
                     &sound.name,
-                    &bytes,
-                    "audio/mp3",
+                    bytes,
+                    self.upload_format.content_type(),
                 )
                 .await?;
 
@@ -150,8 +564,8 @@ impl DiscordClient {
                     guild_id,
                     sound_name,
                     sound_name,
-                    &bytes,
-                    "audio/mp3",
+                    bytes,
+                    self.upload_format.content_type(),
                 )
                 .await?;
             }
@@ -287,3 +701,72 @@ impl DiscordClient {
         Ok(())
     }
 }
+
+/// Run `pre` over the decoded samples of `track`
+fn prepare(pre: &dyn AudioProcessor, samples: Vec<f32>, track: &Track) -> Result<Vec<f32>> {
+    pre.process(
+        &samples,
+        track.codec_params.channels.unwrap().count(),
+        track.codec_params.sample_rate.unwrap(),
+    )
+}
+
+/// Median of the loudness `levels`, the target loudness of relative normalization and
+/// whether each level is an outlier to be normalized, or `None` when there are no levels
+///
+/// The target is the median clamped to [`Normalizer::MAX_TARGET_LOUDNESS`]. Levels within
+/// `tolerance` LU of the target are not outliers.
+fn relative_outliers(levels: &[f64], tolerance: f64) -> Option<(f64, f64, Vec<bool>)> {
+    let median = median(&mut levels.to_vec())?;
+    let target = median.min(Normalizer::MAX_TARGET_LOUDNESS);
+    let outliers = levels
+        .iter()
+        .map(|level| (level - target).abs() > tolerance)
+        .collect();
+
+    Some((median, target, outliers))
+}
+
+/// Report of a sound that was normalized and uploaded, logging the outcome
+fn clip_report(name: &str, uploaded: Result<NormalizationReport>) -> ClipReport {
+    match uploaded {
+        Ok(report) => {
+            info!("Successfully processed and uploaded sound: {}", name);
+            ClipReport::normalized(name, report)
+        }
+        Err(e) => {
+            warn!("Failed to process sound '{}': {}", name, e);
+            ClipReport::skipped(name, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_outliers_around_median() {
+        let levels = [-30.0, -19.0, -18.0, -17.5, -10.0];
+
+        let (median, target, outliers) = relative_outliers(&levels, 2.0).unwrap();
+        assert_eq!(median, -18.0);
+        assert_eq!(target, -18.0);
+        assert_eq!(outliers, [true, false, false, false, true]);
+    }
+
+    #[test]
+    fn test_relative_target_capped_at_max_loudness() {
+        let levels = [-14.0, -12.0, -13.0, -20.0];
+
+        let (median, target, outliers) = relative_outliers(&levels, 1.0).unwrap();
+        assert_eq!(median, -13.5);
+        assert_eq!(target, Normalizer::MAX_TARGET_LOUDNESS);
+        assert_eq!(outliers, [false, true, true, true]);
+    }
+
+    #[test]
+    fn test_relative_outliers_without_levels() {
+        assert!(relative_outliers(&[], 2.0).is_none());
+    }
+}
@@ -1,6 +1,8 @@
-use std::{fs::File, path::Path};
+use std::{fs::File, path::Path, sync::Mutex};
 
 use anyhow::Error;
+use fundsp::fft::{inverse_fft, real_fft};
+use num_complex::Complex32;
 use symphonia::{
     core::{
         formats::{FormatOptions, Track},
@@ -11,6 +13,8 @@ use symphonia::{
     default,
 };
 
+use crate::audio_metadata::Metadata;
+
 pub trait AudioProcessor: Send + Sync {
     fn process(
         &self,
@@ -20,6 +24,116 @@ pub trait AudioProcessor: Send + Sync {
     ) -> Result<Vec<f32>, Error>;
 }
 
+/// Block-based processing interface for real-time use
+///
+/// Blocks are interleaved and must contain a whole number of frames.
+pub trait StreamingProcessor: Send {
+    /// Allocate state for the given stream format. Must be called before processing.
+    fn prepare(&mut self, channels: usize, sample_rate: u32) -> Result<(), Error>;
+
+    /// Process a block of interleaved samples in place
+    fn process_block(&mut self, block: &mut [f32]) -> Result<(), Error>;
+
+    /// Delay introduced by the processor, in frames
+    fn latency(&self) -> usize;
+
+    /// Clear all internal state without changing the stream format
+    fn reset(&mut self);
+}
+
+/// Runs a [`StreamingProcessor`] over a whole clip so it can be used as an [`AudioProcessor`]
+///
+/// The output is latency compensated, so it lines up with the input sample for sample.
+pub struct StreamingAdapter<P: StreamingProcessor> {
+    processor: Mutex<P>,
+    block_frames: usize,
+}
+
+impl<P: StreamingProcessor> StreamingAdapter<P> {
+    pub const DEFAULT_BLOCK_FRAMES: usize = 512;
+
+    pub fn new(processor: P) -> Self {
+        Self {
+            processor: Mutex::new(processor),
+            block_frames: Self::DEFAULT_BLOCK_FRAMES,
+        }
+    }
+
+    pub fn with_block_frames(mut self, block_frames: usize) -> Self {
+        self.block_frames = block_frames.max(1);
+        self
+    }
+
+    pub fn into_inner(self) -> P {
+        self.processor
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<P: StreamingProcessor> AudioProcessor for StreamingAdapter<P> {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let mut processor = self
+            .processor
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Streaming processor lock was poisoned"))?;
+
+        processor.prepare(channels, sample_rate)?;
+        processor.reset();
+
+        // Pad the tail so the delayed output can be flushed out in full
+        let delay = processor.latency() * channels;
+        let mut buffer = Vec::with_capacity(samples.len() + delay);
+        buffer.extend_from_slice(samples);
+        buffer.resize(samples.len() + delay, 0.0);
+
+        for block in buffer.chunks_mut(self.block_frames * channels) {
+            processor.process_block(block)?;
+        }
+
+        Ok(buffer.split_off(delay))
+    }
+}
+
+/// Runs processors one after another, each on the output of the one before
+#[derive(Default)]
+pub struct ProcessorChain {
+    processors: Vec<Box<dyn AudioProcessor>>,
+}
+
+impl ProcessorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a processor to the end of the chain
+    pub fn then(mut self, processor: impl AudioProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+}
+
+impl AudioProcessor for ProcessorChain {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        let mut output = samples.to_vec();
+        for processor in &self.processors {
+            output = processor.process(&output, channels, sample_rate)?;
+        }
+
+        Ok(output)
+    }
+}
+
 /// Convert a linear value to a decibel scale
 pub fn linear_to_db(linear: f64) -> f64 {
     20.0 * linear.log10()
@@ -39,6 +153,845 @@ pub fn max_peak(samples: &[f32]) -> f64 {
         .unwrap_or(0.0)
 }
 
+/// Median of the values, or `None` when there are none. Reorders the slice.
+pub fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[mid - 1] + values[mid]) / 2.0)
+    } else {
+        Some(values[mid])
+    }
+}
+
+/// Root mean square level of interleaved samples in dBFS
+///
+/// A full scale sine wave measures -3.01 dBFS.
+pub fn rms_db(samples: &[f32]) -> f64 {
+    if samples.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+
+    let sum_squares: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
+    10.0 * (sum_squares / samples.len() as f64).log10()
+}
+
+/// Root mean square level of interleaved samples after IEC 61672 A-weighting, in dBFS
+pub fn a_weighted_rms_db(samples: &[f32], channels: usize, sample_rate: u32) -> f64 {
+    let mut filters: Vec<[Biquad; 3]> = (0..channels).map(|_| a_weighting(sample_rate)).collect();
+    let weighted: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            filters[i % channels]
+                .iter_mut()
+                .fold(s as f64, |x, filter| filter.process(x)) as f32
+        })
+        .collect();
+
+    rms_db(&weighted)
+}
+
+/// Second order IIR filter section in transposed direct form II
+///
+/// Coefficient formulas follow the RBJ audio EQ cookbook.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn new(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    pub fn lowpass(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        Self::new(
+            (1.0 - cos) / 2.0,
+            1.0 - cos,
+            (1.0 - cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    pub fn highpass(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        Self::new(
+            (1.0 + cos) / 2.0,
+            -(1.0 + cos),
+            (1.0 + cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    pub fn bandpass(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        Self::new(alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    pub fn notch(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        Self::new(1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    pub fn peaking(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        let a = 10f64.powf(gain_db / 40.0);
+        Self::new(
+            1.0 + alpha * a,
+            -2.0 * cos,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos,
+            1.0 - alpha / a,
+        )
+    }
+
+    pub fn low_shelf(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        let a = 10f64.powf(gain_db / 40.0);
+        let sqrt_a = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a),
+            (a + 1.0) + (a - 1.0) * cos + sqrt_a,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - sqrt_a,
+        )
+    }
+
+    pub fn high_shelf(sample_rate: u32, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate, frequency, q);
+        let a = 10f64.powf(gain_db / 40.0);
+        let sqrt_a = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a),
+            (a + 1.0) - (a - 1.0) * cos + sqrt_a,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - sqrt_a,
+        )
+    }
+
+    /// Cosine of the normalized frequency and the bandwidth term alpha
+    fn omega(sample_rate: u32, frequency: f64, q: f64) -> (f64, f64) {
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    /// Switch to the coefficients of another filter, keeping this filter's state
+    ///
+    /// Used for time-varying filters whose parameters change while they run.
+    pub fn retune(&mut self, other: &Biquad) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+
+    /// Scale the output of the filter by a linear gain
+    pub fn scaled(mut self, gain: f64) -> Self {
+        self.b0 *= gain;
+        self.b1 *= gain;
+        self.b2 *= gain;
+        self
+    }
+
+    /// Magnitude response of the filter at the given frequency
+    pub fn magnitude(&self, sample_rate: u32, frequency: f64) -> f64 {
+        let w = 2.0 * std::f64::consts::PI * frequency / sample_rate as f64;
+        let (c1, s1) = (w.cos(), w.sin());
+        let (c2, s2) = ((2.0 * w).cos(), (2.0 * w).sin());
+
+        let num_re = self.b0 + self.b1 * c1 + self.b2 * c2;
+        let num_im = -(self.b1 * s1 + self.b2 * s2);
+        let den_re = 1.0 + self.a1 * c1 + self.a2 * c2;
+        let den_im = -(self.a1 * s1 + self.a2 * s2);
+
+        ((num_re.powi(2) + num_im.powi(2)) / (den_re.powi(2) + den_im.powi(2))).sqrt()
+    }
+}
+
+/// IEC 61672 A-weighting as a cascade of three biquads, normalized to 0 dB at 1 kHz
+///
+/// The analog prototype is mapped with the bilinear transform, one pole pair per section.
+pub fn a_weighting(sample_rate: u32) -> [Biquad; 3] {
+    const F1: f64 = 20.598997;
+    const F2: f64 = 107.65265;
+    const F3: f64 = 737.86223;
+    const F4: f64 = 12194.217;
+
+    let k = 2.0 * sample_rate as f64;
+    let w = |f: f64| 2.0 * std::f64::consts::PI * f;
+
+    // Bilinear transform of (s + p) is (k + p) + (p - k)z^-1, of s alone it is k(1 - z^-1)
+    let pole_pair = |p1: f64, p2: f64| {
+        let (a0, a1) = (k + p1, p1 - k);
+        let (b0, b1) = (k + p2, p2 - k);
+        (a0 * b0, a0 * b1 + a1 * b0, a1 * b1)
+    };
+
+    // s^2 / (s + w1)^2
+    let (a0, a1, a2) = pole_pair(w(F1), w(F1));
+    let highpass_low = Biquad::new(k * k, -2.0 * k * k, k * k, a0, a1, a2);
+
+    // s^2 / ((s + w2)(s + w3))
+    let (a0, a1, a2) = pole_pair(w(F2), w(F3));
+    let highpass_mid = Biquad::new(k * k, -2.0 * k * k, k * k, a0, a1, a2);
+
+    // 1 / (s + w4)^2
+    let (a0, a1, a2) = pole_pair(w(F4), w(F4));
+    let lowpass_high = Biquad::new(1.0, 2.0, 1.0, a0, a1, a2);
+
+    let sections = [highpass_low, highpass_mid, lowpass_high];
+    let gain_at_1k: f64 = sections
+        .iter()
+        .map(|section| section.magnitude(sample_rate, 1000.0))
+        .product();
+
+    [
+        sections[0].scaled(1.0 / gain_at_1k),
+        sections[1],
+        sections[2],
+    ]
+}
+
+/// ITU-R BS.1770 K-weighting: the high-shelf pre-filter followed by the RLB high-pass
+pub fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    [
+        Biquad::high_shelf(
+            sample_rate,
+            1681.974450955533,
+            0.7071752369554196,
+            3.999843853973347,
+        ),
+        Biquad::highpass(sample_rate, 38.13547087602444, 0.5003270373238773),
+    ]
+}
+
+/// K-weighted energy of one STFT frame and the part of it inside a frequency band
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandEnergy {
+    pub total: f64,
+    pub band: f64,
+}
+
+impl BandEnergy {
+    /// Share of the frame's energy inside the band, in dB
+    pub fn ratio_db(&self) -> f64 {
+        10.0 * (self.band / self.total).log10()
+    }
+}
+
+/// Long-term and largest single-frame band share in dB
+///
+/// Frames more than `gate` dB below the loudest frame are ignored, so silence and tails do
+/// not dilute the share.
+pub fn gated_band_ratio(frames: &[BandEnergy], gate: f64) -> (f64, f64) {
+    let loudest = frames.iter().map(|f| f.total).fold(0.0, f64::max);
+    let threshold = loudest * 10f64.powf(gate / 10.0);
+
+    let (mut band, mut total, mut peak_ratio) = (0.0, 0.0, f64::NEG_INFINITY);
+    for frame in frames
+        .iter()
+        .filter(|f| f.total > 0.0 && f.total >= threshold)
+    {
+        band += frame.band;
+        total += frame.total;
+        peak_ratio = peak_ratio.max(frame.ratio_db());
+    }
+
+    let ratio = if total > 0.0 {
+        10.0 * (band / total).log10()
+    } else {
+        f64::NEG_INFINITY
+    };
+
+    (ratio, peak_ratio)
+}
+
+/// K-weighted STFT energy of the mono downmix, with the energy between `low` and `high` Hz
+///
+/// The band energy is K-weighted too unless `weight_band` is false, which shows the lows
+/// that K-weighting rolls off. Frames are Hann windowed with 50% overlap. `fft_size` must
+/// be a power of two.
+pub fn band_energies(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    (low, high): (f64, f64),
+    weight_band: bool,
+    fft_size: usize,
+) -> Vec<BandEnergy> {
+    let mono = downmix_to_mono(samples, channels);
+    let window = hann_window(fft_size);
+    let filters = k_weighting(sample_rate);
+    let bin_width = sample_rate as f64 / fft_size as f64;
+
+    // Power response of the K-weighting filters at every bin
+    let weights: Vec<f64> = (0..=fft_size / 2)
+        .map(|k| {
+            filters
+                .iter()
+                .map(|filter| filter.magnitude(sample_rate, k as f64 * bin_width).powi(2))
+                .product()
+        })
+        .collect();
+
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while start < mono.len() {
+        let end = (start + fft_size).min(mono.len());
+        let mut energy = BandEnergy {
+            total: 0.0,
+            band: 0.0,
+        };
+
+        for (k, bin) in spectrum(&mono[start..end], &window, fft_size)
+            .iter()
+            .enumerate()
+        {
+            let power = bin.norm_sqr() as f64;
+            energy.total += power * weights[k];
+
+            let frequency = k as f64 * bin_width;
+            if (low..high).contains(&frequency) {
+                energy.band += if weight_band {
+                    power * weights[k]
+                } else {
+                    power
+                };
+            }
+        }
+
+        frames.push(energy);
+        start += fft_size / 2;
+    }
+
+    frames
+}
+
+/// Dynamic EQ that cuts a band only while the band's share of the K-weighted mono sum is
+/// over a threshold
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicFilter {
+    /// Band share above which the band is cut, in dB
+    pub threshold: f64,
+    /// Deepest cut in dB
+    pub max_cut: f64,
+    /// dB of cut per dB the band is over the threshold
+    pub cut_per_db: f64,
+    /// Smoothing of the band and total energy detectors
+    pub detector_ms: f64,
+    pub attack_ms: f64,
+    pub release_ms: f64,
+}
+
+impl DynamicFilter {
+    /// Samples between updates of the cutting filter
+    const UPDATE_INTERVAL: usize = 32;
+
+    /// Filter interleaved samples, returning them with the deepest cut in dB
+    ///
+    /// `band` picks the band the detector listens to out of the mono sum and the K-weighted
+    /// mono sum, sample by sample. `cut_filter` makes the filter for a gain in dB, which is
+    /// linked across channels.
+    pub fn apply(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+        mut band: impl FnMut(f64, f64) -> f64,
+        cut_filter: impl Fn(f64) -> Biquad,
+    ) -> (Vec<f32>, f64) {
+        let coeff = |ms: f64| (-1.0 / (ms * 0.001 * sample_rate as f64)).exp();
+        let detector_coeff = coeff(self.detector_ms);
+        let attack_coeff = coeff(self.attack_ms);
+        let release_coeff = coeff(self.release_ms);
+
+        let mut weighting = k_weighting(sample_rate);
+        let mut filters = vec![cut_filter(0.0); channels];
+
+        let (mut total_energy, mut band_energy) = (0.0_f64, 0.0_f64);
+        let mut cut = 0.0_f64;
+        let mut deepest_cut = 0.0_f64;
+        let mut output = Vec::with_capacity(samples.len());
+
+        for (index, frame) in samples.chunks(channels).enumerate() {
+            let mono = frame.iter().map(|&s| s as f64).sum::<f64>() / channels as f64;
+            let weighted = weighting.iter_mut().fold(mono, |x, f| f.process(x));
+            let band = band(mono, weighted);
+
+            total_energy =
+                detector_coeff * total_energy + (1.0 - detector_coeff) * weighted * weighted;
+            band_energy = detector_coeff * band_energy + (1.0 - detector_coeff) * band * band;
+
+            let excess = if total_energy > 1e-12 {
+                10.0 * (band_energy / total_energy).log10() - self.threshold
+            } else {
+                0.0
+            };
+            let target_cut = (excess * self.cut_per_db).clamp(0.0, self.max_cut);
+            let smoothing = if target_cut > cut {
+                attack_coeff
+            } else {
+                release_coeff
+            };
+            cut = smoothing * cut + (1.0 - smoothing) * target_cut;
+            deepest_cut = deepest_cut.max(cut);
+
+            if index % Self::UPDATE_INTERVAL == 0 {
+                let coefficients = cut_filter(-cut);
+                filters.iter_mut().for_each(|f| f.retune(&coefficients));
+            }
+
+            for (&sample, filter) in frame.iter().zip(filters.iter_mut()) {
+                output.push(filter.process(sample as f64) as f32);
+            }
+        }
+
+        (output, deepest_cut)
+    }
+}
+
+/// How the two channels of a stereo clip relate to each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StereoAnalysis {
+    /// Phase correlation between -1 (one channel inverted) and 1 (identical channels)
+    pub correlation: f64,
+    /// K-weighted loudness of the left channel relative to the right, in dB
+    pub balance: f64,
+    /// Energy lost when the channels are summed to mono, in dB
+    pub mono_loss: f64,
+}
+
+/// Largest balance and mono-fold loss reported, in dB, for clips with a silent or cancelling side
+const STEREO_LIMIT_DB: f64 = 60.0;
+
+/// Correlation, balance and mono compatibility of interleaved stereo samples.
+/// Returns `None` unless the clip has exactly two channels.
+pub fn analyze_stereo(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+) -> Option<StereoAnalysis> {
+    if channels != 2 {
+        return None;
+    }
+
+    let mut left_weighting = k_weighting(sample_rate);
+    let mut right_weighting = k_weighting(sample_rate);
+
+    let (mut left, mut right, mut cross, mut mid) = (0.0_f64, 0.0_f64, 0.0_f64, 0.0_f64);
+    let (mut left_weighted, mut right_weighted) = (0.0_f64, 0.0_f64);
+    for frame in samples.chunks_exact(2) {
+        let (l, r) = (frame[0] as f64, frame[1] as f64);
+        left += l * l;
+        right += r * r;
+        cross += l * r;
+        mid += (0.5 * (l + r)).powi(2);
+
+        let lw = left_weighting.iter_mut().fold(l, |x, f| f.process(x));
+        let rw = right_weighting.iter_mut().fold(r, |x, f| f.process(x));
+        left_weighted += lw * lw;
+        right_weighted += rw * rw;
+    }
+
+    let ratio_db = |a: f64, b: f64| {
+        if a <= 0.0 && b <= 0.0 {
+            0.0
+        } else {
+            (10.0 * (a / b).log10()).clamp(-STEREO_LIMIT_DB, STEREO_LIMIT_DB)
+        }
+    };
+
+    let correlation = if left > 0.0 && right > 0.0 {
+        cross / (left * right).sqrt()
+    } else {
+        0.0
+    };
+
+    Some(StereoAnalysis {
+        correlation,
+        balance: ratio_db(left_weighted, right_weighted),
+        mono_loss: -ratio_db(mid, 0.5 * (left + right)),
+    })
+}
+
+/// Periodic Hann window
+pub fn hann_window(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / len as f32).cos())
+        .collect()
+}
+
+/// Average the channels of interleaved samples into one
+pub fn downmix_to_mono(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Real FFT of a windowed frame, zero padded to `fft_size`, returning bins 0..=fft_size/2
+///
+/// `fft_size` must be a power of two between 2 and 32768.
+pub fn spectrum(frame: &[f32], window: &[f32], fft_size: usize) -> Vec<Complex32> {
+    let mut input = vec![0.0; fft_size];
+    for ((x, &s), &w) in input.iter_mut().zip(frame).zip(window) {
+        *x = s * w;
+    }
+
+    let mut output = vec![Complex32::default(); fft_size / 2 + 1];
+    real_fft(&input, &mut output);
+    output
+}
+
+/// Inverse of [`spectrum`]: real signal of `(bins.len() - 1) * 2` samples from its half spectrum
+///
+/// The inverse transform is already scaled by `1 / fft_size`.
+pub fn inverse_spectrum(bins: &[Complex32]) -> Vec<f32> {
+    let fft_size = (bins.len() - 1) * 2;
+    let mut full = vec![Complex32::default(); fft_size];
+    full[..bins.len()].copy_from_slice(bins);
+    for k in 1..fft_size / 2 {
+        full[fft_size - k] = bins[k].conj();
+    }
+
+    let mut output = vec![Complex32::default(); fft_size];
+    inverse_fft(&full, &mut output);
+    output.iter().map(|c| c.re).collect()
+}
+
+/// Long-term average power spectrum of the mono downmix, bins 0..=fft_size/2
+///
+/// Frames are Hann windowed with 50% overlap. `fft_size` must be a power of two.
+pub fn average_power_spectrum(samples: &[f32], channels: usize, fft_size: usize) -> Vec<f64> {
+    let mono = downmix_to_mono(samples, channels);
+    let window = hann_window(fft_size);
+    let mut power = vec![0.0; fft_size / 2 + 1];
+    let mut frames = 0;
+
+    let mut start = 0;
+    while start < mono.len() {
+        let end = (start + fft_size).min(mono.len());
+        for (p, bin) in power
+            .iter_mut()
+            .zip(spectrum(&mono[start..end], &window, fft_size))
+        {
+            *p += bin.norm_sqr() as f64;
+        }
+        frames += 1;
+        start += fft_size / 2;
+    }
+
+    if frames > 0 {
+        power.iter_mut().for_each(|p| *p /= frames as f64);
+    }
+    power
+}
+
+/// Design a linear-phase FIR filter from a magnitude response at bins 0..=n/2
+///
+/// The filter has `n` taps and a delay of `n / 2` samples.
+pub fn design_fir(magnitudes: &[f64]) -> Vec<f32> {
+    let bins: Vec<Complex32> = magnitudes
+        .iter()
+        .map(|&m| Complex32::new(m as f32, 0.0))
+        .collect();
+    let impulse = inverse_spectrum(&bins);
+    let taps = impulse.len();
+
+    // Centre the zero-phase impulse and taper it to limit ripple
+    let window = hann_window(taps);
+    (0..taps)
+        .map(|i| impulse[(i + taps / 2) % taps] * window[i])
+        .collect()
+}
+
+/// Filter interleaved samples with a linear-phase FIR, compensating its `taps / 2` delay
+///
+/// Uses FFT overlap-add, so the output has the same length as the input.
+pub fn convolve_linear_phase(samples: &[f32], channels: usize, fir: &[f32]) -> Vec<f32> {
+    let delay = fir.len() / 2;
+    let fft_size = (2 * fir.len()).next_power_of_two();
+    let block = fft_size - fir.len() + 1;
+    let no_window = vec![1.0; fft_size];
+    let fir_spectrum = spectrum(fir, &no_window, fft_size);
+
+    let frames = samples.len() / channels;
+    let mut output = vec![0.0; samples.len()];
+
+    for channel in 0..channels {
+        let input: Vec<f32> = samples
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+        let mut filtered = vec![0.0_f32; frames + fft_size];
+
+        for (index, chunk) in input.chunks(block).enumerate() {
+            let mut bins = spectrum(chunk, &no_window, fft_size);
+            for (bin, h) in bins.iter_mut().zip(&fir_spectrum) {
+                *bin *= h;
+            }
+
+            let offset = index * block;
+            for (y, x) in filtered[offset..offset + fft_size]
+                .iter_mut()
+                .zip(inverse_spectrum(&bins))
+            {
+                *y += x;
+            }
+        }
+
+        for (frame, &y) in filtered[delay..delay + frames].iter().enumerate() {
+            output[frame * channels + channel] = y;
+        }
+    }
+
+    output
+}
+
+/// Changes the duration of clips without changing their pitch, see [`time_stretch`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeStretcher {
+    ratio: f64,
+}
+
+impl TimeStretcher {
+    /// Shortest output allowed, as a share of the input duration
+    pub const MIN_RATIO: f64 = 0.5;
+    /// Longest output allowed, as a share of the input duration
+    pub const MAX_RATIO: f64 = 2.0;
+
+    /// `ratio` is the output duration over the input duration, below 1 to shorten
+    pub fn new(ratio: f64) -> Result<Self, Error> {
+        if !(Self::MIN_RATIO..=Self::MAX_RATIO).contains(&ratio) {
+            return Err(anyhow::anyhow!(
+                "Stretch ratio must be between {} and {} (got: {})",
+                Self::MIN_RATIO,
+                Self::MAX_RATIO,
+                ratio
+            ));
+        }
+
+        Ok(Self { ratio })
+    }
+}
+
+impl AudioProcessor for TimeStretcher {
+    fn process(
+        &self,
+        samples: &[f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Vec<f32>, Error> {
+        time_stretch(samples, channels, sample_rate, self.ratio)
+    }
+}
+
+/// Reject ratios of frames out over frames in that no clip can be stretched or resampled by
+fn check_ratio(ratio: f64) -> Result<(), Error> {
+    if !ratio.is_finite() || ratio <= 0.0 {
+        return Err(anyhow::anyhow!(
+            "Ratio must be a positive number (got: {})",
+            ratio
+        ));
+    }
+    Ok(())
+}
+
+/// Length of the WSOLA frames, long enough to hold a couple of periods of a low voice
+const STRETCH_FRAME_MS: f64 = 40.0;
+/// How far a frame may be moved from its nominal position to line up with the output
+const STRETCH_TOLERANCE_MS: f64 = 10.0;
+/// Sample and offset step of the coarse alignment search, refined afterwards
+const STRETCH_SEARCH_STEP: usize = 4;
+
+/// Change the duration of interleaved samples by `ratio` without changing their pitch
+///
+/// Uses WSOLA: Hann windowed frames at half-frame hops in the output are taken from
+/// around their nominal position in the input, each moved to where it best continues the
+/// waveform already written, and overlap-added. The alignment is found on the mono
+/// downmix and applied to all channels, so the stereo image is kept.
+pub(crate) fn time_stretch(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    ratio: f64,
+) -> Result<Vec<f32>, Error> {
+    check_ratio(ratio)?;
+    let frames = samples.len() / channels;
+    if frames == 0 || ratio == 1.0 {
+        return Ok(samples.to_vec());
+    }
+
+    let ms_to_frames = |ms: f64| (ms * 0.001 * sample_rate as f64) as usize;
+    let hop = ms_to_frames(STRETCH_FRAME_MS / 2.0).max(1);
+    let frame_len = 2 * hop;
+    let tolerance = ms_to_frames(STRETCH_TOLERANCE_MS) as isize;
+    let window = hann_window(frame_len);
+
+    let mono = downmix_to_mono(samples, channels);
+    let at = |i: isize| match usize::try_from(i) {
+        Ok(i) if i < frames => mono[i],
+        _ => 0.0,
+    };
+    // Similarity of the first half of a frame at `candidate` to the natural continuation
+    let similarity = |continuation: isize, candidate: isize, step: usize| {
+        (0..hop as isize)
+            .step_by(step)
+            .map(|i| at(continuation + i) * at(candidate + i))
+            .sum::<f32>()
+    };
+
+    let out_frames = (frames as f64 * ratio).round() as usize;
+    let mut output = vec![0.0_f32; (out_frames + frame_len) * channels];
+    let mut weight = vec![0.0_f32; out_frames + frame_len];
+    let mut previous: Option<isize> = None;
+
+    for (k, position) in (0..out_frames).step_by(hop).enumerate() {
+        let nominal = ((k * hop) as f64 / ratio).round() as isize;
+        let start = match previous {
+            None => nominal,
+            Some(previous) => {
+                let continuation = previous + hop as isize;
+                let range = (nominal - tolerance).max(0)..=(nominal + tolerance);
+                let best = |candidates: &mut dyn Iterator<Item = isize>, step: usize| {
+                    candidates
+                        .map(|c| (c, similarity(continuation, c, step)))
+                        .max_by(|a, b| a.1.total_cmp(&b.1))
+                        .map_or(nominal, |(c, _)| c)
+                };
+
+                let coarse = best(
+                    &mut range.clone().step_by(STRETCH_SEARCH_STEP),
+                    STRETCH_SEARCH_STEP,
+                );
+                let step = STRETCH_SEARCH_STEP as isize;
+                best(
+                    &mut ((coarse - step).max(*range.start())..=(coarse + step).min(*range.end())),
+                    1,
+                )
+            }
+        };
+
+        for (i, &w) in window.iter().enumerate() {
+            let Ok(source) = usize::try_from(start + i as isize) else {
+                continue;
+            };
+            if source >= frames {
+                break;
+            }
+
+            let target = position + i;
+            weight[target] += w;
+            for channel in 0..channels {
+                output[target * channels + channel] += w * samples[source * channels + channel];
+            }
+        }
+        previous = Some(start);
+    }
+
+    // The windows sum to one except where only a single frame covers the output
+    output.truncate(out_frames * channels);
+    for (frame, &w) in output.chunks_mut(channels).zip(&weight) {
+        if w > 1e-3 {
+            frame.iter_mut().for_each(|s| *s /= w);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Zero crossings of the resampling kernel on either side of its centre
+const RESAMPLE_ZERO_CROSSINGS: usize = 16;
+
+/// Resample interleaved samples to `ratio` times as many frames
+///
+/// Played back at the original rate the result is `1 / ratio` times higher in pitch. Uses
+/// Hann windowed sinc interpolation, with the cutoff lowered when shortening so nothing
+/// folds back above the new Nyquist frequency.
+pub(crate) fn resample(samples: &[f32], channels: usize, ratio: f64) -> Result<Vec<f32>, Error> {
+    check_ratio(ratio)?;
+    let frames = samples.len() / channels;
+    if frames == 0 || ratio == 1.0 {
+        return Ok(samples.to_vec());
+    }
+
+    let cutoff = ratio.min(1.0);
+    let half_width = RESAMPLE_ZERO_CROSSINGS as f64 / cutoff;
+    let kernel = |distance: f64| {
+        if distance.abs() >= half_width {
+            return 0.0;
+        }
+        let x = std::f64::consts::PI * cutoff * distance;
+        let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+        let window = 0.5 + 0.5 * (std::f64::consts::PI * distance / half_width).cos();
+        cutoff * sinc * window
+    };
+
+    let out_frames = (frames as f64 * ratio).round() as usize;
+    let mut output = Vec::with_capacity(out_frames * channels);
+    let mut accumulated = vec![0.0_f64; channels];
+    for j in 0..out_frames {
+        let position = j as f64 / ratio;
+        let first = (position - half_width).ceil().max(0.0) as usize;
+        let last = ((position + half_width).floor() as usize).min(frames - 1);
+
+        accumulated.iter_mut().for_each(|a| *a = 0.0);
+        for n in first..=last {
+            let weight = kernel(position - n as f64);
+            for (a, &s) in accumulated.iter_mut().zip(&samples[n * channels..]) {
+                *a += weight * s as f64;
+            }
+        }
+        output.extend(accumulated.iter().map(|&a| a as f32));
+    }
+
+    Ok(output)
+}
+
 /// Decode the audio stream to samples
 pub fn decode_to_samples(
     format: &mut Box<dyn symphonia::core::formats::FormatReader>,
@@ -87,19 +1040,36 @@ pub fn decode_to_samples(
     Ok(samples)
 }
 
-/// Process the audio stream to get samples and track info
-pub fn decode_file(input_path: &Path) -> Result<(Vec<f32>, Track), anyhow::Error> {
+/// Process the audio stream to get samples, track info and the tags kept by [`Metadata`]
+pub fn decode_file_with_metadata(
+    input_path: &Path,
+) -> Result<(Vec<f32>, Track, Metadata), anyhow::Error> {
     // First get the track info
     let file = File::open(input_path)?;
     let mss = MediaSourceStream::new(Box::new(file), Default::default());
 
     let probe = default::get_probe();
-    let format_opts: FormatOptions = Default::default();
+    // Drop the encoder delay and padding MP3 and AAC files declare, so clips come back
+    // sample-accurate however often they are re-encoded
+    let format_opts = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
     let metadata_opts: MetadataOptions = Default::default();
     let hint = Hint::new();
 
-    let probed = probe.format(&hint, mss, &format_opts, &metadata_opts)?;
+    let mut probed = probe.format(&hint, mss, &format_opts, &metadata_opts)?;
     let mut format_reader = probed.format;
+
+    // Tags in front of the stream, such as ID3v2, come with the probe, the ones inside
+    // it, such as Vorbis comments and RIFF INFO, with the reader
+    let mut metadata = Metadata::new();
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        metadata.add_revision(revision);
+    }
+    if let Some(revision) = format_reader.metadata().current() {
+        metadata.add_revision(revision);
+    }
     let track = format_reader
         .default_track()
         .ok_or(anyhow::anyhow!("No default track found"))?
@@ -112,5 +1082,178 @@ pub fn decode_file(input_path: &Path) -> Result<(Vec<f32>, Track), anyhow::Error
     // Decode samples
     let samples = decode_to_samples(&mut format_reader, track.id, decoder)?;
 
+    Ok((samples, track, metadata))
+}
+
+/// Decode a file to interleaved samples and its track, leaving out its tags
+pub fn decode_file(input_path: &Path) -> Result<(Vec<f32>, Track), anyhow::Error> {
+    let (samples, track, _) = decode_file_with_metadata(input_path)?;
     Ok((samples, track))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::sine;
+
+    #[test]
+    fn test_a_weighting_response() {
+        let sample_rate = 48000;
+        let cascade = a_weighting(sample_rate);
+        let response_db = |frequency: f64| {
+            linear_to_db(
+                cascade
+                    .iter()
+                    .map(|section| section.magnitude(sample_rate, frequency))
+                    .product(),
+            )
+        };
+
+        // Reference values from IEC 61672-1
+        for (frequency, expected) in [(100.0, -19.1), (1000.0, 0.0), (4000.0, 1.0)] {
+            let actual = response_db(frequency);
+            assert!(
+                (actual - expected).abs() < 0.3,
+                "Expected {:.1} dB at {} Hz, got {:.2} dB",
+                expected,
+                frequency,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_rms_db_of_full_scale_sine() {
+        let samples = sine(1000.0, 1.0, 48000, 48000);
+
+        assert!((rms_db(&samples) + 3.01).abs() < 0.01);
+        assert_eq!(rms_db(&[]), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_analyze_stereo() {
+        let tone = sine(1000.0, 0.5, 48000, 48000);
+        let interleave = |right_gain: f32| {
+            tone.iter()
+                .flat_map(|&s| [s, s * right_gain])
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(analyze_stereo(&tone, 1, 48000), None);
+
+        let dual_mono = analyze_stereo(&interleave(1.0), 2, 48000).unwrap();
+        assert!((dual_mono.correlation - 1.0).abs() < 1e-6);
+        assert!(dual_mono.balance.abs() < 0.01);
+        assert!(dual_mono.mono_loss.abs() < 0.01);
+
+        let inverted = analyze_stereo(&interleave(-1.0), 2, 48000).unwrap();
+        assert!((inverted.correlation + 1.0).abs() < 1e-6);
+        assert!(inverted.mono_loss > 40.0);
+
+        let lopsided = analyze_stereo(&interleave(0.5), 2, 48000).unwrap();
+        assert!((lopsided.balance - 6.02).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_processor_chain_runs_in_order() {
+        struct Scale(f32);
+        impl AudioProcessor for Scale {
+            fn process(&self, samples: &[f32], _: usize, _: u32) -> Result<Vec<f32>, Error> {
+                Ok(samples.iter().map(|s| s * self.0).collect())
+            }
+        }
+        struct Offset(f32);
+        impl AudioProcessor for Offset {
+            fn process(&self, samples: &[f32], _: usize, _: u32) -> Result<Vec<f32>, Error> {
+                Ok(samples.iter().map(|s| s + self.0).collect())
+            }
+        }
+
+        let chain = ProcessorChain::new().then(Scale(2.0)).then(Offset(0.5));
+        assert_eq!(chain.process(&[0.25, -0.5], 1, 48000).unwrap(), [1.0, -0.5]);
+        assert_eq!(
+            ProcessorChain::new().process(&[0.1], 1, 48000).unwrap(),
+            [0.1]
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [-20.0, -30.0, -10.0]), Some(-20.0));
+        assert_eq!(median(&mut [-24.0, -18.0, -30.0, -20.0]), Some(-22.0));
+    }
+
+    #[test]
+    fn test_inverse_spectrum_round_trip() {
+        let frame: Vec<f32> = (0..256)
+            .map(|i| ((i * 7) % 13) as f32 / 13.0 - 0.5)
+            .collect();
+        let bins = spectrum(&frame, &vec![1.0; 256], 256);
+        let restored = inverse_spectrum(&bins);
+
+        for (a, b) in frame.iter().zip(&restored) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_flat_fir_is_transparent() {
+        let samples = sine(440.0, 1.0, 44100, 4000);
+        let fir = design_fir(&vec![1.0; 257]);
+        let filtered = convolve_linear_phase(&samples, 2, &fir);
+
+        assert_eq!(filtered.len(), samples.len());
+        for (a, b) in samples.iter().zip(&filtered).skip(600).take(2800) {
+            assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_time_stretch_keeps_pitch() {
+        let sample_rate = 44100;
+        let tone = sine(220.0, 0.5, sample_rate, sample_rate as usize * 2);
+        // Upward zero crossings per second of the middle of the clip
+        let pitch = |s: &[f32]| {
+            let middle = &s[s.len() / 4..s.len() * 3 / 4];
+            let crossings = middle.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0);
+            crossings.count() as f64 * sample_rate as f64 / middle.len() as f64
+        };
+
+        for ratio in [0.8, 1.25] {
+            let stretched = time_stretch(&tone, 1, sample_rate, ratio).unwrap();
+            assert_eq!(
+                stretched.len(),
+                (tone.len() as f64 * ratio).round() as usize
+            );
+            assert!((pitch(&stretched) - 220.0).abs() < 2.0);
+            let middle = &stretched[stretched.len() / 4..stretched.len() * 3 / 4];
+            assert!((rms_db(middle) - rms_db(&tone)).abs() < 1.0);
+        }
+
+        assert!(TimeStretcher::new(0.3).is_err());
+        assert!(TimeStretcher::new(2.5).is_err());
+        assert!(time_stretch(&tone, 1, sample_rate, 0.0).is_err());
+        assert!(time_stretch(&tone, 1, sample_rate, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_resample_changes_pitch() {
+        let tone = sine(1000.0, 0.5, 44100, 44100);
+        let crossings = |s: &[f32]| s.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+
+        let longer = resample(&tone, 1, 2.0).unwrap();
+        assert_eq!(longer.len(), 88200);
+        assert!(crossings(&longer).abs_diff(1000) <= 1);
+
+        // Halving the frames doubles the pitch, and a tone above the new Nyquist is removed
+        let shorter = resample(&tone, 1, 0.5).unwrap();
+        assert_eq!(shorter.len(), 22050);
+        assert!(crossings(&shorter).abs_diff(1000) <= 1);
+        let high = sine(15000.0, 0.5, 44100, 44100);
+        assert!(rms_db(&resample(&high, 1, 0.5).unwrap()[1000..21000]) < -40.0);
+
+        assert!(resample(&tone, 1, -1.0).is_err());
+        assert!(resample(&tone, 1, f64::INFINITY).is_err());
+    }
+}
@@ -1,6 +1,24 @@
+pub mod audio_analyzer;
+pub mod audio_bass_tamer;
 pub mod audio_converter;
+pub mod audio_declicker;
+pub mod audio_deharsher;
+pub mod audio_denoiser;
+pub mod audio_duration_fitter;
 pub mod audio_file;
-pub mod audio_normalizer;
+pub mod audio_flac;
+pub mod audio_hum_remover;
+pub mod audio_leveler;
 pub mod audio_limiter;
+pub mod audio_loudness;
+pub mod audio_matcher;
+pub mod audio_metadata;
+pub mod audio_normalizer;
+pub mod audio_pitch_shifter;
+pub mod audio_stereo_fixer;
+pub mod audio_transient_shaper;
 pub mod discord;
 pub mod dsp;
+pub mod sample_format;
+#[cfg(test)]
+mod test_signals;
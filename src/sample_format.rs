@@ -0,0 +1,134 @@
+/// How the rounding error of the conversion to integers is treated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Round to the nearest step. Deterministic, but the error follows the signal and is
+    /// heard as distortion on quiet fades.
+    None,
+    /// Add triangular dither of up to one step, which turns the error into steady hiss
+    #[default]
+    Triangular,
+    /// Triangular dither with the error fed back so the hiss moves up to frequencies the
+    /// ear is less sensitive to, tuned for 44.1 and 48 kHz
+    Shaped,
+}
+
+/// Error feedback filter of [`Dither::Shaped`], the 3 tap F-weighted filter of Wannamaker
+const SHAPING_FILTER: [f64; 3] = [1.623, -0.982, 0.109];
+
+/// Largest error fed back, in steps, so a clipped sample does not upset the filter
+const MAX_FEEDBACK: f64 = 2.0;
+
+/// Convert interleaved float samples in [-1, 1] to signed integers of `bits` bits
+///
+/// Full scale maps to the largest positive value, and samples beyond it are clipped.
+pub fn quantize(samples: &[f32], channels: usize, bits: u32, dither: Dither) -> Vec<i32> {
+    let max = ((1_i64 << (bits - 1)) - 1) as f64;
+    let min = -max - 1.0;
+
+    // Fixed seed, so the same clip always converts to the same samples
+    let mut state = 0x2545_f491_u32;
+    let mut uniform = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f64 / u32::MAX as f64 - 0.5
+    };
+
+    let mut errors = vec![[0.0_f64; 3]; channels];
+    samples
+        .iter()
+        .enumerate()
+        .map(|(i, &sample)| {
+            let value = sample as f64 * max;
+            let (wanted, noise) = match dither {
+                Dither::None => (value, 0.0),
+                Dither::Triangular => (value, uniform() + uniform()),
+                Dither::Shaped => {
+                    let error = &errors[i % channels];
+                    let feedback: f64 = SHAPING_FILTER.iter().zip(error).map(|(h, e)| h * e).sum();
+                    (value - feedback, uniform() + uniform())
+                }
+            };
+
+            let quantized = (wanted + noise).round().clamp(min, max);
+            if dither == Dither::Shaped {
+                let error = &mut errors[i % channels];
+                error.rotate_right(1);
+                error[0] = (quantized - wanted).clamp(-MAX_FEEDBACK, MAX_FEEDBACK);
+            }
+            quantized as i32
+        })
+        .collect()
+}
+
+/// Convert interleaved float samples in [-1, 1] to 16-bit integers
+pub fn to_i16(samples: &[f32], channels: usize, dither: Dither) -> Vec<i16> {
+    quantize(samples, channels, 16, dither)
+        .into_iter()
+        .map(|s| s as i16)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_signals::sine;
+
+    #[test]
+    fn test_rounds_and_clips_without_dither() {
+        let converted = to_i16(&[0.0, 0.5, -0.5, 1.0, -1.0, 1.5, -1.5], 1, Dither::None);
+        assert_eq!(converted, [0, 16384, -16384, 32767, -32767, 32767, -32768]);
+        assert_eq!(
+            quantize(&[1.0, -2.0], 1, 24, Dither::None),
+            [8388607, -8388608]
+        );
+    }
+
+    #[test]
+    fn test_dither_keeps_signals_below_one_step() {
+        // A sine at a third of a step disappears when rounded, but survives dither
+        let step = 1.0 / 32767.0;
+        let quiet = sine(440.0, step / 3.0, 44100, 44100);
+        let correlation = |converted: &[i16]| {
+            converted
+                .iter()
+                .zip(&quiet)
+                .map(|(&q, &x)| q as f64 * x as f64)
+                .sum::<f64>()
+        };
+
+        assert!(to_i16(&quiet, 1, Dither::None).iter().all(|&s| s == 0));
+        for dither in [Dither::Triangular, Dither::Shaped] {
+            assert!(correlation(&to_i16(&quiet, 1, dither)) > 0.0);
+        }
+        // Triangular dither never moves a sample more than one step
+        assert!(to_i16(&quiet, 1, Dither::Triangular)
+            .iter()
+            .all(|&s| s.abs() <= 1));
+
+        // The same clip always converts to the same samples
+        assert_eq!(
+            to_i16(&quiet, 1, Dither::Triangular),
+            to_i16(&quiet, 1, Dither::Triangular)
+        );
+    }
+
+    #[test]
+    fn test_shaping_moves_noise_out_of_the_low_band() {
+        let signal = sine(440.0, 0.25, 44100, 44100);
+        // Error power after a moving average, which keeps the low frequencies
+        let low_band_error = |dither: Dither| {
+            let error: Vec<f64> = to_i16(&signal, 1, dither)
+                .iter()
+                .zip(&signal)
+                .map(|(&q, &x)| q as f64 - x as f64 * 32767.0)
+                .collect();
+            error
+                .windows(8)
+                .map(|w| (w.iter().sum::<f64>() / 8.0).powi(2))
+                .sum::<f64>()
+        };
+
+        assert!(low_band_error(Dither::Shaped) < low_band_error(Dither::Triangular) * 0.5);
+    }
+}
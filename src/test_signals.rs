@@ -0,0 +1,44 @@
+//! Deterministic signals shared by the unit tests
+
+use symphonia::core::audio::Channels;
+use symphonia::core::codecs::CodecParameters;
+use symphonia::core::formats::Track;
+
+/// Mono sine wave at `frequency` Hz with a peak of `amplitude`, `frames` samples long
+pub(crate) fn sine(frequency: f32, amplitude: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin()
+        })
+        .collect()
+}
+
+/// Mono white noise spread evenly between `-amplitude` and `amplitude`, from an xorshift
+/// generator so every run gets the same noise
+pub(crate) fn noise(frames: usize, amplitude: f32) -> Vec<f32> {
+    let mut state = 0x1234_5678_u32;
+    (0..frames)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            ((state as f32 / u32::MAX as f32) * 2.0 - 1.0) * amplitude
+        })
+        .collect()
+}
+
+/// The mono `samples` copied into each of `channels` interleaved channels
+pub(crate) fn interleave(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples
+        .iter()
+        .flat_map(|&s| std::iter::repeat_n(s, channels))
+        .collect()
+}
+
+/// Decoded track of a 44.1 kHz clip with the given speaker layout, for the writers
+pub(crate) fn track(channels: Channels) -> Track {
+    let mut params = CodecParameters::new();
+    params.with_sample_rate(44100).with_channels(channels);
+    Track::new(0, params)
+}